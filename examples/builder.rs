@@ -0,0 +1,73 @@
+//! ```
+//! ### 以下のコマンドで実行。
+//! cd C:\MuzudhoDrive\projects_rust\rust_kifuwarabe_shell
+//! cargo run --example builder
+//! ```
+
+extern crate kifuwarabe_shell;
+use kifuwarabe_shell::diagram::*;
+use kifuwarabe_shell::shell::*;
+
+// 任意のオブジェクト。
+pub struct ShellVar {
+    pub count: i32,
+}
+
+/// # テスト方法。
+///
+/// diagram.json を使わずに、コードで組み立てたダイアグラムをテストします。
+///
+/// - 次のように表示される。
+///   Go.
+///   Btime(500).
+///   Go-NewLine.
+fn main() {
+    let mut diagram: Diagram<ShellVar> = Diagram::new();
+    diagram.insert_fn("do_go", do_go);
+    diagram.insert_fn("do_btime", do_btime);
+    diagram.insert_fn("do_go_newline", do_go_newline);
+
+    diagram.set_entry_point("HEAD.neutral".to_string());
+    diagram.node("HEAD.neutral").next(["TK.go"]);
+    diagram
+        .node("TK.go")
+        .token("go")
+        .fn_("do_go")
+        .next(["TK.btime"])
+        .newline("TAIL.go");
+    diagram.node("TK.btime").token("btime").next(["TK.btime.num"]);
+    diagram
+        .node("TK.btime.num")
+        .regex(r"(\d+)")
+        .fn_("do_btime")
+        .newline("TAIL.go");
+    diagram
+        .node("TAIL.go")
+        .fn_("do_go_newline")
+        .next(["HEAD.neutral"]);
+
+    // 登録漏れの確認。
+    for node_label in diagram.find_missing_nodes() {
+        println!("Missing node: {}.", node_label);
+    }
+
+    let mut shell_var = ShellVar { count: 0 };
+    let mut shell = Shell::new();
+    shell.execute_line(&mut diagram, &mut shell_var, "go btime 500");
+    println!("Finished. shell_var.count: {}.", shell_var.count);
+}
+
+pub fn do_go(shell_var: &mut ShellVar, _req: &dyn Request, _res: &mut dyn Response) {
+    shell_var.count += 1;
+    println!("Go.");
+}
+
+pub fn do_btime(shell_var: &mut ShellVar, req: &dyn Request, _res: &mut dyn Response) {
+    shell_var.count += 1;
+    println!("Btime({}).", req.get_groups()[0]);
+}
+
+pub fn do_go_newline(shell_var: &mut ShellVar, _req: &dyn Request, _res: &mut dyn Response) {
+    shell_var.count += 1;
+    println!("Go-NewLine.");
+}
@@ -1,9 +1,9 @@
-/// ```
-/// ### 以下のコマンドで実行。
-/// cls
-/// cd C:\MuzudhoDrive\projects_rust\rust_kifuwarabe_shell
-/// cargo run --example main
-/// ```
+//! ```
+//! ### 以下のコマンドで実行。
+//! cls
+//! cd C:\MuzudhoDrive\projects_rust\rust_kifuwarabe_shell
+//! cargo run --example main
+//! ```
 
 // 参考:
 // https://github.com/serde-rs/json |serde_json
@@ -20,28 +20,28 @@ use test_scenario::*;
 /// diagram.json ファイルに書かれているスクリプトをテストします。
 /// 
 /// - 「ab cde」と打鍵して [Enter]キーを押す。
-///     Ab.
-///     Cde.
-///     Ab-NewLine.
+///   Ab.
+///   Cde.
+///   Ab-NewLine.
 /// - 「end xyz」と打鍵して [Enter]キーを押す。
-///     End.
-///     Ab-NewLine.
+///   End.
+///   Ab-NewLine.
 /// - 「xyz」と打鍵して [Enter]キーを押す。
-///     Word(xyz).
-///     Ab-NewLine.
+///   Word(xyz).
+///   Ab-NewLine.
 /// - 「ab cde xyz」と打鍵して [Enter]キーを押す。
-///     Ab.
-///     Cde.
-///     Word(xyz).
-///     Ab-NewLine.
+///   Ab.
+///   Cde.
+///   Word(xyz).
+///   Ab-NewLine.
 /// - 「quit」と打鍵して [Enter]キーを押す。
-///     Quit.
-///     Finished. shell_var.count: 12.
+///   Quit.
+///   Finished. shell_var.count: 12.
 /// - 強制終了したいなら、[Ctrl]+[C]キー を押す。
 /// 
 /// - また、「reload」と打鍵して [Enter]キーを押す。
-///     Reload.
-///     diagram.json ファイルを再読み込みするはず。
+///   Reload.
+///   diagram.json ファイルを再読み込みするはず。
 fn main() {
     // 任意のオブジェクト。
     let mut shell_var = ShellVar::new();
@@ -52,7 +52,7 @@ fn main() {
     let mut diagram = Diagram::new();
     setup_diagram(&mut diagram); // test_scenario.rs 参照。
     // ダイアグラムの入り口に遷移。
-    shell.enter(&diagram);
+    shell.enter(&diagram, &mut shell_var);
 
     // 内容確認出力。
     {
@@ -19,10 +19,10 @@ use test_scenario::*;
 /// diagram.json ファイルに書かれているスクリプトをテストします。
 ///
 /// - 次のように表示される。
-///     Ab.
-///     Cde.
-///     Word(xyz).
-///     Ab-NewLine.
+///   Ab.
+///   Cde.
+///   Word(xyz).
+///   Ab-NewLine.
 fn main() {
     // 任意のオブジェクト。
     let mut shell_var = ShellVar::new();
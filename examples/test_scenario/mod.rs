@@ -1,6 +1,6 @@
 extern crate kifuwarabe_shell;
 use kifuwarabe_shell::diagram::ResponseOption;
-use kifuwarabe_shell::diagram::{Diagram, Request, Response};
+use kifuwarabe_shell::diagram::{list_backups, Diagram, Request, Response};
 
 const DIAGRAM_JSON_FILE: &str = "diagram.json";
 
@@ -20,6 +20,8 @@ pub fn setup_diagram(diagram: &mut Diagram<ShellVar>) {
     diagram.insert_fn("do_ab", do_ab);
     diagram.insert_fn("do_abc", do_abc);
     diagram.insert_fn("do_cde", do_cde);
+    diagram.insert_fn("do_edit_backups", do_edit_backups);
+    diagram.insert_fn("do_edit_restore", do_edit_restore);
     diagram.insert_fn("do_edit_save", do_edit_save);
     diagram.insert_fn("do_end", do_end);
     diagram.insert_fn("do_numvar", do_numvar);
@@ -30,7 +32,7 @@ pub fn setup_diagram(diagram: &mut Diagram<ShellVar>) {
     diagram.insert_fn("do_reload", do_reload);
 
     // ファイルからグラフのノード構成を読取。
-    diagram.read_file(&DIAGRAM_JSON_FILE);
+    diagram.read_file(DIAGRAM_JSON_FILE);
 }
 
 pub fn do_a(shell_var: &mut ShellVar, _req: &dyn Request, _res: &mut dyn Response) {
@@ -58,6 +60,30 @@ pub fn do_cde(shell_var: &mut ShellVar, _req: &dyn Request, _res: &mut dyn Respo
     println!("Cde.");
 }
 
+/// グラフファイルのバックアップを一覧する。
+pub fn do_edit_backups(_shell_var: &mut ShellVar, _req: &dyn Request, _res: &mut dyn Response) {
+    for backup_file in list_backups(DIAGRAM_JSON_FILE) {
+        println!("{}", backup_file);
+    }
+}
+
+/// グラフファイルをバックアップから復元する。
+pub fn do_edit_restore(_shell_var: &mut ShellVar, req: &dyn Request, res: &mut dyn Response) {
+    // "type": "uint" のノードなので、数に変換済み。
+    let generation = match req.get_uint() {
+        Some(n) => n as usize,
+        None => {
+            println!("!Restore. \"{}\" is not a generation.", req.get_matched());
+            return;
+        }
+    };
+    println!("!Restore. {} {}", DIAGRAM_JSON_FILE, generation);
+    res.set_option(ResponseOption::RestoresBackup(
+        DIAGRAM_JSON_FILE.to_string(),
+        generation,
+    ));
+}
+
 /// グラフファイルを上書き保存する。
 pub fn do_edit_save(_shell_var: &mut ShellVar, _req: &dyn Request, res: &mut dyn Response) {
     println!("!Save. {}", DIAGRAM_JSON_FILE);
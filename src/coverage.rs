@@ -0,0 +1,241 @@
+/// どのノード、出口の矢印、コールバック関数を通ったかの記録。
+/// テストのスクリプトを流してから `report` を見れば、試していない文法の枝が分かる。
+use diagram::*;
+use diagram_diff::*;
+use std::collections::HashMap;
+use std::fmt;
+
+/// 通った回数。
+///
+/// # Members
+///
+/// * `node_map` - <ノード, 入った回数>
+/// * `edge_map` - <ノード, <出口, <行き先, 進んだ回数>>>
+/// * `fn_map` - <コールバック関数の登録名, 呼んだ回数>
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+pub struct Coverage {
+    node_map: HashMap<String, usize>,
+    edge_map: HashMap<String, HashMap<String, HashMap<String, usize>>>,
+    fn_map: HashMap<String, usize>,
+}
+impl Coverage {
+    pub fn new() -> Coverage {
+        Coverage::default()
+    }
+
+    pub fn get_node_count(&self, node_label: &str) -> usize {
+        self.node_map.get(node_label).cloned().unwrap_or(0)
+    }
+    pub fn get_edge_count(&self, node_label: &str, door_label: &str, next_label: &str) -> usize {
+        self.edge_map
+            .get(node_label)
+            .and_then(|doors| doors.get(door_label))
+            .and_then(|nexts| nexts.get(next_label))
+            .cloned()
+            .unwrap_or(0)
+    }
+    pub fn get_fn_count(&self, fn_label: &str) -> usize {
+        self.fn_map.get(fn_label).cloned().unwrap_or(0)
+    }
+
+    pub(crate) fn record_node(&mut self, node_label: &str) {
+        *self.node_map.entry(node_label.to_string()).or_insert(0) += 1;
+    }
+    pub(crate) fn record_edge(&mut self, node_label: &str, door_label: &str, next_label: &str) {
+        *self
+            .edge_map
+            .entry(node_label.to_string())
+            .or_default()
+            .entry(door_label.to_string())
+            .or_default()
+            .entry(next_label.to_string())
+            .or_insert(0) += 1;
+    }
+    pub(crate) fn record_fn(&mut self, fn_label: &str) {
+        *self.fn_map.entry(fn_label.to_string()).or_insert(0) += 1;
+    }
+
+    /// ダイアグラムと突き合わせて、一度も通っていないものを並べる。
+    /// コールバック関数は、ノードの `fn` 、 `on_enter` 、 `on_leave` に書いてあるものだけ見る。
+    pub fn report<T>(&self, diagram: &Diagram<T>) -> CoverageReport {
+        let mut report = CoverageReport::default();
+        let mut fn_labels = Vec::new();
+        for (node_label, node) in diagram.get_node_map().iter() {
+            report.node_total += 1;
+            if self.get_node_count(node_label) == 0 {
+                report.unvisited_nodes.push(node_label.to_string());
+            }
+
+            for (door_label, node_vec) in node.get_exit_map().iter() {
+                for next_label in node_vec {
+                    report.edge_total += 1;
+                    if self.get_edge_count(node_label, door_label, next_label) == 0 {
+                        report.untaken_edges.push(ExitEdge {
+                            label: node_label.to_string(),
+                            door: door_label.to_string(),
+                            next: next_label.to_string(),
+                            guard: node
+                                .get_guard(door_label, next_label)
+                                .map(|guard| guard.to_string()),
+                        });
+                    }
+                }
+            }
+
+            if node.get_fn_label() != "" {
+                fn_labels.push(node.get_fn_label().to_string());
+            }
+            for fn_label in node.get_on_enter().iter().chain(node.get_on_leave().iter()) {
+                fn_labels.push(fn_label.to_string());
+            }
+        }
+        fn_labels.sort();
+        fn_labels.dedup();
+        for fn_label in fn_labels {
+            report.fn_total += 1;
+            if self.get_fn_count(&fn_label) == 0 {
+                report.uninvoked_fns.push(fn_label);
+            }
+        }
+
+        // 出力を毎回同じにしたいので整列する。
+        report.unvisited_nodes.sort();
+        report.untaken_edges.sort();
+        report
+    }
+}
+
+/// 一度も通っていないもの。
+///
+/// # Members
+///
+/// * `node_total` - ダイアグラムのノードの数。
+/// * `edge_total` - ダイアグラムの矢印の数。
+/// * `fn_total` - ノードに書いてある コールバック関数の数。
+/// * `unvisited_nodes` - 入っていないノード。
+/// * `untaken_edges` - 進んでいない矢印。
+/// * `uninvoked_fns` - 呼んでいないコールバック関数。
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct CoverageReport {
+    pub node_total: usize,
+    pub edge_total: usize,
+    pub fn_total: usize,
+    pub unvisited_nodes: Vec<String>,
+    pub untaken_edges: Vec<ExitEdge>,
+    pub uninvoked_fns: Vec<String>,
+}
+impl CoverageReport {
+    /// 全部 通っていれば真。
+    pub fn is_complete(&self) -> bool {
+        self.unvisited_nodes.is_empty()
+            && self.untaken_edges.is_empty()
+            && self.uninvoked_fns.is_empty()
+    }
+}
+impl fmt::Display for CoverageReport {
+    /// 先頭に 通った数/全部の数 を書いて、その後に 通っていないものを 1行に1件。
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        writeln!(
+            f,
+            "nodes: {}/{}, exits: {}/{}, fns: {}/{}",
+            self.node_total - self.unvisited_nodes.len(),
+            self.node_total,
+            self.edge_total - self.untaken_edges.len(),
+            self.edge_total,
+            self.fn_total - self.uninvoked_fns.len(),
+            self.fn_total
+        )?;
+        for label in &self.unvisited_nodes {
+            writeln!(f, "node: {}", label)?;
+        }
+        for edge in &self.untaken_edges {
+            writeln!(f, "exit: {}", edge)?;
+        }
+        for fn_label in &self.uninvoked_fns {
+            writeln!(f, "fn: {}", fn_label)?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use shell::*;
+    use test_support;
+    use test_support::*;
+
+    /// `go` か `stop` を読んで、行末で `TAIL` に進む。
+    fn new_diagram() -> Diagram<Vec<String>> {
+        let mut diagram = test_support::new_diagram();
+        diagram.insert_fn("do_go", record);
+        diagram.insert_fn("do_stop", record);
+        diagram.node("HEAD").next(["TK.go", "TK.stop"]);
+        diagram.node("TK.go").token("go").fn_("do_go").newline("TAIL");
+        diagram.node("TK.stop").token("stop").fn_("do_stop").newline("TAIL");
+        diagram
+    }
+
+    #[test]
+    fn coverage_counts_nodes_edges_and_fns() {
+        let mut diagram = new_diagram();
+        let mut shell = Shell::new();
+        let mut log = Vec::new();
+        shell.set_coverage(Some(Coverage::new()));
+        shell.execute_line(&mut diagram, &mut log, "go");
+        shell.execute_line(&mut diagram, &mut log, "go");
+
+        let coverage = shell.get_coverage().unwrap();
+        assert_eq!(coverage.get_node_count("TK.go"), 2);
+        assert_eq!(coverage.get_node_count("TK.stop"), 0);
+        assert_eq!(coverage.get_edge_count("HEAD", NEXT_EXIT_LABEL, "TK.go"), 2);
+        assert_eq!(coverage.get_edge_count("TK.go", NEWLINE_EXIT_LABEL, "TAIL"), 2);
+        assert_eq!(coverage.get_fn_count("do_go"), 2);
+        assert_eq!(coverage.get_fn_count("do_stop"), 0);
+    }
+
+    #[test]
+    fn report_names_what_was_never_exercised() {
+        let mut diagram = new_diagram();
+        let mut shell = Shell::new();
+        let mut log = Vec::new();
+        shell.set_coverage(Some(Coverage::new()));
+        shell.execute_line(&mut diagram, &mut log, "go");
+
+        let report = shell.get_coverage().unwrap().report(&diagram);
+        assert!(!report.is_complete());
+        assert_eq!(report.unvisited_nodes, vec![ELSE_NODE_LABEL, "TK.stop"]);
+        assert_eq!(
+            report.untaken_edges,
+            vec![
+                ExitEdge {
+                    label: "HEAD".to_string(),
+                    door: NEXT_EXIT_LABEL.to_string(),
+                    next: "TK.stop".to_string(),
+                    guard: None,
+                },
+                ExitEdge {
+                    label: "TK.stop".to_string(),
+                    door: NEWLINE_EXIT_LABEL.to_string(),
+                    next: "TAIL".to_string(),
+                    guard: None,
+                },
+            ]
+        );
+        assert_eq!(report.uninvoked_fns, vec!["do_stop", "record_else"]);
+        assert!(format!("{}", report).starts_with("nodes: 3/5, exits: 3/5, fns: 2/4\n"));
+
+        shell.execute_line(&mut diagram, &mut log, "stop");
+        shell.execute_line(&mut diagram, &mut log, "x");
+        assert!(shell.get_coverage().unwrap().report(&diagram).is_complete());
+    }
+
+    #[test]
+    fn coverage_is_off_by_default() {
+        let mut diagram = new_diagram();
+        let mut shell = Shell::new();
+        let mut log = Vec::new();
+        shell.execute_line(&mut diagram, &mut log, "go");
+        assert!(shell.get_coverage().is_none());
+    }
+}
@@ -4,14 +4,24 @@
 extern crate serde_json;
 use serde_json::Value;
 
-use std::fs::File;
-use std::io::Read;
+use std::io;
 use std::io::Write;
 
 use models::diagram_json::*;
+use regex::Regex;
+use separator::*;
+use shell::ELSE_NODE_LABEL;
 use std::any::Any; // https://stackoverflow.com/questions/33687447/how-to-get-a-struct-reference-from-a-boxed-trait
 use std::collections::HashMap;
+use std::convert::TryFrom;
+use std::fs;
+use token_value::*;
+use transition_error::*;
 use std::fs::OpenOptions;
+use std::path::Path;
+
+/// 保存時に残しておくバックアップの世代数の既定値。
+pub const DEFAULT_BACKUP_COUNT: usize = 3;
 
 pub trait Request {
     fn as_mut_any(&mut self) -> &mut dyn Any;
@@ -19,6 +29,24 @@ pub trait Request {
     fn get_line_len(&self) -> usize;
     fn get_caret(&self) -> usize;
     fn get_groups(&self) -> &Vec<String>;
+    /// 今のノードに一致した部分の原文。
+    /// 大文字と小文字や、全角と半角を区別せずに一致させても、ここには打鍵されたままの文字が入る。
+    fn get_matched(&self) -> &str;
+    /// 型付きの値のノードなら、変換した値。
+    fn get_value(&self) -> Option<TokenValue>;
+
+    fn get_int(&self) -> Option<i64> {
+        self.get_value().and_then(|v| v.as_i64())
+    }
+    fn get_uint(&self) -> Option<u64> {
+        self.get_value().and_then(|v| v.as_u64())
+    }
+    fn get_float(&self) -> Option<f64> {
+        self.get_value().and_then(|v| v.as_f64())
+    }
+    fn get_bool(&self) -> Option<bool> {
+        self.get_value().and_then(|v| v.as_bool())
+    }
 }
 
 /// コールバック関数です。トークンを読み取った時に対応づく作業内容を書いてください。
@@ -31,25 +59,70 @@ pub trait Request {
 ///
 /// # 参考
 /// - Rustのコールバック関数について。  
-/// [2016-12-10 Idiomatic callbacks in Rust](https://stackoverflow.com/questions/41081240/idiomatic-callbacks-in-rust)
-pub type Controller<T> = fn(t: &mut T, req: &Request, res: &mut dyn Response);
+///   [2016-12-10 Idiomatic callbacks in Rust](https://stackoverflow.com/questions/41081240/idiomatic-callbacks-in-rust)
+pub type Controller<T> = fn(t: &mut T, req: &dyn Request, res: &mut dyn Response);
+
+/// 出口の行き先を、候補にしてよいか決める述語。
+/// 偽を返すと、その行き先は トークンに一致しなかったのと同じ扱いになる。
+///
+/// * `req` - 読取位置は、その行き先のトークンの先頭。
+pub type Guard<T> = fn(t: &T, req: &dyn Request) -> bool;
 
 /// シェルに指示を出す。
+#[derive(Debug, PartialEq)]
 pub enum ResponseOption {
     None,
     Quits,
     Reloads(String),
     Saves(String),
+    /// ファイル名と、何世代前のバックアップか（1 が最新）。
+    RestoresBackup(String, usize),
 }
 
 pub trait Response {
     fn as_any(&self) -> &dyn Any; // トレイトを実装している方を返すのに使う。
     fn as_mut_any(&mut self) -> &mut dyn Any; // トレイトを実装している方を返すのに使う。
-    fn set_caret(&mut self, usize);
-    fn set_done_line(&mut self, bool);
-    fn set_option(&mut self, ResponseOption);
-    // .rs にハードコーディングして使う。
-    fn forward(&mut self, &'static str);
+    fn set_caret(&mut self, caret: usize);
+    fn set_done_line(&mut self, done_line: bool);
+    fn set_option(&mut self, value: ResponseOption);
+    /// 次に進む出口。実行時に組み立てた名前でもよい。
+    fn forward(&mut self, exit_label: &str);
+    /// 出口を使わずに、このラベルのノードへ跳ぶ。次のトークンは 跳んだ先のノードの出口から探す。
+    /// 無いノードなら、何とも一致しなかったときと同じく `#else` で処理する。
+    fn jump_to(&mut self, node_label: &str);
+}
+
+/// ノードの種類。
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum NodeKind {
+    /// 全文一致させるトークン。
+    Token,
+    /// 正規表現のトークン。
+    Regex,
+    /// トークンを持たないノード。入り口や行末に使う。
+    Single,
+    /// 行の残り全部。
+    Rest,
+    /// 引用符で囲んだ文字列。
+    Quoted,
+    /// 数や真偽値に変換するトークン。
+    Value(ValueType),
+    /// 並べた固定長のトークンの、どれか１つ。
+    OneOf,
+    /// 何とも一致しなかったときの `#else` ノード。
+    Else,
+}
+
+/// 同じトークンを繰り返し読む回数。
+///
+/// # Members
+///
+/// * `min` - 最低限の回数。1 以上。
+/// * `max` - 最大の回数。 None なら行末まで。
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Repeat {
+    pub min: usize,
+    pub max: Option<usize>,
 }
 
 /// トークンと、コントローラーのペアです。
@@ -58,18 +131,88 @@ pub trait Response {
 ///
 /// * `token` - 全文一致させたい文字列です。
 /// * `fn_label` - コールバック関数の登録名です。
-/// * `regex_flag` - トークンに正規表現を使うなら真です。
+/// * `kind` - トークンの種類です。
 /// * `exit_link` - 次はどのノードにつながるか。<任意の名前, ノード名>
+/// * `separator` - トークンの後ろの区切り。未指定ならダイアグラムの区切りに従う。
+/// * `prefix_flag` - トークンの後ろに区切りが無くても一致させるなら真です。
+/// * `ignore_case` - 英字の大文字と小文字を区別しないなら真。未指定ならダイアグラムの設定に従う。
+/// * `nfkc` - Unicode の NFKC 正規化をしてから比べるなら真。未指定ならダイアグラムの設定に従う。
+/// * `min` - 値のノードで、許す最小値。
+/// * `max` - 値のノードで、許す最大値。
+/// * `choices` - どれか１つのノードで、選べるトークン。
+/// * `priority` - 次の行き先の候補が複数一致したとき、大きい方を優先する。既定値は 0 。
+/// * `terminator` - 行がこのノードで終わったら、この行が来るまでの行を つないで 1行として読む。
+/// * `repeat` - トークンを繰り返し読むなら、その回数。
+/// * `call` - 一致した後、このラベルのノードの `#next` に進む。 `#return` で、このノードの出口の行き先に戻る。
+/// * `guard_map` - 出口の行き先ごとの、述語の登録名。<出口, <行き先, 述語名>>
+/// * `on_enter` - このノードに入ったときに呼ぶ、コールバック関数の登録名。
+/// * `on_leave` - このノードから出たときに呼ぶ、コールバック関数の登録名。
+/// * `regex` - 正規表現のノードなら、トークンを設定したときにコンパイルしておいたもの。
+#[derive(Clone)]
 pub struct Node {
     // 行き先が複数パターンある。
     exit_map: HashMap<String, Vec<String>>,
+    guard_map: HashMap<String, HashMap<String, String>>,
 
     label: String,
     token: String,
     fn_label: String,
-    regex_flag: bool,
+    kind: NodeKind,
+    separator: Option<Separator>,
+    prefix_flag: bool,
+    ignore_case: Option<bool>,
+    nfkc: Option<bool>,
+    min: Option<f64>,
+    max: Option<f64>,
+    choices: Vec<String>,
+    priority: i32,
+    terminator: Option<String>,
+    repeat: Option<Repeat>,
+    call: Option<String>,
+    on_enter: Option<String>,
+    on_leave: Option<String>,
+    regex: Option<Regex>,
 }
 impl Node {
+    fn new(
+        label: &str,
+        token: String,
+        fn_label: String,
+        kind: NodeKind,
+        exit_map: HashMap<String, Vec<String>>,
+    ) -> Node {
+        let regex = Node::compile_regex(&token, kind);
+        Node {
+            label: label.to_string(),
+            token,
+            fn_label,
+            kind,
+            exit_map,
+            guard_map: HashMap::new(),
+            separator: None,
+            prefix_flag: false,
+            ignore_case: None,
+            nfkc: None,
+            min: None,
+            max: None,
+            choices: Vec::new(),
+            priority: 0,
+            terminator: None,
+            repeat: None,
+            call: None,
+            on_enter: None,
+            on_leave: None,
+            regex,
+        }
+    }
+    /// 正規表現のノードなら、コンパイルしておく。書き間違えていたら None 。
+    fn compile_regex(token: &str, kind: NodeKind) -> Option<Regex> {
+        if kind == NodeKind::Regex {
+            Regex::new(token).ok()
+        } else {
+            None
+        }
+    }
     pub fn get_label(&self) -> &str {
         &self.label
     }
@@ -80,36 +223,278 @@ impl Node {
         &self.fn_label
     }
     pub fn is_regex(&self) -> bool {
-        self.regex_flag
+        self.kind == NodeKind::Regex
+    }
+    /// 正規表現のノードなら、コンパイル済みの正規表現。
+    pub fn get_regex(&self) -> Option<&Regex> {
+        self.regex.as_ref()
+    }
+    pub fn get_kind(&self) -> NodeKind {
+        if self.label == ELSE_NODE_LABEL {
+            NodeKind::Else
+        } else if self.kind == NodeKind::Token && self.token.is_empty() {
+            NodeKind::Single
+        } else {
+            self.kind
+        }
+    }
+    /// トークンを、種類込みで表した文字列。トークンを持たないノードなら空文字列。
+    /// 比較や表示に使う。
+    pub fn get_token_caption(&self) -> String {
+        let caption = match self.get_kind() {
+            NodeKind::Token => format!("token: {}", self.token),
+            NodeKind::Regex => format!("regex: {}", self.token),
+            NodeKind::Rest => "rest".to_string(),
+            NodeKind::Quoted => "quoted".to_string(),
+            NodeKind::Value(value_type) => {
+                let mut caption = value_type.get_name().to_string();
+                if let Some(min) = self.min {
+                    caption.push_str(&format!(" min: {}", min));
+                }
+                if let Some(max) = self.max {
+                    caption.push_str(&format!(" max: {}", max));
+                }
+                caption
+            }
+            NodeKind::OneOf => format!("one_of: {}", self.choices.join("|")),
+            NodeKind::Single | NodeKind::Else => "".to_string(),
+        };
+        match self.repeat {
+            Some(Repeat { min, max: Some(max) }) if !caption.is_empty() => {
+                format!("{} repeat: {}..{}", caption, min, max)
+            }
+            Some(Repeat { min, max: None }) if !caption.is_empty() => {
+                format!("{} repeat: {}..", caption, min)
+            }
+            _ => caption,
+        }
     }
     /// 確認用。
     pub fn get_exit_map(&self) -> &HashMap<String, Vec<String>> {
         &self.exit_map
     }
     pub fn get_exit_vec(&self, door_label: &str) -> &Vec<String> {
-        if self.contains_exit(&door_label.to_string()) {
-            &self.exit_map[door_label]
-        } else {
-            panic!("\"{}\" door is not found. ({} node)", door_label, self.label);
+        match self.try_get_exit_vec(door_label) {
+            Ok(n) => n,
+            Err(err) => panic!("{}", err),
+        }
+    }
+    pub fn try_get_exit_vec(&self, door_label: &str) -> Result<&Vec<String>, TransitionError> {
+        match self.exit_map.get(door_label) {
+            Some(n) => Ok(n),
+            None => Err(TransitionError::DoorNotFound(
+                self.label.to_string(),
+                door_label.to_string(),
+            )),
         }
     }
     pub fn contains_exit(&self, name: &str) -> bool {
         self.exit_map.contains_key(name)
     }
+    /// 出口の行き先に付けた、述語の登録名。付けていなければ None 。
+    pub fn get_guard(&self, door_label: &str, next_label: &str) -> Option<&str> {
+        self.guard_map
+            .get(door_label)
+            .and_then(|guards| guards.get(next_label))
+            .map(|guard| guard.as_str())
+    }
+    /// 確認用。
+    pub fn get_guard_map(&self) -> &HashMap<String, HashMap<String, String>> {
+        &self.guard_map
+    }
+    pub(crate) fn set_token(&mut self, value: String, kind: NodeKind) {
+        self.regex = Node::compile_regex(&value, kind);
+        self.token = value;
+        self.kind = kind;
+    }
+    /// トークンの種類ごとの設定も含めて、他のノードと同じトークンにする。
+    pub(crate) fn set_token_of(&mut self, other: &Node) {
+        self.set_token(other.token.to_string(), other.kind);
+        self.min = other.min;
+        self.max = other.max;
+        self.choices = other.choices.clone();
+        self.repeat = other.repeat;
+    }
+    pub fn get_repeat(&self) -> Option<Repeat> {
+        self.repeat
+    }
+    pub(crate) fn set_repeat(&mut self, value: Option<Repeat>) {
+        self.repeat = value;
+    }
+    pub fn get_priority(&self) -> i32 {
+        self.priority
+    }
+    pub(crate) fn set_priority(&mut self, value: i32) {
+        self.priority = value;
+    }
+    pub fn get_call(&self) -> &Option<String> {
+        &self.call
+    }
+    pub(crate) fn set_call(&mut self, value: Option<String>) {
+        self.call = value;
+    }
+    pub fn get_on_enter(&self) -> &Option<String> {
+        &self.on_enter
+    }
+    pub(crate) fn set_on_enter(&mut self, value: Option<String>) {
+        self.on_enter = value;
+    }
+    pub fn get_on_leave(&self) -> &Option<String> {
+        &self.on_leave
+    }
+    pub(crate) fn set_on_leave(&mut self, value: Option<String>) {
+        self.on_leave = value;
+    }
+    pub fn get_terminator(&self) -> &Option<String> {
+        &self.terminator
+    }
+    pub(crate) fn set_terminator(&mut self, value: Option<String>) {
+        self.terminator = value;
+    }
+    pub fn get_choices(&self) -> &Vec<String> {
+        &self.choices
+    }
+    pub(crate) fn set_choices(&mut self, value: Vec<String>) {
+        self.choices = value;
+    }
+    pub fn get_min(&self) -> Option<f64> {
+        self.min
+    }
+    pub fn get_max(&self) -> Option<f64> {
+        self.max
+    }
+    pub(crate) fn set_range(&mut self, min: Option<f64>, max: Option<f64>) {
+        self.min = min;
+        self.max = max;
+    }
+    pub fn get_separator(&self) -> &Option<Separator> {
+        &self.separator
+    }
+    pub(crate) fn set_separator(&mut self, value: Option<Separator>) {
+        self.separator = value;
+    }
+    /// 前方一致でよいなら真。偽なら、トークンの後ろは区切りか行末でなければならない。
+    pub fn is_prefix(&self) -> bool {
+        self.prefix_flag
+    }
+    pub(crate) fn set_prefix(&mut self, value: bool) {
+        self.prefix_flag = value;
+    }
+    pub fn get_ignore_case(&self) -> Option<bool> {
+        self.ignore_case
+    }
+    pub(crate) fn set_ignore_case(&mut self, value: Option<bool>) {
+        self.ignore_case = value;
+    }
+    pub fn get_nfkc(&self) -> Option<bool> {
+        self.nfkc
+    }
+    pub(crate) fn set_nfkc(&mut self, value: Option<bool>) {
+        self.nfkc = value;
+    }
+    pub(crate) fn set_fn_label(&mut self, value: String) {
+        self.fn_label = value;
+    }
+    /// 出口の行き先に、述語の登録名を付ける。
+    pub(crate) fn set_guard(&mut self, door_label: &str, next_label: &str, guard: String) {
+        self.guard_map
+            .entry(door_label.to_string())
+            .or_default()
+            .insert(next_label.to_string(), guard);
+    }
+    /// トークン、コールバック関数、出口の他の設定を (名前, 値) で並べる。
+    /// 設定していないか 既定値のままなら空文字列。差分や重ね合わせで、設定が違うか比べるのに使う。
+    pub fn get_option_captions(&self) -> Vec<(&'static str, String)> {
+        fn caption<V: ToString>(value: &Option<V>) -> String {
+            value.as_ref().map(|v| v.to_string()).unwrap_or_default()
+        }
+        let separator = match self.separator {
+            Some(ref separator) => separator_caption(separator),
+            None => "".to_string(),
+        };
+        vec![
+            ("priority", if self.priority == 0 { "".to_string() } else { self.priority.to_string() }),
+            ("call", caption(&self.call)),
+            ("on_enter", caption(&self.on_enter)),
+            ("on_leave", caption(&self.on_leave)),
+            ("terminator", caption(&self.terminator)),
+            ("separator", separator),
+            ("prefix", if self.prefix_flag { "true".to_string() } else { "".to_string() }),
+            ("ignore_case", caption(&self.ignore_case)),
+            ("nfkc", caption(&self.nfkc)),
+        ]
+    }
+    /// `get_option_captions` に並ぶ名前の設定を、 `other` から写す。
+    pub(crate) fn set_option_of(&mut self, other: &Node, name: &str) {
+        match name {
+            "priority" => self.priority = other.priority,
+            "call" => self.call = other.call.clone(),
+            "on_enter" => self.on_enter = other.on_enter.clone(),
+            "on_leave" => self.on_leave = other.on_leave.clone(),
+            "terminator" => self.terminator = other.terminator.clone(),
+            "separator" => self.separator = other.separator.clone(),
+            "prefix" => self.prefix_flag = other.prefix_flag,
+            "ignore_case" => self.ignore_case = other.ignore_case,
+            "nfkc" => self.nfkc = other.nfkc,
+            _ => panic!("\"{}\" option is not found.", name),
+        }
+    }
+    /// 出口に、つながるノードを書き足す。
+    pub(crate) fn push_exit(&mut self, door_label: &str, node_label: String) {
+        self.exit_map
+            .entry(door_label.to_string())
+            .or_default()
+            .push(node_label);
+    }
 }
 
-pub fn empty_controller<T>(_t: &mut T, _req: &Request, _res: &mut dyn Response) {}
+pub fn empty_controller<T>(_t: &mut T, _req: &dyn Request, _res: &mut dyn Response) {}
 
 /// # Parameters.
 ///
 /// * `fn_map` - 任意の名前と、コントローラー。遷移先を振り分けるルーチン。
+/// * `guard_fn_map` - 任意の名前と、出口の行き先を候補にしてよいか決める述語。
 /// * `node_map` - 複数件のトークンです。
-#[derive(Default)]
+/// * `backup_count` - 保存時に残すバックアップの世代数。0 ならバックアップを取らない。
+/// * `separator` - トークンの後ろの区切り。ノードで指定があれば、そちらが優先。
+/// * `ignore_case` - トークンの英字の大文字と小文字を区別しないなら真。ノードで指定があれば、そちらが優先。
+/// * `nfkc` - トークンを NFKC 正規化してから比べるなら真。ノードで指定があれば、そちらが優先。
+/// * `backtrack` - 真なら、行末まで辿り着ける候補だけを選ぶ。行き詰まったら別の候補を試す。
+/// * `line_continuation` - 真なら、行末のバックスラッシュで 次の行とつなぐ。
 pub struct Diagram<T> {
     entry_point: String,
     node_map: HashMap<String, Node>,
+    separator: Separator,
+    ignore_case: bool,
+    nfkc: bool,
+    backtrack: bool,
+    line_continuation: bool,
 
     fn_map: HashMap<String, Controller<T>>,
+    guard_fn_map: HashMap<String, Guard<T>>,
+    backup_count: usize,
+}
+impl<T> Clone for Diagram<T> {
+    /// コントローラーは関数ポインターなので、 T が Clone でなくても複製できる。
+    fn clone(&self) -> Self {
+        Diagram {
+            entry_point: self.entry_point.clone(),
+            node_map: self.node_map.clone(),
+            separator: self.separator.clone(),
+            ignore_case: self.ignore_case,
+            nfkc: self.nfkc,
+            backtrack: self.backtrack,
+            line_continuation: self.line_continuation,
+            fn_map: self.fn_map.clone(),
+            guard_fn_map: self.guard_fn_map.clone(),
+            backup_count: self.backup_count,
+        }
+    }
+}
+impl<T> Default for Diagram<T> {
+    fn default() -> Self {
+        Self::new()
+    }
 }
 impl<T> Diagram<T> {
     /// アプリケーション１つにつき、１つのフローチャートを共有します。
@@ -117,8 +502,15 @@ impl<T> Diagram<T> {
         Diagram {
             node_map: HashMap::new(),
             entry_point: "".to_string(),
+            separator: Separator::default(),
+            ignore_case: false,
+            nfkc: false,
+            backtrack: false,
+            line_continuation: false,
 
             fn_map: HashMap::new(),
+            guard_fn_map: HashMap::new(),
+            backup_count: DEFAULT_BACKUP_COUNT,
         }
     }
     /// 確認用。
@@ -129,6 +521,82 @@ impl<T> Diagram<T> {
     pub fn clear(&mut self) {
         self.entry_point = "".to_string();
         self.node_map.clear();
+        self.separator = Separator::default();
+        self.ignore_case = false;
+        self.nfkc = false;
+        self.backtrack = false;
+        self.line_continuation = false;
+    }
+    pub fn get_separator(&self) -> &Separator {
+        &self.separator
+    }
+    pub fn set_separator(&mut self, value: Separator) {
+        self.separator = value;
+    }
+    pub fn is_ignore_case(&self) -> bool {
+        self.ignore_case
+    }
+    pub fn set_ignore_case(&mut self, value: bool) {
+        self.ignore_case = value;
+    }
+    pub fn is_nfkc(&self) -> bool {
+        self.nfkc
+    }
+    pub fn set_nfkc(&mut self, value: bool) {
+        self.nfkc = value;
+    }
+    pub fn is_backtrack(&self) -> bool {
+        self.backtrack
+    }
+    pub fn set_backtrack(&mut self, value: bool) {
+        self.backtrack = value;
+    }
+    pub fn is_line_continuation(&self) -> bool {
+        self.line_continuation
+    }
+    pub fn set_line_continuation(&mut self, value: bool) {
+        self.line_continuation = value;
+    }
+    /// ダイアグラム全体の設定を (名前, 値) で並べる。既定値のままなら空文字列。差分で使う。
+    pub fn get_setting_captions(&self) -> Vec<(&'static str, String)> {
+        fn flag(value: bool) -> String {
+            if value { "true".to_string() } else { "".to_string() }
+        }
+        vec![
+            (
+                "separator",
+                if self.separator == Separator::default() {
+                    "".to_string()
+                } else {
+                    separator_caption(&self.separator)
+                },
+            ),
+            ("ignore_case", flag(self.ignore_case)),
+            ("nfkc", flag(self.nfkc)),
+            ("backtrack", flag(self.backtrack)),
+            ("line_continuation", flag(self.line_continuation)),
+        ]
+    }
+    /// ノードのトークンを、大文字と小文字を区別せずに比べるなら真。
+    pub fn is_ignore_case_of(&self, node: &Node) -> bool {
+        node.get_ignore_case().unwrap_or(self.ignore_case)
+    }
+    /// ノードのトークンを、 NFKC 正規化してから比べるなら真。
+    pub fn is_nfkc_of(&self, node: &Node) -> bool {
+        node.get_nfkc().unwrap_or(self.nfkc)
+    }
+    /// ノードのトークンの後ろで使う区切り。ノードで指定が無ければ、ダイアグラムの区切り。
+    pub fn get_separator_of<'a>(&'a self, node: &'a Node) -> &'a Separator {
+        match *node.get_separator() {
+            Some(ref separator) => separator,
+            None => &self.separator,
+        }
+    }
+    pub fn get_backup_count(&self) -> usize {
+        self.backup_count
+    }
+    pub fn set_backup_count(&mut self, value: usize) {
+        self.backup_count = value;
     }
     pub fn get_entry_point(&self) -> String {
         self.entry_point.to_string()
@@ -137,29 +605,94 @@ impl<T> Diagram<T> {
         self.entry_point = value;
     }
     pub fn get_node(&self, node_label: &str) -> &Node {
-        if self.contains_node(&node_label.to_string()) {
-            &self.node_map[node_label]
-        } else {
-            panic!("\"{}\" node is not found.", node_label);
+        match self.try_get_node(node_label) {
+            Ok(n) => n,
+            Err(err) => panic!("{}", err),
+        }
+    }
+    pub fn try_get_node(&self, node_label: &str) -> Result<&Node, TransitionError> {
+        match self.node_map.get(node_label) {
+            Some(n) => Ok(n),
+            None => Err(TransitionError::NodeNotFound(node_label.to_string())),
+        }
+    }
+    pub(crate) fn get_node_mut(&mut self, node_label: &str) -> &mut Node {
+        match self.node_map.get_mut(node_label) {
+            Some(n) => n,
+            None => panic!("\"{}\" node is not found.", node_label),
         }
     }
     pub fn contains_node(&self, node_label: &str) -> bool {
-        self.node_map.contains_key(&node_label.to_string())
+        self.node_map.contains_key(node_label)
+    }
+    /// `terminator` を持つノードが 1つでもあれば真。
+    pub fn has_terminator(&self) -> bool {
+        self.node_map
+            .values()
+            .any(|node| node.get_terminator().is_some())
     }
 
     pub fn get_fn(&self, name: &str) -> &Controller<T> {
-        match self.fn_map.get(&name.to_string()) {
-            Some(f) => &f,
+        match self.fn_map.get(name) {
+            Some(f) => f,
             None => panic!("\"{}\" fn is not found. Please use contains_fn().", name),
         }
     }
+    pub fn try_get_fn(&self, name: &str) -> Result<&Controller<T>, TransitionError> {
+        match self.fn_map.get(name) {
+            Some(f) => Ok(f),
+            None => Err(TransitionError::FnNotFound(name.to_string())),
+        }
+    }
+    /// 登録済みのコントローラー。
+    pub(crate) fn get_fn_map(&self) -> &HashMap<String, Controller<T>> {
+        &self.fn_map
+    }
+    pub(crate) fn insert_fn_string(&mut self, name: String, fn2: Controller<T>) {
+        self.fn_map.insert(name, fn2);
+    }
     pub fn contains_fn(&self, name: &str) -> bool {
-        self.fn_map.contains_key(&name.to_string())
+        self.fn_map.contains_key(name)
     }
     /// name は ハードコーディングするので、 &'static str にする。
     pub fn insert_fn(&mut self, name: &'static str, fn2: Controller<T>) {
         self.fn_map.insert(name.to_string(), fn2);
     }
+    pub fn get_guard_fn(&self, name: &str) -> &Guard<T> {
+        match self.guard_fn_map.get(name) {
+            Some(f) => f,
+            None => panic!("\"{}\" guard is not found. Please use contains_guard_fn().", name),
+        }
+    }
+    /// 登録済みの述語。
+    pub(crate) fn get_guard_fn_map(&self) -> &HashMap<String, Guard<T>> {
+        &self.guard_fn_map
+    }
+    pub(crate) fn insert_guard_fn_string(&mut self, name: String, guard: Guard<T>) {
+        self.guard_fn_map.insert(name, guard);
+    }
+    pub fn contains_guard_fn(&self, name: &str) -> bool {
+        self.guard_fn_map.contains_key(name)
+    }
+    /// name は ハードコーディングするので、 &'static str にする。
+    pub fn insert_guard_fn(&mut self, name: &'static str, guard: Guard<T>) {
+        self.guard_fn_map.insert(name.to_string(), guard);
+    }
+    /// 述語を呼んで、出口の行き先を候補にしてよいか決める。登録されていない述語なら偽。
+    pub fn passes_guard(&self, guard: &str, t: &T, req: &dyn Request) -> bool {
+        self.try_passes_guard(guard, t, req).unwrap_or(false)
+    }
+    /// `passes_guard` の、述語が登録されていなければ Err を返す版。
+    pub fn try_passes_guard(&self, guard: &str, t: &T, req: &dyn Request) -> Result<bool, TransitionError> {
+        match self.guard_fn_map.get(guard) {
+            Some(f) => Ok(f(t, req)),
+            None => Err(TransitionError::GuardNotFound(guard.to_string())),
+        }
+    }
+    /// 出来上がっているノードを、そのまま登録する。
+    pub(crate) fn insert_node_struct(&mut self, node: Node) {
+        self.node_map.insert(node.get_label().to_string(), node);
+    }
     /// # Arguments
     ///
     /// * `label2` - 登録用のノード名です。
@@ -173,16 +706,10 @@ impl<T> Diagram<T> {
     ) {
         self.node_map.insert(
             label2.to_string(),
-            Node {
-                label: label2.to_string(),
-                token: token2,
-                fn_label: fn_label2,
-                regex_flag: false,
-                exit_map: exit_map2,
-            },
+            Node::new(label2, token2, fn_label2, NodeKind::Token, exit_map2),
         );
     }
-    /// 正規表現を使うなら。
+    /// 正規表現を使うなら。正規表現を書き間違えていたら、ここで止める。
     ///
     /// # Arguments
     ///
@@ -196,15 +723,12 @@ impl<T> Diagram<T> {
         fn_label2: String,
         exit_map2: HashMap<String, Vec<String>>,
     ) {
+        if let Err(err) = Regex::new(&token2) {
+            panic!("Regex error. ({} node) {}", label, err);
+        }
         self.node_map.insert(
             label.to_string(),
-            Node {
-                label: label.to_string(),
-                token: token2,
-                fn_label: fn_label2,
-                regex_flag: true,
-                exit_map: exit_map2,
-            },
+            Node::new(label, token2, fn_label2, NodeKind::Regex, exit_map2),
         );
     }
     /// パーサーしないノード。任意の名前とコントローラーのマッピング。
@@ -221,15 +745,94 @@ impl<T> Diagram<T> {
         // let exit_map2: HashMap<String, Vec<String>> = [].iter().cloned().collect();
         self.node_map.insert(
             label.to_string(),
-            Node {
-                label: label.to_string(),
-                token: "".to_string(),
-                fn_label: fn_label2,
-                regex_flag: false,
-                exit_map: exit_map2,
-            },
+            Node::new(label, "".to_string(), fn_label2, NodeKind::Single, exit_map2),
+        );
+    }
+    /// 行の残り全部を取るノード。
+    /// 先頭の区切りを除いた残りを groups[0] に入れて コールバック関数に渡し、行を読み終える。
+    ///
+    /// # Arguments
+    ///
+    /// * `label` - 登録用のノード名です。
+    pub fn insert_node_rest(
+        &mut self,
+        label: &str,
+        fn_label2: String,
+        exit_map2: HashMap<String, Vec<String>>,
+    ) {
+        self.node_map.insert(
+            label.to_string(),
+            Node::new(label, "".to_string(), fn_label2, NodeKind::Rest, exit_map2),
         );
     }
+    /// 引用符で囲んだ文字列を取るノード。
+    /// 二重引用符か一重引用符で囲み、中ではバックスラッシュで引用符などをエスケープできる。
+    /// エスケープを解いた中身を groups[0] に入れて コールバック関数に渡す。
+    ///
+    /// # Arguments
+    ///
+    /// * `label` - 登録用のノード名です。
+    pub fn insert_node_quoted(
+        &mut self,
+        label: &str,
+        fn_label2: String,
+        exit_map2: HashMap<String, Vec<String>>,
+    ) {
+        self.node_map.insert(
+            label.to_string(),
+            Node::new(label, "".to_string(), fn_label2, NodeKind::Quoted, exit_map2),
+        );
+    }
+    /// 数や真偽値に変換するノード。
+    /// 変換できないときや、範囲を外れたときは一致しない。
+    /// 変換した値は `Request::get_value` で、原文は groups[0] で コールバック関数に渡す。
+    ///
+    /// # Arguments
+    ///
+    /// * `label` - 登録用のノード名です。
+    /// * `value_type` - 値の種類です。
+    /// * `min` - 許す最小値です。真偽値では無視します。
+    /// * `max` - 許す最大値です。真偽値では無視します。
+    pub fn insert_node_value(
+        &mut self,
+        label: &str,
+        value_type: ValueType,
+        min: Option<f64>,
+        max: Option<f64>,
+        fn_label2: String,
+        exit_map2: HashMap<String, Vec<String>>,
+    ) {
+        let mut node = Node::new(
+            label,
+            "".to_string(),
+            fn_label2,
+            NodeKind::Value(value_type),
+            exit_map2,
+        );
+        node.set_range(min, max);
+        self.node_map.insert(label.to_string(), node);
+    }
+    /// 並べたトークンの、どれか１つに一致するノード。
+    /// 複数に一致するなら長い方を選び、選んだトークンを groups[0] に入れて コールバック関数に渡す。
+    ///
+    /// # Arguments
+    ///
+    /// * `label` - 登録用のノード名です。
+    /// * `choices` - 選べるトークンです。
+    pub fn insert_node_one_of(
+        &mut self,
+        label: &str,
+        choices: Vec<String>,
+        fn_label2: String,
+        exit_map2: HashMap<String, Vec<String>>,
+    ) {
+        if choices.is_empty() {
+            panic!("one_of is empty. ({} node)", label);
+        }
+        let mut node = Node::new(label, "".to_string(), fn_label2, NodeKind::OneOf, exit_map2);
+        node.set_choices(choices);
+        self.node_map.insert(label.to_string(), node);
+    }
 
     /*
     /// JSON配列を、文字列の配列に変換。
@@ -246,88 +849,208 @@ impl<T> Diagram<T> {
     }
     */
     /// JSONオブジェクトを、文字列のハッシュマップに変換。
+    /// 行き先は、ラベルの文字列か、述語付きの `{"label": ..., "guard": ...}` 。
     ///
     /// # Arguments.
     ///
     /// * 'v' - Json object.
     /// * 'str_vec' - let str_vec = Vec::new();
-    fn object_to_map(&self, obj: &Value, map0: &mut HashMap<String, Vec<String>>) {
+    /// * returns - オブジェクトでなかったり、ラベルの無い行き先があれば Err 。
+    fn object_to_map(
+        &self,
+        obj: &Value,
+        map0: &mut HashMap<String, Vec<String>>,
+    ) -> Result<(), io::Error> {
         if !obj.is_null() {
-            for (name1, array1) in obj.as_object().unwrap().iter() {
+            let obj = obj
+                .as_object()
+                .ok_or_else(|| invalid_data("\"exit\" is not an object.".to_string()))?;
+            for (name1, array1) in obj.iter() {
+                let array1 = array1
+                    .as_array()
+                    .ok_or_else(|| invalid_data(format!("\"{}\" exit is not an array.", name1)))?;
                 let mut array2: Vec<String> = Vec::new();
-                for item1 in array1.as_array().unwrap().iter() {
-                    array2.push(item1.as_str().unwrap().to_string());
+                for item1 in array1.iter() {
+                    let label = if item1.is_object() {
+                        item1["label"].as_str()
+                    } else {
+                        item1.as_str()
+                    };
+                    match label {
+                        Some(label) => array2.push(label.to_string()),
+                        None => {
+                            return Err(invalid_data(format!(
+                                "\"{}\" exit has an item without label.",
+                                name1
+                            )))
+                        }
+                    }
                 }
                 map0.insert(name1.to_string(), array2);
             }
         }
+        Ok(())
     }
 
-    /// ファイル読み込み
+    /// ファイル読み込み。読めなければ止める。
     pub fn read_file(&mut self, file: &str) {
-        self.clear();
-
-        let mut file = match File::open(file) {
-            Ok(n) => n,
-            Err(err) => panic!("File open error. {:?}", err),
-        };
+        if let Err(err) = self.try_read_file(file) {
+            panic!("File open error. {}", err);
+        }
+    }
 
-        let mut data = String::new();
-        match file.read_to_string(&mut data) {
-            Ok(n) => n,
-            Err(err) => panic!("File open error. {:?}", err),
-        };
+    /// `read_file` の、読めなければ Err を返す版。ダイアグラムは元のまま。
+    pub fn try_read_file(&mut self, file: &str) -> Result<(), io::Error> {
+        let data = fs::read_to_string(file)
+            .map_err(|err| io::Error::new(err.kind(), format!("{} {}", file, err)))?;
+        self.read_str(&data)
+            .map_err(|err| io::Error::new(err.kind(), format!("{} {}", file, err)))
+    }
 
+    /// diagram.json の中身を読み込む。
+    /// 複製に読み込んでから差し替えるので、書き間違いがあれば Err を返して、ダイアグラムは元のまま。
+    /// 登録したコールバック関数や述語は そのまま残る。
+    pub fn read_str(&mut self, data: &str) -> Result<(), io::Error> {
         // https://docs.serde.rs/serde_json/value/enum.Value.html
-        let v: Value = match serde_json::from_str(&data) {
-            Ok(n) => n,
-            Err(err) => panic!("File open error. {:?}", err),
-        };
+        let v: Value = serde_json::from_str(data)?;
 
+        let mut diagram = self.clone();
+        diagram.clear();
+        diagram.read_value(&v)?;
+        *self = diagram;
+        Ok(())
+    }
+
+    /// 空のダイアグラムに、JSONの中身を読み込む。
+    fn read_value(&mut self, v: &Value) -> Result<(), io::Error> {
         // エントリー・ポイント取得。
-        self.entry_point = v["entry_point"].as_str().unwrap().to_string();
+        self.entry_point = v["entry_point"]
+            .as_str()
+            .ok_or_else(|| invalid_data("\"entry_point\" is not found.".to_string()))?
+            .to_string();
+        if let Some(separator) = read_separator(v)? {
+            self.separator = separator;
+        }
+        self.ignore_case = v["ignore_case"].as_bool().unwrap_or(false);
+        self.nfkc = v["nfkc"].as_bool().unwrap_or(false);
+        self.backtrack = v["backtrack"].as_bool().unwrap_or(false);
+        self.line_continuation = v["line_continuation"].as_bool().unwrap_or(false);
 
-        for node in v["nodes"].as_array().unwrap().iter() {
+        let nodes = v["nodes"]
+            .as_array()
+            .ok_or_else(|| invalid_data("\"nodes\" is not found.".to_string()))?;
+        for node in nodes.iter() {
+            let label = node["label"]
+                .as_str()
+                .ok_or_else(|| invalid_data("A node has no \"label\".".to_string()))?;
+            let fn_label = if node["fn"].is_null() {
+                "".to_string()
+            } else {
+                get_str(node, "fn", label)?.to_string()
+            };
             let mut exit_map: HashMap<String, Vec<String>> = HashMap::new();
-            self.object_to_map(&node["exit"], &mut exit_map);
+            self.object_to_map(&node["exit"], &mut exit_map)?;
             if !node["token"].is_null() {
-                self.insert_node(
-                    &node["label"].as_str().unwrap().to_string(),
-                    node["token"].as_str().unwrap().to_string(),
-                    if node["fn"].is_null() {
-                        "".to_string()
-                    } else {
-                        node["fn"].as_str().unwrap().to_string()
-                    },
-                    exit_map,
-                );
+                self.insert_node(label, get_str(node, "token", label)?.to_string(), fn_label, exit_map);
             } else if !node["regex"].is_null() {
-                self.insert_node_reg(
-                    &node["label"].as_str().unwrap().to_string(),
-                    node["regex"].as_str().unwrap().to_string(),
-                    if node["fn"].is_null() {
-                        "".to_string()
-                    } else {
-                        node["fn"].as_str().unwrap().to_string()
-                    },
+                let regex = get_str(node, "regex", label)?;
+                if let Err(err) = Regex::new(regex) {
+                    return Err(invalid_data(format!("Regex error. ({} node) {}", label, err)));
+                }
+                self.insert_node_reg(label, regex.to_string(), fn_label, exit_map);
+            } else if node["rest"].as_bool() == Some(true) {
+                self.insert_node_rest(label, fn_label, exit_map);
+            } else if !node["one_of"].is_null() {
+                let choices = node["one_of"]
+                    .as_array()
+                    .and_then(|choices| {
+                        choices
+                            .iter()
+                            .map(|choice| choice.as_str().map(|s| s.to_string()))
+                            .collect::<Option<Vec<String>>>()
+                    })
+                    .ok_or_else(|| {
+                        invalid_data(format!("\"one_of\" is not an array of strings. ({} node)", label))
+                    })?;
+                if choices.is_empty() {
+                    return Err(invalid_data(format!("one_of is empty. ({} node)", label)));
+                }
+                self.insert_node_one_of(label, choices, fn_label, exit_map);
+            } else if !node["type"].is_null() {
+                let name = get_str(node, "type", label)?;
+                let value_type = ValueType::try_from_name(name).ok_or_else(|| {
+                    invalid_data(format!("\"{}\" type is not found. ({} node)", name, label))
+                })?;
+                self.insert_node_value(
+                    label,
+                    value_type,
+                    node["min"].as_f64(),
+                    node["max"].as_f64(),
+                    fn_label,
                     exit_map,
                 );
+            } else if node["quoted"].as_bool() == Some(true) {
+                self.insert_node_quoted(label, fn_label, exit_map);
             } else {
-                self.insert_node_single(
-                    &node["label"].as_str().unwrap().to_string(),
-                    if node["fn"].is_null() {
-                        "".to_string()
-                    } else {
-                        node["fn"].as_str().unwrap().to_string()
-                    },
-                    exit_map,
-                );
+                self.insert_node_single(label, fn_label, exit_map);
+            }
+            self.read_node_options(label, node)?;
+        }
+        Ok(())
+    }
+    /// トークンの種類に関わらず書ける、ノードの追加の設定を読み取る。
+    fn read_node_options(&mut self, label: &str, node: &Value) -> Result<(), io::Error> {
+        let separator = read_separator(node)?;
+        self.get_node_mut(label).set_separator(separator);
+        let prefix = node["prefix"].as_bool().unwrap_or(false);
+        self.get_node_mut(label).set_prefix(prefix);
+        self.get_node_mut(label)
+            .set_ignore_case(node["ignore_case"].as_bool());
+        self.get_node_mut(label).set_nfkc(node["nfkc"].as_bool());
+        let priority = read_priority(node, label)?;
+        self.get_node_mut(label).set_priority(priority);
+        self.get_node_mut(label)
+            .set_terminator(node["terminator"].as_str().map(|s| s.to_string()));
+        self.get_node_mut(label)
+            .set_call(node["call"].as_str().map(|s| s.to_string()));
+        self.get_node_mut(label)
+            .set_on_enter(node["on_enter"].as_str().map(|s| s.to_string()));
+        self.get_node_mut(label)
+            .set_on_leave(node["on_leave"].as_str().map(|s| s.to_string()));
+        if !node["repeat"].is_null() {
+            let repeat = read_repeat(&node["repeat"], label)?;
+            self.get_node_mut(label).set_repeat(Some(repeat));
+        }
+        // 出口の形は object_to_map で確かめてある。
+        if let Some(exit_obj) = node["exit"].as_object() {
+            for (door_label, array) in exit_obj.iter() {
+                for item in array.as_array().into_iter().flatten() {
+                    if let (Some(next_label), Some(guard)) =
+                        (item["label"].as_str(), item["guard"].as_str())
+                    {
+                        self.get_node_mut(label).set_guard(
+                            door_label,
+                            next_label,
+                            guard.to_string(),
+                        );
+                    }
+                }
             }
         }
+        Ok(())
     }
+    /// ファイル上書き書込。書けなければ止める。
+    pub fn write_file(&self, file: &str) {
+        if let Err(err) = self.try_write_file(file) {
+            panic!("File write error. {}", err);
+        }
+    }
+
     /// ファイル上書き書込。
+    /// 一時ファイルに書いてからリネームで差し替え、元のファイルはバックアップとして残す。
+    /// 書けなければ Err を返す。一時ファイルは消し、元のファイルは そのまま。
     /// https://qiita.com/garkimasera/items/0442ee896403c6b78fb2 |JSON文字列と構造体の相互変換
-    pub fn write_file(&mut self, file: &str) {
+    pub fn try_write_file(&self, file: &str) -> Result<(), io::Error> {
         // 移し替え。
         let mut diagram_json = DiagramJson::new();
         // エントランス
@@ -335,41 +1058,443 @@ impl<T> Diagram<T> {
             let entry_point = &self.entry_point;
             diagram_json.set_entry_point(entry_point.to_string());
         }
+        if self.separator != Separator::default() {
+            diagram_json.set_separator(&self.separator);
+        }
+        if self.ignore_case {
+            diagram_json.set_ignore_case(Some(true));
+        }
+        if self.nfkc {
+            diagram_json.set_nfkc(Some(true));
+        }
+        if self.backtrack {
+            diagram_json.set_backtrack(Some(true));
+        }
+        if self.line_continuation {
+            diagram_json.set_line_continuation(Some(true));
+        }
 
         // ノード
         for (node_label, node) in &self.node_map {
             let mut node_json = NodeJson::new();
             node_json.set_label(node_label.to_string());
-            if node.is_regex() {
-                node_json.set_regex(Some(node.get_token().to_string()));
-            } else if node.get_token() != "" {
-                node_json.set_token(Some(node.get_token().to_string()));
+            match node.get_kind() {
+                NodeKind::Token => node_json.set_token(Some(node.get_token().to_string())),
+                NodeKind::Regex => node_json.set_regex(Some(node.get_token().to_string())),
+                NodeKind::Rest => node_json.set_rest(Some(true)),
+                NodeKind::Quoted => node_json.set_quoted(Some(true)),
+                NodeKind::Value(value_type) => {
+                    node_json.set_value_type(Some(value_type.get_name().to_string()));
+                    node_json.set_min(node.get_min());
+                    node_json.set_max(node.get_max());
+                }
+                NodeKind::OneOf => node_json.set_one_of(Some(node.get_choices().clone())),
+                NodeKind::Single | NodeKind::Else => {}
             }
             if node.get_fn_label() != "" {
                 node_json.set_fnc(Some(node.get_fn_label().to_string()));
             }
+            if let Some(ref separator) = *node.get_separator() {
+                node_json.set_separator(separator);
+            }
+            if node.is_prefix() {
+                node_json.set_prefix(Some(true));
+            }
+            node_json.set_ignore_case(node.get_ignore_case());
+            node_json.set_nfkc(node.get_nfkc());
+            if node.get_priority() != 0 {
+                node_json.set_priority(Some(node.get_priority()));
+            }
+            node_json.set_terminator(node.get_terminator().clone());
+            node_json.set_call(node.get_call().clone());
+            node_json.set_on_enter(node.get_on_enter().clone());
+            node_json.set_on_leave(node.get_on_leave().clone());
+            if let Some(repeat) = node.get_repeat() {
+                node_json.set_repeat(Some(RepeatJson::new(repeat.min, repeat.max)));
+            }
 
             for (exit_label, node_vec) in node.get_exit_map().iter() {
                 let mut vec = Vec::new();
                 for exit_node in node_vec.iter() {
-                    vec.push(exit_node.to_string());
+                    vec.push(match node.get_guard(exit_label, exit_node) {
+                        Some(guard) => ExitJson::Guarded(GuardedExitJson::new(
+                            exit_node.to_string(),
+                            guard.to_string(),
+                        )),
+                        None => ExitJson::Label(exit_node.to_string()),
+                    });
                 }
                 node_json.insert_exit(&exit_label.to_string(), vec);
             }
 
             diagram_json.push_node(node_json);
         }
-        let json_str = serde_json::to_string(&diagram_json).unwrap();
+        let json_str = serde_json::to_string(&diagram_json)?;
+
+        // 一時ファイルに書いてから差し替える。書込み途中で落ちても、元のファイルは壊れない。
+        self.replace_file(file, json_str.as_bytes())
+            .map_err(|err| io::Error::new(err.kind(), format!("{} {}", file, err)))
+    }
+
+    /// バックアップからファイルを復元し、読み込み直す。
+    /// 復元する前のファイルも、バックアップの最新世代として残る。
+    ///
+    /// # Arguments.
+    ///
+    /// * 'file' - 復元先のファイル名。
+    /// * 'generation' - 何世代前のバックアップか。1 が最新。
+    /// * returns - 世代が 0 か 残す世代数より大きいとき、バックアップが無いか読めないときは Err 。ファイルもダイアグラムも そのまま。
+    pub fn restore_backup(&mut self, file: &str, generation: usize) -> Result<(), io::Error> {
+        if generation == 0 || self.backup_count < generation {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!(
+                    "Backup generation {} is out of range. (1..={})",
+                    generation, self.backup_count
+                ),
+            ));
+        }
+        let backup_file = backup_file_name(file, generation);
+        let data = fs::read_to_string(&backup_file)
+            .map_err(|err| io::Error::new(err.kind(), format!("{} {}", backup_file, err)))?;
 
-        // 上書き書込。
-        match &mut OpenOptions::new()
+        // 読めないバックアップなら、ファイルを差し替える前に止める。
+        let mut restored = self.clone();
+        restored
+            .read_str(&data)
+            .map_err(|err| io::Error::new(err.kind(), format!("{} {}", backup_file, err)))?;
+        self.replace_file(file, data.as_bytes())?;
+        *self = restored;
+        Ok(())
+    }
+
+    /// 一時ファイルに書き出し、バックアップを回してから、リネームで差し替える。
+    /// 途中で失敗したら、一時ファイルは消す。
+    fn replace_file(&self, file: &str, contents: &[u8]) -> Result<(), io::Error> {
+        let temp_file = format!("{}.tmp", file);
+        let result = self.write_and_rename(&temp_file, file, contents);
+        if result.is_err() && Path::new(&temp_file).exists() {
+            let _ = fs::remove_file(&temp_file);
+        }
+        result
+    }
+    fn write_and_rename(&self, temp_file: &str, file: &str, contents: &[u8]) -> Result<(), io::Error> {
+        let mut contents_file = OpenOptions::new()
             .create(true)
             .write(true)
             .truncate(true)
-            .open(file)
-        {
-            Ok(contents_file) => contents_file.write_all(json_str.as_bytes()),
-            Err(err) => panic!("Log file open (write mode) error. {}", err),
-        };
+            .open(temp_file)?;
+        contents_file.write_all(contents)?;
+        contents_file.sync_all()?;
+
+        self.rotate_backups(file)?;
+        fs::rename(temp_file, file)
+    }
+
+    /// 古いバックアップを1世代ずつずらし、現在のファイルを最新のバックアップとしてコピーする。
+    /// 現在のファイルはコピーするだけなので、差し替えるまで消えない。
+    fn rotate_backups(&self, file: &str) -> Result<(), io::Error> {
+        if self.backup_count == 0 || !Path::new(file).exists() {
+            return Ok(());
+        }
+
+        let oldest = backup_file_name(file, self.backup_count);
+        if Path::new(&oldest).exists() {
+            fs::remove_file(&oldest)?;
+        }
+        for generation in (1..self.backup_count).rev() {
+            let from = backup_file_name(file, generation);
+            if Path::new(&from).exists() {
+                fs::rename(&from, backup_file_name(file, generation + 1))?;
+            }
+        }
+
+        fs::copy(file, backup_file_name(file, 1))?;
+        Ok(())
+    }
+}
+
+/// 区切りの表示。文字で区切るなら、その文字も付ける。
+fn separator_caption(separator: &Separator) -> String {
+    match *separator {
+        Separator::Chars(ref chars) => format!("chars: {}", chars),
+        ref separator => separator.get_name().to_string(),
+    }
+}
+
+/// diagram.json の書き間違い。
+fn invalid_data(message: String) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message)
+}
+
+/// ノードに書いた文字列。文字列でなければ Err 。
+fn get_str<'a>(node: &'a Value, key: &str, label: &str) -> Result<&'a str, io::Error> {
+    node[key]
+        .as_str()
+        .ok_or_else(|| invalid_data(format!("\"{}\" is not a string. ({} node)", key, label)))
+}
+
+/// `"priority"` を読む。書いていなければ 0 。整数でないか i32 に収まらなければ Err 。
+fn read_priority(node: &Value, label: &str) -> Result<i32, io::Error> {
+    if node["priority"].is_null() {
+        return Ok(0);
+    }
+    let priority = node["priority"]
+        .as_i64()
+        .ok_or_else(|| invalid_data(format!("\"priority\" is not an integer. ({} node)", label)))?;
+    i32::try_from(priority)
+        .map_err(|_| invalid_data(format!("\"priority\" is out of range. ({} node)", label)))
+}
+
+/// `"repeat"` を読む。 `min` を書いていなければ 1 、 `max` を書いていなければ行末まで。
+/// 回数が正の整数でないか、 `max` が `min` より小さければ Err 。
+fn read_repeat(v: &Value, label: &str) -> Result<Repeat, io::Error> {
+    if !v.is_object() {
+        return Err(invalid_data(format!("\"repeat\" is not an object. ({} node)", label)));
+    }
+    let count = |key: &str| -> Result<Option<usize>, io::Error> {
+        if v[key].is_null() {
+            return Ok(None);
+        }
+        v[key]
+            .as_u64()
+            .and_then(|n| usize::try_from(n).ok())
+            .filter(|&n| 0 < n)
+            .map(Some)
+            .ok_or_else(|| {
+                invalid_data(format!("\"repeat\" {} is not a positive integer. ({} node)", key, label))
+            })
+    };
+    let repeat = Repeat {
+        min: count("min")?.unwrap_or(1),
+        max: count("max")?,
+    };
+    if let Some(max) = repeat.max {
+        if max < repeat.min {
+            return Err(invalid_data(format!("Repeat range is empty. ({} node)", label)));
+        }
+    }
+    Ok(repeat)
+}
+
+/// `"separator"` と `"separator_chars"` を読む。書いていなければ None 。
+fn read_separator(v: &Value) -> Result<Option<Separator>, io::Error> {
+    Separator::try_from_name(v["separator"].as_str(), v["separator_chars"].as_str())
+        .map_err(|name| invalid_data(format!("\"{}\" separator is not found.", name)))
+}
+
+/// バックアップのファイル名。 `diagram.json` の 1世代前なら `diagram.json.1` 。
+pub fn backup_file_name(file: &str, generation: usize) -> String {
+    format!("{}.{}", file, generation)
+}
+
+/// 存在するバックアップのファイル名を、新しい順に返す。
+pub fn list_backups(file: &str) -> Vec<String> {
+    let mut vec = Vec::new();
+    let mut generation = 1;
+    loop {
+        let backup_file = backup_file_name(file, generation);
+        if !Path::new(&backup_file).exists() {
+            break;
+        }
+        vec.push(backup_file);
+        generation += 1;
+    }
+    vec
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::env;
+    use std::process;
+
+    /// テストごとに別のディレクトリに置く、ダイアグラムのファイル名。
+    fn temp_file(name: &str) -> String {
+        let dir = env::temp_dir().join(format!("kifuwarabe_shell_{}_{}", name, process::id()));
+        if dir.exists() {
+            fs::remove_dir_all(&dir).unwrap();
+        }
+        fs::create_dir_all(&dir).unwrap();
+        dir.join("diagram.json").to_str().unwrap().to_string()
+    }
+
+    /// 入り口だけを変えて、続けて保存する。
+    fn save_entry_points(file: &str, entry_points: &[&str]) -> Diagram<()> {
+        let mut diagram = Diagram::new();
+        for entry_point in entry_points {
+            diagram.set_entry_point(entry_point.to_string());
+            diagram.write_file(file);
+        }
+        diagram
+    }
+
+    fn read_entry_point(file: &str) -> String {
+        let mut diagram: Diagram<()> = Diagram::new();
+        diagram.read_file(file);
+        diagram.get_entry_point()
+    }
+
+    #[test]
+    fn write_file_keeps_numbered_backups() {
+        let file = temp_file("backups");
+        save_entry_points(&file, &["A", "B", "C", "D", "E"]);
+
+        assert_eq!(read_entry_point(&file), "E");
+        assert_eq!(
+            list_backups(&file),
+            vec![
+                backup_file_name(&file, 1),
+                backup_file_name(&file, 2),
+                backup_file_name(&file, 3)
+            ]
+        );
+        assert_eq!(read_entry_point(&backup_file_name(&file, 1)), "D");
+        assert_eq!(read_entry_point(&backup_file_name(&file, 3)), "B");
+    }
+
+    #[test]
+    fn restore_backup_reads_the_generation_and_backs_up_the_current_file() {
+        let file = temp_file("restore");
+        let mut diagram = save_entry_points(&file, &["A", "B", "C"]);
+
+        diagram.restore_backup(&file, 2).unwrap();
+        assert_eq!(diagram.get_entry_point(), "A");
+        assert_eq!(read_entry_point(&file), "A");
+        assert_eq!(read_entry_point(&backup_file_name(&file, 1)), "C");
+    }
+
+    #[test]
+    fn restore_backup_rejects_out_of_range_generations() {
+        let file = temp_file("restore_range");
+        let mut diagram = save_entry_points(&file, &["A", "B"]);
+
+        for generation in &[0, 4, usize::MAX] {
+            let err = diagram.restore_backup(&file, *generation).unwrap_err();
+            assert_eq!(err.kind(), io::ErrorKind::InvalidInput);
+        }
+        assert_eq!(diagram.get_entry_point(), "B");
+        assert_eq!(read_entry_point(&file), "B");
+    }
+
+    #[test]
+    fn restore_backup_reports_a_missing_generation() {
+        let file = temp_file("restore_missing");
+        let mut diagram = save_entry_points(&file, &["A", "B"]);
+
+        let err = diagram.restore_backup(&file, 2).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::NotFound);
+        assert_eq!(diagram.get_entry_point(), "B");
+        assert_eq!(read_entry_point(&file), "B");
+        assert_eq!(list_backups(&file), vec![backup_file_name(&file, 1)]);
+    }
+
+    #[test]
+    fn read_str_rejects_mistakes_and_keeps_the_diagram() {
+        let mut diagram: Diagram<()> = Diagram::new();
+        diagram
+            .read_str(r#"{ "entry_point": "HEAD", "nodes": [ { "label": "HEAD", "token": "go" } ] }"#)
+            .unwrap();
+
+        for data in &[
+            "{ not json",
+            r#"{ "nodes": [] }"#,
+            r#"{ "entry_point": "X", "nodes": [ { "token": "go" } ] }"#,
+            r#"{ "entry_point": "X", "nodes": [ { "label": "X", "regex": "(" } ] }"#,
+            r#"{ "entry_point": "X", "nodes": [ { "label": "X", "type": "u8" } ] }"#,
+            r#"{ "entry_point": "X", "nodes": [ { "label": "X", "one_of": [] } ] }"#,
+            r#"{ "entry_point": "X", "nodes": [ { "label": "X", "separator": "tab" } ] }"#,
+            r##"{ "entry_point": "X", "nodes": [ { "label": "X", "exit": { "#next": "Y" } } ] }"##,
+        ] {
+            let err = diagram.read_str(data).unwrap_err();
+            assert_eq!(err.kind(), io::ErrorKind::InvalidData, "{}", data);
+            assert_eq!(diagram.get_entry_point(), "HEAD");
+            assert_eq!(diagram.get_node("HEAD").get_token(), "go");
+        }
+    }
+
+    #[test]
+    fn read_str_checks_the_priority() {
+        let mut diagram: Diagram<()> = Diagram::new();
+        diagram
+            .read_str(r#"{ "entry_point": "X", "nodes": [ { "label": "X", "token": "x", "priority": -3 } ] }"#)
+            .unwrap();
+        assert_eq!(diagram.get_node("X").get_priority(), -3);
+
+        for priority in &["\"9\"", "1.5", "true", "2147483648", "-2147483649"] {
+            let data = format!(
+                r#"{{ "entry_point": "X", "nodes": [ {{ "label": "X", "token": "x", "priority": {} }} ] }}"#,
+                priority
+            );
+            let err = diagram.read_str(&data).unwrap_err();
+            assert_eq!(err.kind(), io::ErrorKind::InvalidData, "{}", priority);
+            assert!(err.to_string().contains("priority"), "{}", err);
+        }
+        assert_eq!(diagram.get_node("X").get_priority(), -3);
+    }
+
+    #[test]
+    fn read_str_checks_the_repeat_range() {
+        let mut diagram: Diagram<()> = Diagram::new();
+        diagram
+            .read_str(r#"{ "entry_point": "X", "nodes": [ { "label": "X", "token": "x", "repeat": { "max": 3 } } ] }"#)
+            .unwrap();
+        assert_eq!(diagram.get_node("X").get_repeat(), Some(Repeat { min: 1, max: Some(3) }));
+
+        for repeat in &[
+            r#"{ "min": 3, "max": 2 }"#,
+            r#"{ "max": 0 }"#,
+            r#"{ "min": 0 }"#,
+            r#"{ "min": -1 }"#,
+            r#"{ "min": 1.5 }"#,
+            r#"{ "max": "9" }"#,
+            r#"true"#,
+        ] {
+            let data = format!(
+                r#"{{ "entry_point": "X", "nodes": [ {{ "label": "X", "token": "x", "repeat": {} }} ] }}"#,
+                repeat
+            );
+            let err = diagram.read_str(&data).unwrap_err();
+            assert_eq!(err.kind(), io::ErrorKind::InvalidData, "{}", repeat);
+        }
+        assert_eq!(diagram.get_node("X").get_repeat(), Some(Repeat { min: 1, max: Some(3) }));
+    }
+
+    #[test]
+    fn try_read_file_reports_a_missing_file() {
+        let file = temp_file("read_missing");
+        let mut diagram = save_entry_points(&file, &["A"]);
+
+        let missing = format!("{}.missing", file);
+        let err = diagram.try_read_file(&missing).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::NotFound);
+        assert!(err.to_string().contains(&missing));
+        assert_eq!(diagram.get_entry_point(), "A");
+    }
+
+    #[test]
+    fn restore_backup_leaves_everything_when_the_backup_is_broken() {
+        let file = temp_file("restore_broken");
+        let mut diagram = save_entry_points(&file, &["A", "B"]);
+        fs::write(backup_file_name(&file, 1), "{ broken").unwrap();
+
+        let err = diagram.restore_backup(&file, 1).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+        assert_eq!(diagram.get_entry_point(), "B");
+        assert_eq!(read_entry_point(&file), "B");
+        assert_eq!(fs::read_to_string(backup_file_name(&file, 1)).unwrap(), "{ broken");
+    }
+
+    #[test]
+    fn try_write_file_reports_errors_and_removes_the_temp_file() {
+        let file = temp_file("write_error");
+        // 書込み先が ディレクトリなので、差し替えられない。
+        fs::create_dir_all(&file).unwrap();
+        fs::write(Path::new(&file).join("keep"), "").unwrap();
+        let diagram: Diagram<()> = Diagram::new();
+
+        assert!(diagram.try_write_file(&file).is_err());
+        assert!(!Path::new(&format!("{}.tmp", file)).exists());
+        assert!(Path::new(&file).join("keep").exists());
     }
 }
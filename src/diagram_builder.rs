@@ -0,0 +1,423 @@
+/// ダイアグラムを、JSONファイルを使わずに Rust のコードで組み立てる。
+///
+/// ```text
+/// diagram.node("TK.go").token("go").fn_("do_go").next(["TK.btime", "TK.infinite"]).newline("TAIL.go");
+/// ```
+use diagram::*;
+use regex::Regex;
+use separator::*;
+use shell::*;
+use std::collections::HashMap;
+use token_value::*;
+
+/// ノード１つ分のビルダー。
+/// ノードは `Diagram::node` を呼んだ時点で登録済みで、メソッドを呼ぶたびに書き換わる。
+///
+/// # Members
+///
+/// * `diagram` - 書き込み先のダイアグラム。
+/// * `label` - 組み立て中のノードのラベル。
+pub struct NodeBuilder<'a, T: 'a> {
+    diagram: &'a mut Diagram<T>,
+    label: String,
+}
+impl<'a, T> NodeBuilder<'a, T> {
+    /// 全文一致させたい文字列。
+    pub fn token(self, token: &str) -> Self {
+        self.diagram
+            .get_node_mut(&self.label)
+            .set_token(token.to_string(), NodeKind::Token);
+        self
+    }
+
+    /// 正規表現。書き間違えていたら、ここで止める。
+    pub fn regex(self, regex: &str) -> Self {
+        if let Err(err) = Regex::new(regex) {
+            panic!("Regex error. ({} node) {}", self.label, err);
+        }
+        self.diagram
+            .get_node_mut(&self.label)
+            .set_token(regex.to_string(), NodeKind::Regex);
+        self
+    }
+
+    /// 行の残り全部を取る。
+    pub fn rest(self) -> Self {
+        self.diagram
+            .get_node_mut(&self.label)
+            .set_token("".to_string(), NodeKind::Rest);
+        self
+    }
+
+    /// 引用符で囲んだ文字列を取る。
+    pub fn quoted(self) -> Self {
+        self.diagram
+            .get_node_mut(&self.label)
+            .set_token("".to_string(), NodeKind::Quoted);
+        self
+    }
+
+    /// 並べたトークンの、どれか１つ。
+    pub fn one_of<I, S>(self, choices: I) -> Self
+    where
+        I: IntoIterator<Item = S>,
+        S: Into<String>,
+    {
+        let choices: Vec<String> = choices.into_iter().map(Into::into).collect();
+        if choices.is_empty() {
+            panic!("one_of is empty. ({} node)", self.label);
+        }
+        let node = self.diagram.get_node_mut(&self.label);
+        node.set_token("".to_string(), NodeKind::OneOf);
+        node.set_choices(choices);
+        self
+    }
+
+    /// 数や真偽値に変換して取る。
+    pub fn value(self, value_type: ValueType) -> Self {
+        self.diagram
+            .get_node_mut(&self.label)
+            .set_token("".to_string(), NodeKind::Value(value_type));
+        self
+    }
+
+    /// 値のノードで、許す範囲。 None なら制限しない。
+    pub fn range<N: Into<f64>>(self, min: Option<N>, max: Option<N>) -> Self {
+        self.diagram
+            .get_node_mut(&self.label)
+            .set_range(min.map(Into::into), max.map(Into::into));
+        self
+    }
+
+    /// コールバック関数の登録名。
+    pub fn fn_(self, fn_label: &str) -> Self {
+        self.diagram
+            .get_node_mut(&self.label)
+            .set_fn_label(fn_label.to_string());
+        self
+    }
+
+    /// トークンの後ろの区切り。指定しなければダイアグラムの区切りに従う。
+    pub fn separator(self, separator: Separator) -> Self {
+        self.diagram
+            .get_node_mut(&self.label)
+            .set_separator(Some(separator));
+        self
+    }
+
+    /// 前方一致でよいことにする。
+    /// 指定しなければ、トークンの後ろが区切りか行末のときだけ一致する。
+    pub fn prefix(self) -> Self {
+        self.diagram.get_node_mut(&self.label).set_prefix(true);
+        self
+    }
+
+    /// トークンを繰り返し読む。 `min` は 1 以上。 `max` が None なら行末まで。
+    /// 読んだトークンはまとめて groups に入り、コールバック関数は 1回 だけ呼ばれる。
+    pub fn repeat(self, min: usize, max: Option<usize>) -> Self {
+        if min == 0 {
+            panic!("Repeat min is 0. ({} node)", self.label);
+        }
+        if let Some(max) = max {
+            if max < min {
+                panic!("Repeat range is empty. ({} node)", self.label);
+            }
+        }
+        self.diagram
+            .get_node_mut(&self.label)
+            .set_repeat(Some(Repeat { min, max }));
+        self
+    }
+
+    /// 次の行き先の候補が複数一致したとき、大きい方を優先する。
+    pub fn priority(self, priority: i32) -> Self {
+        self.diagram.get_node_mut(&self.label).set_priority(priority);
+        self
+    }
+
+    /// 一致した後、 `callee` のノードの `#next` に進む。
+    /// 行き先に `#return` と書いたところで、このノードの出口の行き先に戻ってくる。
+    pub fn call(self, callee: &str) -> Self {
+        if callee.trim().is_empty() {
+            panic!("Callee label is empty. ({} node)", self.label);
+        }
+        self.diagram
+            .get_node_mut(&self.label)
+            .set_call(Some(callee.to_string()));
+        self
+    }
+
+    /// このノードに入ったときに呼ぶ、コールバック関数の登録名。
+    pub fn on_enter(self, fn_label: &str) -> Self {
+        self.diagram
+            .get_node_mut(&self.label)
+            .set_on_enter(Some(fn_label.to_string()));
+        self
+    }
+
+    /// このノードから出たときに呼ぶ、コールバック関数の登録名。
+    pub fn on_leave(self, fn_label: &str) -> Self {
+        self.diagram
+            .get_node_mut(&self.label)
+            .set_on_leave(Some(fn_label.to_string()));
+        self
+    }
+
+    /// 行がこのノードで終わったら、 `terminator` の行が来るまでの行を つないで 1行として読む。
+    pub fn terminator(self, terminator: &str) -> Self {
+        self.diagram
+            .get_node_mut(&self.label)
+            .set_terminator(Some(terminator.to_string()));
+        self
+    }
+
+    /// 英字の大文字と小文字を区別しない。
+    pub fn ignore_case(self) -> Self {
+        self.diagram
+            .get_node_mut(&self.label)
+            .set_ignore_case(Some(true));
+        self
+    }
+
+    /// NFKC 正規化してから比べる。全角英数字も半角と同じに扱う。
+    pub fn nfkc(self) -> Self {
+        self.diagram.get_node_mut(&self.label).set_nfkc(Some(true));
+        self
+    }
+
+    /// 任意の出口に、つながるノードを書き足す。
+    pub fn exit<I, S>(self, door_label: &str, node_labels: I) -> Self
+    where
+        I: IntoIterator<Item = S>,
+        S: Into<String>,
+    {
+        if door_label.trim() == "" {
+            panic!("Exit label is empty. ({} node)", self.label);
+        }
+        for node_label in node_labels {
+            let node_label = node_label.into();
+            if node_label.trim() == "" {
+                panic!(
+                    "Next node label is empty. ({} node, {} exit)",
+                    self.label, door_label
+                );
+            }
+            self.diagram
+                .get_node_mut(&self.label)
+                .push_exit(door_label, node_label);
+        }
+        self
+    }
+
+    /// 任意の出口に、述語の付いた行き先を書き足す。
+    /// 述語が真のときだけ、この行き先を候補にする。述語は `Diagram::insert_guard_fn` で登録する。
+    pub fn guarded_exit(self, door_label: &str, node_label: &str, guard: &str) -> Self {
+        let builder = self.exit(door_label, vec![node_label]);
+        builder
+            .diagram
+            .get_node_mut(&builder.label)
+            .set_guard(door_label, node_label, guard.to_string());
+        builder
+    }
+
+    /// `#next` 出口に、つながるノードを書き足す。
+    pub fn next<I, S>(self, node_labels: I) -> Self
+    where
+        I: IntoIterator<Item = S>,
+        S: Into<String>,
+    {
+        self.exit(NEXT_EXIT_LABEL, node_labels)
+    }
+
+    /// `#newline` 出口の行き先。行末ノードは１つだけ。
+    pub fn newline(self, node_label: &str) -> Self {
+        if self
+            .diagram
+            .get_node(&self.label)
+            .contains_exit(NEWLINE_EXIT_LABEL)
+        {
+            panic!("\"#newline\" door is already set. ({} node)", self.label);
+        }
+        self.exit(NEWLINE_EXIT_LABEL, vec![node_label])
+    }
+}
+
+impl<T> Diagram<T> {
+    /// ノードを登録して、そのノードのビルダーを返す。
+    /// ラベルが空、または登録済みなら止める。
+    ///
+    /// # Arguments
+    ///
+    /// * `label` - 登録用のノード名です。
+    pub fn node(&mut self, label: &str) -> NodeBuilder<'_, T> {
+        if label.trim() == "" {
+            panic!("Node label is empty.");
+        }
+        if self.contains_node(label) {
+            panic!("\"{}\" node already exists.", label);
+        }
+        self.insert_node_single(label, "".to_string(), HashMap::new());
+        NodeBuilder {
+            diagram: self,
+            label: label.to_string(),
+        }
+    }
+
+    /// どの出口からも参照されているのに、登録されていないノードのラベルを返す。
+    /// 前方参照があるので、ノードを全部登録し終えてから確認する。
+    pub fn find_missing_nodes(&self) -> Vec<String> {
+        let mut vec = Vec::new();
+        if self.get_entry_point() != "" && !self.contains_node(&self.get_entry_point()) {
+            vec.push(self.get_entry_point());
+        }
+        for node in self.get_node_map().values() {
+            if let Some(ref callee) = *node.get_call() {
+                if !self.contains_node(callee) && !vec.contains(callee) {
+                    vec.push(callee.to_string());
+                }
+            }
+            for node_vec in node.get_exit_map().values() {
+                for node_label in node_vec {
+                    if node_label != RETURN_LABEL
+                        && !self.contains_node(node_label)
+                        && !vec.contains(node_label)
+                    {
+                        vec.push(node_label.to_string());
+                    }
+                }
+            }
+        }
+        vec.sort();
+        vec
+    }
+
+    /// 出口の行き先に付いているのに、登録されていない述語の名前を返す。
+    /// 登録されていない述語は偽になるので、書き間違えると その行き先には進めない。
+    /// diagram.json を読んで、述語を全部登録し終えてから確認する。
+    pub fn find_missing_guards(&self) -> Vec<String> {
+        let mut vec = Vec::new();
+        for node in self.get_node_map().values() {
+            for guard_map in node.get_guard_map().values() {
+                for guard in guard_map.values() {
+                    if !self.contains_guard_fn(guard) && !vec.contains(guard) {
+                        vec.push(guard.to_string());
+                    }
+                }
+            }
+        }
+        vec.sort();
+        vec
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn node_builder_sets_token_fn_and_exits() {
+        let mut diagram: Diagram<()> = Diagram::new();
+        diagram
+            .node("TK.go")
+            .token("go")
+            .fn_("do_go")
+            .next(["TK.btime", "TK.infinite"])
+            .newline("TAIL.go");
+
+        let node = diagram.get_node("TK.go");
+        assert_eq!(node.get_token(), "go");
+        assert_eq!(node.get_kind(), NodeKind::Token);
+        assert_eq!(node.get_fn_label(), "do_go");
+        assert_eq!(
+            node.get_exit_vec(NEXT_EXIT_LABEL),
+            &vec!["TK.btime".to_string(), "TK.infinite".to_string()]
+        );
+        assert_eq!(
+            node.get_exit_vec(NEWLINE_EXIT_LABEL),
+            &vec!["TAIL.go".to_string()]
+        );
+    }
+
+    #[test]
+    fn node_builder_sets_node_options() {
+        let mut diagram: Diagram<()> = Diagram::new();
+        diagram
+            .node("TK.a")
+            .token("a")
+            .priority(5)
+            .call("SUB")
+            .on_enter("enter")
+            .on_leave("leave")
+            .terminator("end")
+            .prefix()
+            .guarded_exit(NEXT_EXIT_LABEL, "TK.b", "is_ready");
+
+        let node = diagram.get_node("TK.a");
+        assert_eq!(node.get_priority(), 5);
+        assert_eq!(node.get_call(), &Some("SUB".to_string()));
+        assert_eq!(node.get_on_enter(), &Some("enter".to_string()));
+        assert_eq!(node.get_on_leave(), &Some("leave".to_string()));
+        assert_eq!(node.get_terminator(), &Some("end".to_string()));
+        assert!(node.is_prefix());
+        assert_eq!(node.get_guard(NEXT_EXIT_LABEL, "TK.b"), Some("is_ready"));
+    }
+
+    #[test]
+    fn find_missing_nodes_lists_unregistered_labels() {
+        let mut diagram: Diagram<()> = Diagram::new();
+        diagram.set_entry_point("HEAD".to_string());
+        diagram.node("HEAD").next(["TK.a", "TK.b"]);
+        diagram.node("TK.a").token("a").call("SUB").next([RETURN_LABEL]);
+
+        assert_eq!(
+            diagram.find_missing_nodes(),
+            vec!["SUB".to_string(), "TK.b".to_string()]
+        );
+    }
+
+    fn always(_t: &(), _req: &dyn Request) -> bool {
+        true
+    }
+
+    #[test]
+    fn find_missing_guards_lists_unregistered_guards() {
+        let mut diagram: Diagram<()> = Diagram::new();
+        diagram.insert_guard_fn("is_ready", always);
+        diagram
+            .node("HEAD")
+            .guarded_exit(NEXT_EXIT_LABEL, "TK.a", "is_ready")
+            .guarded_exit(NEXT_EXIT_LABEL, "TK.b", "is_redy")
+            .guarded_exit(NEWLINE_EXIT_LABEL, "TK.a", "is_redy");
+
+        assert_eq!(diagram.find_missing_guards(), vec!["is_redy".to_string()]);
+    }
+
+    #[test]
+    #[should_panic(expected = "already exists")]
+    fn node_rejects_a_duplicate_label() {
+        let mut diagram: Diagram<()> = Diagram::new();
+        diagram.node("TK.a");
+        diagram.node("TK.a");
+    }
+
+    #[test]
+    #[should_panic(expected = "Regex error")]
+    fn regex_rejects_a_bad_pattern() {
+        let mut diagram: Diagram<()> = Diagram::new();
+        diagram.node("RE.a").regex("(");
+    }
+
+    #[test]
+    #[should_panic(expected = "Repeat min is 0")]
+    fn repeat_rejects_a_zero_min() {
+        let mut diagram: Diagram<()> = Diagram::new();
+        diagram.node("TK.a").token("a").repeat(0, None);
+    }
+
+    #[test]
+    #[should_panic(expected = "already set")]
+    fn newline_rejects_a_second_tail() {
+        let mut diagram: Diagram<()> = Diagram::new();
+        diagram.node("TK.a").newline("TAIL.a").newline("TAIL.b");
+    }
+}
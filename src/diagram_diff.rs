@@ -0,0 +1,489 @@
+/// ２つのダイアグラムの、意味のある違いを調べる。
+/// JSONの差分だと、ノードの並び順や空白まで違いとして出てしまうので、ノード単位で比べる。
+use diagram::*;
+use std::fmt;
+
+/// ノード１つ分の、値の変更。
+///
+/// # Members
+///
+/// * `label` - ノードのラベル。
+/// * `before` - 変更前の値。
+/// * `after` - 変更後の値。
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct NodeChange {
+    pub label: String,
+    pub before: String,
+    pub after: String,
+}
+
+/// ノード１つ分の、設定の変更。
+///
+/// # Members
+///
+/// * `label` - ノードのラベル。
+/// * `name` - 設定の名前。 `priority` など。
+/// * `before` - 変更前の値。設定していなければ空文字列。
+/// * `after` - 変更後の値。設定していなければ空文字列。
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct OptionChange {
+    pub label: String,
+    pub name: String,
+    pub before: String,
+    pub after: String,
+}
+
+/// ダイアグラム全体の、設定の変更。
+///
+/// # Members
+///
+/// * `name` - 設定の名前。 `separator` など。
+/// * `before` - 変更前の値。既定値なら空文字列。
+/// * `after` - 変更後の値。既定値なら空文字列。
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct SettingChange {
+    pub name: String,
+    pub before: String,
+    pub after: String,
+}
+
+/// 出口１つ分の、行き先の並び順の変更。並び順は、候補を試す順番になる。
+///
+/// # Members
+///
+/// * `label` - 出口のあるノードのラベル。
+/// * `door` - 出口の名前。 `#next` など。
+/// * `before` - 変更前の行き先。並び順通り。
+/// * `after` - 変更後の行き先。並び順通り。
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ExitOrderChange {
+    pub label: String,
+    pub door: String,
+    pub before: Vec<String>,
+    pub after: Vec<String>,
+}
+
+/// 出口の矢印１本。
+///
+/// # Members
+///
+/// * `label` - 矢印の根元のノードのラベル。
+/// * `door` - 出口の名前。 `#next` など。
+/// * `next` - 行き先のノードのラベル。
+/// * `guard` - 行き先に付いた述語の登録名。述語が変わったら、矢印を引き直したものとして扱う。
+#[derive(Clone, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub struct ExitEdge {
+    pub label: String,
+    pub door: String,
+    pub next: String,
+    pub guard: Option<String>,
+}
+impl fmt::Display for ExitEdge {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{} {} -> {}", self.label, self.door, self.next)?;
+        if let Some(ref guard) = self.guard {
+            write!(f, " [{}]", guard)?;
+        }
+        Ok(())
+    }
+}
+
+/// ダイアグラムの差分。
+///
+/// # Members
+///
+/// * `entry_point` - 入り口が変わったなら (変更前, 変更後)。
+/// * `changed_settings` - 区切りや `backtrack` など、ダイアグラム全体の設定の変更。
+/// * `added_nodes` - 増えたノードのラベル。
+/// * `removed_nodes` - 消えたノードのラベル。
+/// * `changed_tokens` - トークン、正規表現が変わったノード。
+/// * `changed_fns` - コールバック関数の登録名が変わったノード。
+/// * `changed_options` - `priority` や `call` など、その他の設定が変わったノード。
+/// * `added_exits` - 増えた矢印。
+/// * `removed_exits` - 消えた矢印。
+/// * `reordered_exits` - 両方に残った行き先の、並び順が変わった出口。
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct DiagramDiff {
+    pub entry_point: Option<(String, String)>,
+    pub changed_settings: Vec<SettingChange>,
+    pub added_nodes: Vec<String>,
+    pub removed_nodes: Vec<String>,
+    pub changed_tokens: Vec<NodeChange>,
+    pub changed_fns: Vec<NodeChange>,
+    pub changed_options: Vec<OptionChange>,
+    pub added_exits: Vec<ExitEdge>,
+    pub removed_exits: Vec<ExitEdge>,
+    pub reordered_exits: Vec<ExitOrderChange>,
+}
+impl DiagramDiff {
+    /// 違いが無ければ真。
+    pub fn is_empty(&self) -> bool {
+        self.entry_point.is_none()
+            && self.changed_settings.is_empty()
+            && self.added_nodes.is_empty()
+            && self.removed_nodes.is_empty()
+            && self.changed_tokens.is_empty()
+            && self.changed_fns.is_empty()
+            && self.changed_options.is_empty()
+            && self.added_exits.is_empty()
+            && self.removed_exits.is_empty()
+            && self.reordered_exits.is_empty()
+    }
+}
+impl fmt::Display for DiagramDiff {
+    /// 1行に1件。先頭の記号は、 `+` 追加、 `-` 削除、 `~` 変更。
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        if let Some((ref before, ref after)) = self.entry_point {
+            writeln!(f, "~ entry_point: {} -> {}", before, after)?;
+        }
+        for change in &self.changed_settings {
+            writeln!(f, "~ {}: [{}] -> [{}]", change.name, change.before, change.after)?;
+        }
+        for label in &self.added_nodes {
+            writeln!(f, "+ node: {}", label)?;
+        }
+        for label in &self.removed_nodes {
+            writeln!(f, "- node: {}", label)?;
+        }
+        for change in &self.changed_tokens {
+            writeln!(
+                f,
+                "~ token: {} [{}] -> [{}]",
+                change.label, change.before, change.after
+            )?;
+        }
+        for change in &self.changed_fns {
+            writeln!(
+                f,
+                "~ fn: {} [{}] -> [{}]",
+                change.label, change.before, change.after
+            )?;
+        }
+        for change in &self.changed_options {
+            writeln!(
+                f,
+                "~ {}: {} [{}] -> [{}]",
+                change.name, change.label, change.before, change.after
+            )?;
+        }
+        for edge in &self.added_exits {
+            writeln!(f, "+ exit: {}", edge)?;
+        }
+        for edge in &self.removed_exits {
+            writeln!(f, "- exit: {}", edge)?;
+        }
+        for change in &self.reordered_exits {
+            writeln!(
+                f,
+                "~ exit order: {} {} [{}] -> [{}]",
+                change.label,
+                change.door,
+                change.before.join(", "),
+                change.after.join(", ")
+            )?;
+        }
+        Ok(())
+    }
+}
+
+impl<T> Diagram<T> {
+    /// 自分を変更前、 `other` を変更後として、違いを調べる。
+    /// 登録したコントローラーの関数は比べない。
+    pub fn diff(&self, other: &Diagram<T>) -> DiagramDiff {
+        let mut diff = DiagramDiff::default();
+
+        if self.get_entry_point() != other.get_entry_point() {
+            diff.entry_point = Some((self.get_entry_point(), other.get_entry_point()));
+        }
+        for ((name, before_value), (_, after_value)) in self
+            .get_setting_captions()
+            .into_iter()
+            .zip(other.get_setting_captions())
+        {
+            if before_value != after_value {
+                diff.changed_settings.push(SettingChange {
+                    name: name.to_string(),
+                    before: before_value,
+                    after: after_value,
+                });
+            }
+        }
+
+        for (label, after) in other.get_node_map().iter() {
+            if !self.contains_node(label) {
+                diff.added_nodes.push(label.to_string());
+                continue;
+            }
+
+            let before = self.get_node(label);
+            // トークンから正規表現に変わった場合も違いとして出したいので、種類込みで比べる。
+            if before.get_token_caption() != after.get_token_caption() {
+                diff.changed_tokens.push(NodeChange {
+                    label: label.to_string(),
+                    before: before.get_token_caption(),
+                    after: after.get_token_caption(),
+                });
+            }
+            if before.get_fn_label() != after.get_fn_label() {
+                diff.changed_fns.push(NodeChange {
+                    label: label.to_string(),
+                    before: before.get_fn_label().to_string(),
+                    after: after.get_fn_label().to_string(),
+                });
+            }
+            diff_exit_orders(label, before, after, &mut diff.reordered_exits);
+            for ((name, before_value), (_, after_value)) in before
+                .get_option_captions()
+                .into_iter()
+                .zip(after.get_option_captions())
+            {
+                if before_value != after_value {
+                    diff.changed_options.push(OptionChange {
+                        label: label.to_string(),
+                        name: name.to_string(),
+                        before: before_value,
+                        after: after_value,
+                    });
+                }
+            }
+        }
+        for label in self.get_node_map().keys() {
+            if !other.contains_node(label) {
+                diff.removed_nodes.push(label.to_string());
+            }
+        }
+
+        let before_edges = collect_exit_edges(self);
+        let after_edges = collect_exit_edges(other);
+        for edge in &after_edges {
+            if !before_edges.contains(edge) {
+                diff.added_exits.push(edge.clone());
+            }
+        }
+        for edge in &before_edges {
+            if !after_edges.contains(edge) {
+                diff.removed_exits.push(edge.clone());
+            }
+        }
+
+        // 出力を毎回同じにしたいので整列する。
+        diff.added_nodes.sort();
+        diff.removed_nodes.sort();
+        diff.changed_tokens.sort_by(|a, b| a.label.cmp(&b.label));
+        diff.changed_fns.sort_by(|a, b| a.label.cmp(&b.label));
+        diff.changed_options
+            .sort_by(|a, b| a.label.cmp(&b.label).then(a.name.cmp(&b.name)));
+        diff.reordered_exits
+            .sort_by(|a, b| a.label.cmp(&b.label).then(a.door.cmp(&b.door)));
+        diff
+    }
+}
+
+/// 出口ごとに、両方に残った行き先の並び順を比べる。
+/// 増えた行き先と消えた行き先は 矢印の増減で出すので、除いてから比べる。同じ行き先が重なった数の違いも出る。
+fn diff_exit_orders(label: &str, before: &Node, after: &Node, vec: &mut Vec<ExitOrderChange>) {
+    for (door, before_vec) in before.get_exit_map().iter() {
+        let after_vec = match after.get_exit_map().get(door) {
+            Some(after_vec) => after_vec,
+            None => continue,
+        };
+        let before_kept: Vec<&String> = before_vec.iter().filter(|next| after_vec.contains(next)).collect();
+        let after_kept: Vec<&String> = after_vec.iter().filter(|next| before_vec.contains(next)).collect();
+        if before_kept != after_kept {
+            vec.push(ExitOrderChange {
+                label: label.to_string(),
+                door: door.to_string(),
+                before: before_vec.clone(),
+                after: after_vec.clone(),
+            });
+        }
+    }
+}
+
+/// 矢印を全部、整列して並べる。出力を毎回同じにするためで、並び順の違いは `diff_exit_orders` で調べる。
+fn collect_exit_edges<T>(diagram: &Diagram<T>) -> Vec<ExitEdge> {
+    let mut vec = Vec::new();
+    for (label, node) in diagram.get_node_map().iter() {
+        for (door, node_vec) in node.get_exit_map().iter() {
+            for next in node_vec {
+                vec.push(ExitEdge {
+                    label: label.to_string(),
+                    door: door.to_string(),
+                    next: next.to_string(),
+                    guard: node.get_guard(door, next).map(|guard| guard.to_string()),
+                });
+            }
+        }
+    }
+    vec.sort();
+    vec.dedup();
+    vec
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use separator::*;
+    use shell::*;
+
+    fn base_diagram() -> Diagram<()> {
+        let mut diagram = Diagram::new();
+        diagram.set_entry_point("TK.a".to_string());
+        diagram.node("TK.a").token("a").fn_("do_a").next(["TK.b"]);
+        diagram.node("TK.b").token("b").next(["TK.a"]);
+        diagram
+    }
+
+    #[test]
+    fn diff_of_the_same_diagram_is_empty() {
+        assert!(base_diagram().diff(&base_diagram()).is_empty());
+    }
+
+    #[test]
+    fn diff_reports_nodes_tokens_fns_and_exits() {
+        let before = base_diagram();
+        let mut after = Diagram::new();
+        after.set_entry_point("TK.a".to_string());
+        after.node("TK.a").regex("a+").fn_("do_aa").next(["TK.c"]);
+        after.node("TK.c").token("c");
+
+        let diff = before.diff(&after);
+        assert_eq!(diff.added_nodes, vec!["TK.c".to_string()]);
+        assert_eq!(diff.removed_nodes, vec!["TK.b".to_string()]);
+        assert_eq!(diff.changed_tokens[0].after, "regex: a+");
+        assert_eq!(diff.changed_fns[0].after, "do_aa");
+        assert_eq!(diff.added_exits.len(), 1);
+        assert_eq!(diff.removed_exits.len(), 2);
+    }
+
+    #[test]
+    fn diff_reports_node_options() {
+        let before = base_diagram();
+        let mut after = Diagram::new();
+        after.set_entry_point("TK.a".to_string());
+        after
+            .node("TK.a")
+            .token("a")
+            .fn_("do_a")
+            .priority(9)
+            .call("SUB")
+            .on_enter("enter")
+            .on_leave("leave")
+            .terminator("end")
+            .separator(Separator::Whitespace)
+            .prefix()
+            .ignore_case()
+            .nfkc()
+            .next(["TK.b"]);
+        after.node("TK.b").token("b").next(["TK.a"]);
+
+        let diff = before.diff(&after);
+        assert!(!diff.is_empty());
+        let names: Vec<&str> = diff.changed_options.iter().map(|c| c.name.as_str()).collect();
+        assert_eq!(
+            names,
+            vec![
+                "call",
+                "ignore_case",
+                "nfkc",
+                "on_enter",
+                "on_leave",
+                "prefix",
+                "priority",
+                "separator",
+                "terminator"
+            ]
+        );
+        assert!(diff.to_string().contains("~ priority: TK.a [] -> [9]"));
+    }
+
+    #[test]
+    fn diff_reports_diagram_settings() {
+        let before = base_diagram();
+        let mut after = base_diagram();
+        after.set_separator(Separator::Whitespace);
+        after.set_ignore_case(true);
+        after.set_nfkc(true);
+        after.set_backtrack(true);
+        after.set_line_continuation(true);
+
+        let diff = before.diff(&after);
+        let names: Vec<&str> = diff.changed_settings.iter().map(|c| c.name.as_str()).collect();
+        assert_eq!(
+            names,
+            vec!["separator", "ignore_case", "nfkc", "backtrack", "line_continuation"]
+        );
+        assert!(diff.to_string().contains("~ backtrack: [] -> [true]"));
+        assert!(after.diff(&after.clone()).is_empty());
+    }
+
+    #[test]
+    fn diff_reports_exit_order_changes() {
+        let mut before = base_diagram();
+        before.node("TK.c").token("c");
+        before.node("TK.d").token("d");
+        before.get_node_mut("TK.a").push_exit(NEXT_EXIT_LABEL, "TK.c".to_string());
+        let mut after = base_diagram();
+        after.node("TK.c").token("c");
+        after.node("TK.d").token("d");
+        after.get_node_mut("TK.a").push_exit(NEXT_EXIT_LABEL, "TK.d".to_string());
+        after.get_node_mut("TK.a").push_exit(NEXT_EXIT_LABEL, "TK.c".to_string());
+        // 並び替えただけ。
+        let mut swapped = Diagram::new();
+        swapped.set_entry_point("TK.a".to_string());
+        swapped.node("TK.a").token("a").fn_("do_a").next(["TK.c", "TK.b"]);
+        swapped.node("TK.b").token("b").next(["TK.a"]);
+        swapped.node("TK.c").token("c");
+        swapped.node("TK.d").token("d");
+
+        // 行き先が増えただけなら、並び順の変更ではない。
+        let diff = before.diff(&after);
+        assert_eq!(diff.added_exits.len(), 1);
+        assert!(diff.reordered_exits.is_empty());
+
+        let diff = before.diff(&swapped);
+        assert!(diff.added_exits.is_empty());
+        assert!(diff.removed_exits.is_empty());
+        assert_eq!(
+            diff.reordered_exits,
+            vec![ExitOrderChange {
+                label: "TK.a".to_string(),
+                door: NEXT_EXIT_LABEL.to_string(),
+                before: vec!["TK.b".to_string(), "TK.c".to_string()],
+                after: vec!["TK.c".to_string(), "TK.b".to_string()],
+            }]
+        );
+        assert!(diff
+            .to_string()
+            .contains("~ exit order: TK.a #next [TK.b, TK.c] -> [TK.c, TK.b]"));
+    }
+
+    #[test]
+    fn diff_reports_a_repeat_change_as_a_token_change() {
+        let before = base_diagram();
+        let mut after = Diagram::new();
+        after.set_entry_point("TK.a".to_string());
+        after.node("TK.a").token("a").fn_("do_a").repeat(1, None).next(["TK.b"]);
+        after.node("TK.b").token("b").next(["TK.a"]);
+
+        let diff = before.diff(&after);
+        assert_eq!(diff.changed_tokens[0].after, "token: a repeat: 1..");
+    }
+
+    #[test]
+    fn diff_reports_a_guard_change_as_a_new_exit() {
+        let before = base_diagram();
+        let mut after = Diagram::new();
+        after.set_entry_point("TK.a".to_string());
+        after
+            .node("TK.a")
+            .token("a")
+            .fn_("do_a")
+            .guarded_exit(NEXT_EXIT_LABEL, "TK.b", "is_ready");
+        after.node("TK.b").token("b").next(["TK.a"]);
+
+        let diff = before.diff(&after);
+        assert_eq!(diff.added_exits[0].guard, Some("is_ready".to_string()));
+        assert_eq!(diff.removed_exits[0].guard, None);
+    }
+}
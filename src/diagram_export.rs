@@ -0,0 +1,268 @@
+/// ダイアグラムを、図を描くツールの書式で書き出す。
+///
+/// - Graphviz の DOT 言語。 `dot -Tsvg diagram.dot -o diagram.svg` などで画像にする。
+/// - Mermaid の flowchart 。 Markdown のドキュメントにそのまま貼れる。
+use diagram::*;
+use shell::*;
+
+impl<T> Diagram<T> {
+    /// Graphviz の DOT 言語で書き出す。
+    /// ノードの形は種類で分ける。トークンは箱、正規表現は六角形、行の残りは平行四辺形、引用符の文字列は cds 、
+    /// 型付きの値は家形、 one_of は立体の箱、トークンなしは楕円、 `#else` は八角形。
+    /// 登録されていない行き先は点線で描く。 `#return` は二重丸、呼び出しは `#call` の矢印で描く。
+    /// 述語の付いた矢印は、出口の名前の後ろに `[述語名]` を書く。
+    pub fn to_dot(&self) -> String {
+        let mut text = String::new();
+        text.push_str("digraph diagram {\n");
+        text.push_str("    rankdir=LR;\n");
+
+        if self.get_entry_point() != "" {
+            text.push_str("    \"#entry_point\" [shape=point];\n");
+            text.push_str(&format!(
+                "    \"#entry_point\" -> \"{}\";\n",
+                escape_dot(&self.get_entry_point())
+            ));
+        }
+
+        for node_label in self.collect_labels() {
+            if self.contains_node(&node_label) {
+                let node = self.get_node(&node_label);
+                let shape = match node.get_kind() {
+                    NodeKind::Token => "box",
+                    NodeKind::Regex => "hexagon",
+                    NodeKind::Rest => "parallelogram",
+                    NodeKind::Quoted => "cds",
+                    NodeKind::Value(_) => "house",
+                    NodeKind::OneOf => "box3d",
+                    NodeKind::Single => "ellipse",
+                    NodeKind::Else => "octagon",
+                };
+                text.push_str(&format!(
+                    "    \"{}\" [shape={}, label=\"{}\"];\n",
+                    escape_dot(&node_label),
+                    shape,
+                    node_caption(node)
+                        .iter()
+                        .map(|line| escape_dot(line))
+                        .collect::<Vec<String>>()
+                        .join("\\n")
+                ));
+            } else if node_label == RETURN_LABEL {
+                text.push_str(&format!(
+                    "    \"{}\" [shape=doublecircle];\n",
+                    escape_dot(&node_label)
+                ));
+            } else {
+                text.push_str(&format!(
+                    "    \"{}\" [shape=ellipse, style=dashed];\n",
+                    escape_dot(&node_label)
+                ));
+            }
+        }
+
+        for (node_label, door_label, next_label) in self.collect_edges() {
+            text.push_str(&format!(
+                "    \"{}\" -> \"{}\" [label=\"{}\"];\n",
+                escape_dot(&node_label),
+                escape_dot(&next_label),
+                escape_dot(&door_label)
+            ));
+        }
+
+        text.push_str("}\n");
+        text
+    }
+
+    /// Mermaid の flowchart で書き出す。
+    /// ラベルに使えない文字があるので、ノードID は n0, n1, ... と振り直す。
+    /// ノードの形は種類で分ける。トークンは箱、正規表現は六角形、行の残りは平行四辺形、引用符の文字列は競技場形、
+    /// 型付きの値はサブルーチン形、 one_of は円柱、トークンなしは角丸、 `#else` は円。
+    pub fn to_mermaid(&self) -> String {
+        let labels = self.collect_labels();
+        let node_id = |label: &str| match labels.iter().position(|x| x == label) {
+            Some(n) => format!("n{}", n),
+            None => panic!("\"{}\" node is not found.", label),
+        };
+
+        let mut text = String::new();
+        text.push_str("flowchart LR\n");
+
+        if self.get_entry_point() != "" {
+            text.push_str("    entry_point((\" \"))\n");
+            text.push_str(&format!(
+                "    entry_point --> {}\n",
+                node_id(&self.get_entry_point())
+            ));
+        }
+
+        for (i, node_label) in labels.iter().enumerate() {
+            if self.contains_node(node_label) {
+                let node = self.get_node(node_label);
+                let caption = node_caption(node)
+                    .iter()
+                    .map(|line| escape_mermaid(line))
+                    .collect::<Vec<String>>()
+                    .join("<br/>");
+                let (open, close) = match node.get_kind() {
+                    NodeKind::Token => ("[", "]"),
+                    NodeKind::Regex => ("{{", "}}"),
+                    NodeKind::Rest => ("[/", "/]"),
+                    NodeKind::Quoted => ("([", "])"),
+                    NodeKind::Value(_) => ("[[", "]]"),
+                    NodeKind::OneOf => ("[(", ")]"),
+                    NodeKind::Single => ("(", ")"),
+                    NodeKind::Else => ("((", "))"),
+                };
+                text.push_str(&format!("    n{}{}\"{}\"{}\n", i, open, caption, close));
+            } else if node_label == RETURN_LABEL {
+                text.push_str(&format!(
+                    "    n{}(((\"{}\")))\n",
+                    i,
+                    escape_mermaid(node_label)
+                ));
+            } else {
+                text.push_str(&format!(
+                    "    n{}[\"{}\"]\n    style n{} stroke-dasharray: 5 5\n",
+                    i,
+                    escape_mermaid(node_label),
+                    i
+                ));
+            }
+        }
+
+        for (node_label, door_label, next_label) in self.collect_edges() {
+            text.push_str(&format!(
+                "    {} -->|\"{}\"| {}\n",
+                node_id(&node_label),
+                escape_mermaid(&door_label),
+                node_id(&next_label)
+            ));
+        }
+
+        text
+    }
+
+    /// 登録済みのノードと、出口から参照されているノードのラベルを、重複なしで並べる。
+    fn collect_labels(&self) -> Vec<String> {
+        let mut vec: Vec<String> = Vec::new();
+        for (node_label, node) in self.get_node_map().iter() {
+            vec.push(node_label.to_string());
+            for node_vec in node.get_exit_map().values() {
+                for next_label in node_vec {
+                    vec.push(next_label.to_string());
+                }
+            }
+            if let Some(ref callee) = *node.get_call() {
+                vec.push(callee.to_string());
+            }
+        }
+        if self.get_entry_point() != "" {
+            vec.push(self.get_entry_point());
+        }
+        vec.sort();
+        vec.dedup();
+        vec
+    }
+
+    /// (ノード, 出口, 行き先) の組を並べる。出力を毎回同じにしたいので整列する。
+    /// 述語が付いていれば、出口に書き添える。
+    fn collect_edges(&self) -> Vec<(String, String, String)> {
+        let mut vec = Vec::new();
+        for (node_label, node) in self.get_node_map().iter() {
+            for (door_label, node_vec) in node.get_exit_map().iter() {
+                for next_label in node_vec {
+                    let door_caption = match node.get_guard(door_label, next_label) {
+                        Some(guard) => format!("{} [{}]", door_label, guard),
+                        None => door_label.to_string(),
+                    };
+                    vec.push((
+                        node_label.to_string(),
+                        door_caption,
+                        next_label.to_string(),
+                    ));
+                }
+            }
+            if let Some(ref callee) = *node.get_call() {
+                vec.push((node_label.to_string(), "#call".to_string(), callee.to_string()));
+            }
+        }
+        vec.sort();
+        vec
+    }
+}
+
+/// 図に書く、ノードの説明の行。
+fn node_caption(node: &Node) -> Vec<String> {
+    let mut vec = vec![node.get_label().to_string()];
+    if node.get_token_caption() != "" {
+        vec.push(node.get_token_caption());
+    }
+    if node.get_fn_label() != "" {
+        vec.push(format!("fn: {}", node.get_fn_label()));
+    }
+    if let Some(ref fn_label) = *node.get_on_enter() {
+        vec.push(format!("on_enter: {}", fn_label));
+    }
+    if let Some(ref fn_label) = *node.get_on_leave() {
+        vec.push(format!("on_leave: {}", fn_label));
+    }
+    vec
+}
+
+fn escape_dot(text: &str) -> String {
+    text.replace('\\', "\\\\").replace('"', "\\\"")
+}
+
+/// Mermaid は文字参照 `#quot;` などで書く。
+fn escape_mermaid(text: &str) -> String {
+    text.replace('"', "#quot;")
+        .replace('<', "#lt;")
+        .replace('>', "#gt;")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use token_value::*;
+
+    fn diagram_of_every_kind() -> Diagram<()> {
+        let mut diagram = Diagram::new();
+        diagram.set_entry_point("TK.a".to_string());
+        diagram.node("TK.a").token("a").next(["RE.b"]);
+        diagram.node("RE.b").regex("b").next(["RS.c"]);
+        diagram.node("RS.c").rest().next(["QT.d"]);
+        diagram.node("QT.d").quoted().next(["VL.e"]);
+        diagram.node("VL.e").value(ValueType::Int).next(["OO.f"]);
+        diagram.node("OO.f").one_of(["f", "g"]).next(["SG.h"]);
+        diagram.node("SG.h").next(["TK.missing"]);
+        diagram
+    }
+
+    #[test]
+    fn to_dot_draws_each_kind_with_its_shape() {
+        let dot = diagram_of_every_kind().to_dot();
+        for (label, shape) in &[
+            ("TK.a", "box"),
+            ("RE.b", "hexagon"),
+            ("RS.c", "parallelogram"),
+            ("QT.d", "cds"),
+            ("VL.e", "house"),
+            ("OO.f", "box3d"),
+            ("SG.h", "ellipse"),
+        ] {
+            let head = format!("    \"{}\" [shape={},", label, shape);
+            assert!(dot.contains(&head), "{} in\n{}", head, dot);
+        }
+        assert!(dot.contains("\"TK.missing\" [shape=ellipse, style=dashed];"));
+        assert!(dot.contains("\"TK.a\" -> \"RE.b\" [label=\"#next\"];"));
+    }
+
+    #[test]
+    fn to_mermaid_draws_each_kind_with_its_shape() {
+        let mermaid = diagram_of_every_kind().to_mermaid();
+        for open in &["[\"", "{{\"", "[/\"", "([\"", "[[\"", "[(\"", "(\""] {
+            assert!(mermaid.contains(open), "{} in\n{}", open, mermaid);
+        }
+        assert!(mermaid.contains("stroke-dasharray"));
+    }
+}
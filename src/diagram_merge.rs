@@ -0,0 +1,317 @@
+/// ダイアグラムを重ね合わせる。
+/// 共通の文法に、エンジンごとの拡張を上乗せするときに使う。
+use diagram::*;
+use std::fmt;
+
+/// 同じラベルで中身が違うノードがあったとき、どうするか。
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum MergePolicy {
+    /// 上乗せする側で上書きする。
+    Override,
+    /// 元の側を残す。
+    Keep,
+    /// 何も変更せず、エラーにする。
+    Error,
+}
+
+/// 衝突の種類。
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ConflictKind {
+    EntryPoint,
+    Token,
+    Fn,
+    /// `priority` や `call` など、その他の設定。 `Node::get_option_captions` の名前。
+    Option(&'static str),
+}
+
+/// 衝突１件。
+///
+/// # Members
+///
+/// * `kind` - 何が衝突したか。
+/// * `label` - ノードのラベル。入り口の衝突なら空文字列。
+/// * `ours` - 元の側の値。
+/// * `theirs` - 上乗せする側の値。
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct MergeConflict {
+    pub kind: ConflictKind,
+    pub label: String,
+    pub ours: String,
+    pub theirs: String,
+}
+impl fmt::Display for MergeConflict {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self.kind {
+            ConflictKind::EntryPoint => {
+                write!(f, "entry_point: [{}] <> [{}]", self.ours, self.theirs)
+            }
+            ConflictKind::Token => write!(
+                f,
+                "token: {} [{}] <> [{}]",
+                self.label, self.ours, self.theirs
+            ),
+            ConflictKind::Fn => write!(
+                f,
+                "fn: {} [{}] <> [{}]",
+                self.label, self.ours, self.theirs
+            ),
+            ConflictKind::Option(name) => write!(
+                f,
+                "{}: {} [{}] <> [{}]",
+                name, self.label, self.ours, self.theirs
+            ),
+        }
+    }
+}
+
+impl<T> Diagram<T> {
+    /// `other` を重ね合わせる。
+    ///
+    /// - ノードは和集合を取る。同じラベルのノードは、出口ごとに行き先の和集合を取る。
+    /// - 同じラベルでトークンや `fn` 、 `priority` や `call` などの設定が違うもの、入り口が違うものは衝突として返す。
+    ///   片方が空か既定値なら衝突とせず、そうでない方を使う。
+    /// - 登録済みのコントローラーと述語も重ね合わせる。同じ名前なら `policy` に従う。
+    ///
+    /// # Returns.
+    ///
+    /// 衝突の一覧。 `MergePolicy::Error` で衝突があったときは、何も変更せずに Err で返す。
+    pub fn merge(
+        &mut self,
+        other: &Diagram<T>,
+        policy: MergePolicy,
+    ) -> Result<Vec<MergeConflict>, Vec<MergeConflict>> {
+        let conflicts = self.find_merge_conflicts(other);
+        if policy == MergePolicy::Error && !conflicts.is_empty() {
+            return Err(conflicts);
+        }
+        let overrides = policy == MergePolicy::Override;
+
+        if self.get_entry_point() == "" || (overrides && other.get_entry_point() != "") {
+            self.set_entry_point(other.get_entry_point());
+        }
+
+        for (label, theirs) in other.get_node_map().iter() {
+            if !self.contains_node(label) {
+                self.insert_node_struct(theirs.clone());
+                continue;
+            }
+
+            let ours = self.get_node_mut(label);
+            if theirs.get_token_caption() != ""
+                && (ours.get_token_caption() == "" || overrides)
+            {
+                ours.set_token_of(theirs);
+            }
+            if theirs.get_fn_label() != "" && (ours.get_fn_label() == "" || overrides) {
+                ours.set_fn_label(theirs.get_fn_label().to_string());
+            }
+            let ours_options = ours.get_option_captions();
+            for ((name, ours_value), (_, theirs_value)) in
+                ours_options.into_iter().zip(theirs.get_option_captions())
+            {
+                if !theirs_value.is_empty() && (ours_value.is_empty() || overrides) {
+                    ours.set_option_of(theirs, name);
+                }
+            }
+            for (door_label, node_vec) in theirs.get_exit_map().iter() {
+                for next_label in node_vec {
+                    let contains = ours.contains_exit(door_label)
+                        && ours.get_exit_vec(door_label).contains(next_label);
+                    if !contains {
+                        ours.push_exit(door_label, next_label.to_string());
+                    }
+                    if let Some(guard) = theirs.get_guard(door_label, next_label) {
+                        if ours.get_guard(door_label, next_label).is_none() || overrides {
+                            ours.set_guard(door_label, next_label, guard.to_string());
+                        }
+                    }
+                }
+            }
+        }
+
+        for (name, fn2) in other.get_fn_map().iter() {
+            if !self.contains_fn(name) || overrides {
+                self.insert_fn_string(name.to_string(), *fn2);
+            }
+        }
+
+        for (name, guard) in other.get_guard_fn_map().iter() {
+            if !self.contains_guard_fn(name) || overrides {
+                self.insert_guard_fn_string(name.to_string(), *guard);
+            }
+        }
+
+        Ok(conflicts)
+    }
+
+    /// 重ね合わせたときに衝突するものを調べる。
+    fn find_merge_conflicts(&self, other: &Diagram<T>) -> Vec<MergeConflict> {
+        let mut vec = Vec::new();
+
+        if self.get_entry_point() != ""
+            && other.get_entry_point() != ""
+            && self.get_entry_point() != other.get_entry_point()
+        {
+            vec.push(MergeConflict {
+                kind: ConflictKind::EntryPoint,
+                label: "".to_string(),
+                ours: self.get_entry_point(),
+                theirs: other.get_entry_point(),
+            });
+        }
+
+        let mut labels: Vec<&String> = other.get_node_map().keys().collect();
+        labels.sort();
+        for label in labels {
+            if !self.contains_node(label) {
+                continue;
+            }
+            let ours = self.get_node(label);
+            let theirs = other.get_node(label);
+
+            if ours.get_token_caption() != ""
+                && theirs.get_token_caption() != ""
+                && ours.get_token_caption() != theirs.get_token_caption()
+            {
+                vec.push(MergeConflict {
+                    kind: ConflictKind::Token,
+                    label: label.to_string(),
+                    ours: ours.get_token_caption(),
+                    theirs: theirs.get_token_caption(),
+                });
+            }
+            if ours.get_fn_label() != ""
+                && theirs.get_fn_label() != ""
+                && ours.get_fn_label() != theirs.get_fn_label()
+            {
+                vec.push(MergeConflict {
+                    kind: ConflictKind::Fn,
+                    label: label.to_string(),
+                    ours: ours.get_fn_label().to_string(),
+                    theirs: theirs.get_fn_label().to_string(),
+                });
+            }
+            for ((name, ours_value), (_, theirs_value)) in ours
+                .get_option_captions()
+                .into_iter()
+                .zip(theirs.get_option_captions())
+            {
+                if !ours_value.is_empty() && !theirs_value.is_empty() && ours_value != theirs_value {
+                    vec.push(MergeConflict {
+                        kind: ConflictKind::Option(name),
+                        label: label.to_string(),
+                        ours: ours_value,
+                        theirs: theirs_value,
+                    });
+                }
+            }
+        }
+        vec
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use shell::*;
+
+    fn do_nothing(_t: &mut (), _req: &dyn Request, _res: &mut dyn Response) {}
+
+    fn base_diagram() -> Diagram<()> {
+        let mut diagram = Diagram::new();
+        diagram.set_entry_point("TK.a".to_string());
+        diagram.node("TK.a").token("a").next(["TK.b"]);
+        diagram.node("TK.b").token("b");
+        diagram
+    }
+
+    #[test]
+    fn merge_adds_nodes_exits_and_fns() {
+        let mut ours = base_diagram();
+        let mut theirs: Diagram<()> = Diagram::new();
+        theirs.node("TK.a").token("a").next(["TK.c"]);
+        theirs.node("TK.c").token("c").fn_("do_c");
+        theirs.insert_fn("do_c", do_nothing);
+
+        let conflicts = ours.merge(&theirs, MergePolicy::Error).unwrap();
+        assert!(conflicts.is_empty());
+        assert_eq!(ours.get_entry_point(), "TK.a");
+        assert_eq!(
+            ours.get_node("TK.a").get_exit_vec(NEXT_EXIT_LABEL),
+            &vec!["TK.b".to_string(), "TK.c".to_string()]
+        );
+        assert!(ours.contains_node("TK.c"));
+        assert!(ours.contains_fn("do_c"));
+    }
+
+    #[test]
+    fn merge_copies_every_node_option_into_an_existing_node() {
+        let mut ours = base_diagram();
+        let mut theirs: Diagram<()> = Diagram::new();
+        theirs
+            .node("TK.a")
+            .token("a")
+            .priority(5)
+            .call("SUB")
+            .on_enter("enter")
+            .on_leave("leave")
+            .terminator("end")
+            .prefix()
+            .ignore_case()
+            .nfkc();
+
+        ours.merge(&theirs, MergePolicy::Override).unwrap();
+        let node = ours.get_node("TK.a");
+        assert_eq!(node.get_priority(), 5);
+        assert_eq!(node.get_call(), &Some("SUB".to_string()));
+        assert_eq!(node.get_on_enter(), &Some("enter".to_string()));
+        assert_eq!(node.get_on_leave(), &Some("leave".to_string()));
+        assert_eq!(node.get_terminator(), &Some("end".to_string()));
+        assert!(node.is_prefix());
+        assert_eq!(node.get_ignore_case(), Some(true));
+        assert_eq!(node.get_nfkc(), Some(true));
+    }
+
+    #[test]
+    fn merge_reports_option_conflicts_and_follows_the_policy() {
+        let mut theirs: Diagram<()> = Diagram::new();
+        theirs.node("TK.a").token("a").priority(5).call("SUB2");
+        let make_ours = || {
+            let mut ours: Diagram<()> = Diagram::new();
+            ours.node("TK.a").token("a").priority(1).call("SUB1");
+            ours
+        };
+
+        let mut ours = make_ours();
+        let conflicts = ours.merge(&theirs, MergePolicy::Error).unwrap_err();
+        assert_eq!(
+            conflicts.iter().map(|c| c.kind).collect::<Vec<ConflictKind>>(),
+            vec![ConflictKind::Option("priority"), ConflictKind::Option("call")]
+        );
+        assert_eq!(conflicts[0].to_string(), "priority: TK.a [1] <> [5]");
+        assert_eq!(ours.get_node("TK.a").get_priority(), 1);
+
+        let mut ours = make_ours();
+        assert_eq!(ours.merge(&theirs, MergePolicy::Keep).unwrap().len(), 2);
+        assert_eq!(ours.get_node("TK.a").get_priority(), 1);
+        assert_eq!(ours.get_node("TK.a").get_call(), &Some("SUB1".to_string()));
+
+        let mut ours = make_ours();
+        assert_eq!(ours.merge(&theirs, MergePolicy::Override).unwrap().len(), 2);
+        assert_eq!(ours.get_node("TK.a").get_priority(), 5);
+        assert_eq!(ours.get_node("TK.a").get_call(), &Some("SUB2".to_string()));
+    }
+
+    #[test]
+    fn merge_reports_token_and_entry_point_conflicts() {
+        let mut ours = base_diagram();
+        let mut theirs: Diagram<()> = Diagram::new();
+        theirs.set_entry_point("TK.b".to_string());
+        theirs.node("TK.a").regex("a+");
+
+        let conflicts = ours.merge(&theirs, MergePolicy::Error).unwrap_err();
+        assert_eq!(conflicts[0].kind, ConflictKind::EntryPoint);
+        assert_eq!(conflicts[1].kind, ConflictKind::Token);
+        assert_eq!(ours.get_node("TK.a").get_token_caption(), "token: a");
+    }
+}
@@ -1,16 +1,37 @@
+use coverage::*;
 use diagram::*;
 use line_parser::*;
+use parse_observer::*;
+use shell::*;
+use std::mem;
+use transition_error::*;
 
-/// 不具合を取りたいときに真にする。
-// const VERBOSE: bool = false;
+/// 呼び出し元。 `#return` で戻ってくる先。
+///
+/// # Members.
+///
+/// * `label` - `call` を持つノードのラベル。
+/// * `door_label` - 呼び出したときの出口。戻ったら、この出口の行き先に進む。
+#[derive(Clone, Debug, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub struct CallFrame {
+    pub label: String,
+    pub door_label: String,
+}
 
 /// ダイアグラム再生機。
 ///
 /// # Members.
 ///
 /// * `current_label` - 現在のノードのラベル。
+/// * `call_stack` - 呼び出し元の積み重ね。
+/// * `coverage` - 通った記録。 None なら記録しない。
+#[derive(Clone, Debug, Serialize, Deserialize)]
 pub struct DiagramPlayer {
     current_label: String,
+    #[serde(default)]
+    call_stack: Vec<CallFrame>,
+    #[serde(default)]
+    coverage: Option<Coverage>,
 }
 impl Default for DiagramPlayer {
     fn default() -> Self {
@@ -21,6 +42,8 @@ impl DiagramPlayer {
     pub fn new() -> DiagramPlayer {
         DiagramPlayer {
             current_label: "".to_string(),
+            call_stack: Vec::new(),
+            coverage: None,
         }
     }
 
@@ -31,129 +54,405 @@ impl DiagramPlayer {
 
     /// 現在地が遷移図の外か。
     pub fn is_out(&self) -> bool {
-        self.current_label == ""
+        self.current_label.is_empty()
     }
 
-    /// 現在ノードのラベル。
-    pub fn set_current(&mut self, value: &str) {
+    /// 現在ノードを書き換えるだけ。 `on_enter` や `on_leave` は呼ばない。
+    /// 保存しておいた状態に戻すときなどに使う。アクションを呼んで移るなら `move_to` 。
+    pub fn set_current_without_actions(&mut self, value: &str) {
         self.current_label = value.to_string();
-        // TODO println!("set_current: [{}].", self.current_label);
+    }
+    /// 以前の名前。 `on_enter` や `on_leave` を呼ばないことが分かるように 名前を変えた。
+    #[deprecated(note = "Use set_current_without_actions. It does not call on_enter or on_leave.")]
+    pub fn set_current(&mut self, value: &str) {
+        self.set_current_without_actions(value);
+    }
+
+    /// 呼び出し元の積み重ね。最後が 一番最近の呼び出し元。
+    pub fn get_call_stack(&self) -> &Vec<CallFrame> {
+        &self.call_stack
     }
 
-    /// グラフの入り口に遷移する。
-    pub fn enter<T>(&mut self, diagram: &Diagram<T>) {
-        self.set_current(&diagram.get_entry_point().to_string());
+    /// 呼び出しから全部戻ったことにする。
+    pub fn clear_call_stack(&mut self) {
+        self.call_stack.clear();
+    }
+
+    /// 通った記録。記録していなければ None 。
+    pub fn get_coverage(&self) -> Option<&Coverage> {
+        self.coverage.as_ref()
+    }
+    /// `Some(Coverage::new())` を入れると記録を始め、 None を入れると止める。
+    pub fn set_coverage(&mut self, value: Option<Coverage>) {
+        self.coverage = value;
+    }
+    pub(crate) fn record_node(&mut self, node_label: &str) {
+        if let Some(ref mut coverage) = self.coverage {
+            coverage.record_node(node_label);
+        }
+    }
+    pub(crate) fn record_edge(&mut self, node_label: &str, door_label: &str, next_label: &str) {
+        if let Some(ref mut coverage) = self.coverage {
+            coverage.record_edge(node_label, door_label, next_label);
+        }
+    }
+    pub(crate) fn record_fn(&mut self, fn_label: &str) {
+        if let Some(ref mut coverage) = self.coverage {
+            coverage.record_fn(fn_label);
+        }
+    }
+
+    /// 現在ノードを移す。出るノードの `on_leave` 、入るノードの `on_enter` を この順に呼ぶ。
+    /// 同じノードに移るときは、どちらも呼ばない。入った回数は、同じノードでも数える。
+    ///
+    /// # Arguments
+    ///
+    /// * `req` - `on_enter` などに、そのまま渡す。
+    /// * `res` - `on_enter` などが `set_option` で出した指示だけを 書き写す。
+    /// * `label` - 移る先のノードのラベル。空文字列なら遷移図の外に出る。
+    pub fn move_to<T>(
+        &mut self,
+        diagram: &Diagram<T>,
+        t: &mut T,
+        req: &dyn Request,
+        res: &mut dyn Response,
+        label: &str,
+        observer: &mut dyn ParseObserver,
+    ) {
+        if diagram.contains_node(label) {
+            self.record_node(label);
+        }
+        if self.current_label == label {
+            return;
+        }
+        if diagram.contains_node(&self.current_label) {
+            let node = diagram.get_node(&self.current_label);
+            self.call_action(diagram, t, req, res, node, node.get_on_leave(), observer);
+        }
+        self.set_current_without_actions(label);
+        if diagram.contains_node(label) {
+            let node = diagram.get_node(label);
+            self.call_action(diagram, t, req, res, node, node.get_on_enter(), observer);
+        }
+    }
+
+    /// `on_enter` や `on_leave` に書いた コールバック関数を呼ぶ。
+    /// 使い捨てのレスポンスを渡すので、 `forward` 、 `jump_to` 、 `done_line` は 行の解析に影響しない。
+    /// `set_option` で出した指示だけを `res` に書き写す。
+    #[allow(clippy::too_many_arguments)]
+    fn call_action<T>(
+        &mut self,
+        diagram: &Diagram<T>,
+        t: &mut T,
+        req: &dyn Request,
+        res: &mut dyn Response,
+        node: &Node,
+        fn_label: &Option<String>,
+        observer: &mut dyn ParseObserver,
+    ) {
+        if let Some(ref fn_label) = *fn_label {
+            if diagram.contains_fn(fn_label) {
+                observer.on_controller(node.get_label(), fn_label);
+                self.record_fn(fn_label);
+                let mut scratch = ResponseStruct::new();
+                (diagram.get_fn(fn_label))(t, req, &mut scratch);
+                if scratch.option != ResponseOption::None {
+                    res.set_option(scratch.option);
+                }
+            } else {
+                // 無い関数が設定されていた場合は、コンソール表示だけする。
+                println!(
+                    "IGNORE: \"{}\" fn (in {} node) is not found.",
+                    fn_label,
+                    node.get_label()
+                );
+            }
+        }
+    }
+
+    /// グラフの入り口に遷移する。 `on_enter` には、空の行を渡す。
+    ///
+    /// # Returns.
+    ///
+    /// `on_enter` などが `set_option` で出した指示。従うのは呼び出し側。
+    pub fn enter<T>(&mut self, diagram: &Diagram<T>, t: &mut T) -> ResponseOption {
+        let entry_point = diagram.get_entry_point();
+        self.move_to_without_line(diagram, t, &entry_point)
+    }
+
+    /// 行を読まずに移る。 `on_enter` などには空の行を渡し、出した指示を返す。出口の指定は使わない。
+    fn move_to_without_line<T>(
+        &mut self,
+        diagram: &Diagram<T>,
+        t: &mut T,
+        label: &str,
+    ) -> ResponseOption {
+        let req = RequestStruct::new("".to_string());
+        let mut res = ResponseStruct::new();
+        self.move_to(diagram, t, &req, &mut res, label, &mut NullObserver {});
+        mem::replace(&mut res.option, ResponseOption::None)
     }
 
     /// 入り口に入っていないなら、入り口に進む。
-    pub fn enter_when_out<T>(&mut self, diagram: &Diagram<T>) {
-        // println!("元入り口: [{}].", self.current_label);
+    pub fn enter_when_out<T>(
+        &mut self,
+        diagram: &Diagram<T>,
+        t: &mut T,
+        req: &dyn Request,
+        res: &mut dyn Response,
+        observer: &mut dyn ParseObserver,
+    ) {
         if self.is_out() {
-            self.set_current(&diagram.get_entry_point().to_string());
-            // println!("入り口を初期化: [{}].", self.current_label);
+            let entry_point = diagram.get_entry_point();
+            self.move_to(diagram, t, req, res, &entry_point, observer);
         }
     }
 
     /// パースを行い、次に一致するノード名。
-    /// `req` - 正規表現で一致があれば、 groups メンバーに内容を入れる。
+    /// 優先順は `LineParser::rank_candidates` の通り。
+    /// ダイアグラムが backtrack なら、行末まで辿り着ける候補の中から選ぶ。
+    /// `t` - 出口の述語や、 `on_enter` などに渡す、任意のオブジェクト。
+    /// `req` - 一致したノードが取り込んだ内容を、 groups メンバーに入れる。
+    /// `res` - `on_enter` などに渡す。
+    /// `observer` - 候補と、選んだノードを知らせる先。
     /// # Returns.
-    /// 正規表現で一致したら真。
+    /// 取り込むノードで一致したら真。
     pub fn forward_parse<T>(
         &mut self,
         diagram: &Diagram<T>,
+        t: &mut T,
         req: &mut dyn Request,
+        res: &mut dyn Response,
         door_label: &str,
+        observer: &mut dyn ParseObserver,
     ) -> bool {
-        // 現在ノード取得。
-        let current_node = diagram.get_node(&self.get_current());
-        // 現在ノードの出口ドア一覧。
-        let current_exit_vec = match current_node.get_exit_map().get(door_label) {
-            Some(n) => n,
-            None => panic!(
-                "door_label=[{}] is not found. (current=[{}] node)",
+        match self.try_forward_parse(diagram, t, req, res, door_label, observer) {
+            Ok(n) => n,
+            Err(err) => panic!("{}", err),
+        }
+    }
+
+    /// `forward_parse` の、現在ノードや出口が無ければ 何もせずに Err を返す版。
+    /// どの候補にも一致しないのはエラーではない。遷移図の外に出て Ok を返す。
+    pub fn try_forward_parse<T>(
+        &mut self,
+        diagram: &Diagram<T>,
+        t: &mut T,
+        req: &mut dyn Request,
+        res: &mut dyn Response,
+        door_label: &str,
+        observer: &mut dyn ParseObserver,
+    ) -> Result<bool, TransitionError> {
+        self.try_forward_parse_match(diagram, t, req, res, door_label, observer)?;
+        Ok(match diagram.get_node_map().get(&self.get_current()) {
+            Some(node) => node.get_kind() != NodeKind::Token && node.get_kind() != NodeKind::OneOf,
+            None => false,
+        })
+    }
+
+    /// `try_forward_parse` の、選んだ候補の一致結果を返す版。
+    /// 行単位パーサーは、候補を選ぶときに調べた一致を そのまま使い、もう一度 照合しない。
+    /// どの候補にも一致しなければ None 。
+    pub(crate) fn try_forward_parse_match<T>(
+        &mut self,
+        diagram: &Diagram<T>,
+        t: &mut T,
+        req: &mut dyn Request,
+        res: &mut dyn Response,
+        door_label: &str,
+        observer: &mut dyn ParseObserver,
+    ) -> Result<Option<NodeMatch>, TransitionError> {
+        let current_label = self.get_current();
+        // 候補を探す前に、出口があるか確かめる。
+        LineParser::collect_exits(diagram, &current_label, door_label, &self.call_stack)?;
+        let chosen = if diagram.is_backtrack() {
+            LineParser::find_path(
+                diagram,
+                t,
+                &current_label,
+                door_label,
+                &self.call_stack,
+                req,
+                observer,
+            )
+            .and_then(|path| path.into_iter().next())
+        } else {
+            LineParser::rank_candidates(
+                diagram,
+                t,
+                &current_label,
                 door_label,
-                self.get_current()
-            ),
+                &self.call_stack,
+                req,
+                observer,
+            )?
+            .into_iter()
+            .next()
         };
 
-        // 一番優先されるものを探す。
-        let mut best_node_label = "".to_string();
-        let mut best_node_re_label = "".to_string();
-
-        // 次の候補。
-        let mut max_token_len = 0;
-        for i_next_node_label in current_exit_vec.iter() {
-            let next_node_label = i_next_node_label.trim();
-            // println!("next_node_label: {}", next_node_label);
-            let node_name = next_node_label.to_string();
-            if node_name.trim() == "" {
-                panic!("Next node label is empty. ([{}] node)", &self.get_current())
+        let (label, node_match) = match chosen {
+            Some(candidate) => {
+                for (node_label, door_label, next_label) in &candidate.edges {
+                    self.record_edge(node_label, door_label, next_label);
+                }
+                self.call_stack = candidate.stack;
+                observer.on_chosen(&candidate.label);
+                (candidate.label, Some(candidate.node_match))
             }
-
-            if diagram.contains_node(&node_name) {
-                //println!("contains.");
-
-                let node = &diagram.get_node(&node_name);
-
-                let matched;
-                if node.is_regex() {
-                    if LineParser::starts_with_reg(node, req) {
-                        // 正規表現で一致したなら。
-                        best_node_re_label = node_name;
-                        // 固定長で一致するものも探したい。
-                    }
-                } else {
-                    matched = LineParser::starts_with_literal(node, req);
-                    if matched {
-                        //println!("starts_with_literal.");
-                        let token_len = node.get_token().chars().count();
-                        if max_token_len < token_len {
-                            max_token_len = token_len;
-                            best_node_label = node_name;
-                            // まだ、一番長い、固定長トークンを探したい。
-                        };
-                        //} else {
-                        //    println!("not starts_with_literal. req.line={}, req.line_len={}, res.starts={}", req.line, req.line_len, res.starts);
-                    }
+            None => {
+                observer.on_no_match(&current_label, door_label, req.get_caret());
+                ("".to_string(), None)
+            }
+        };
+        if let Some(req) = req.as_mut_any().downcast_mut::<RequestStruct>() {
+            match node_match {
+                Some(ref n) => {
+                    req.groups = n.groups.clone();
+                    req.value = n.value;
+                }
+                None => {
+                    req.groups = Vec::new();
+                    req.value = None;
                 }
             }
+        } else {
+            panic!("Downcast fail.");
         }
-
-        // TODO カレントを遷移し、それが正規表現かどうかだけ返す。
-        if best_node_label != "" {
-            // 固定長での一致を優先。
-            self.set_current(&best_node_label);
-            return false;
-        }
-        // 正規表現は優先度低い。
-        self.set_current(&best_node_re_label);
-        true
+        self.move_to(diagram, t, req, res, &label, observer);
+        Ok(node_match)
     }
 
     /// 状態遷移する。
     /// パーサーのマッチングを省いて、強制的に指定のドアにフォワードする。
     /// １つのドアから複数のノードにつながっている場合は　エラーとする。
-    pub fn forward_force<T>(&mut self, diagram: &Diagram<T>, door_label: &str) {
-        // 現在ノード取得。
-        let current_node = diagram.get_node(&self.get_current());
-        // 現在ノード
-        let current_exit_vec = match current_node.get_exit_map().get(door_label) {
-            Some(n) => n,
-            None => panic!(
-                "door_label: [{}] is not found. ([{}] node)",
-                self.get_current(),
-                door_label
-            ),
+    /// `on_enter` などには、空の行を渡す。
+    ///
+    /// # Returns.
+    ///
+    /// `on_enter` などが `set_option` で出した指示。従うのは呼び出し側。
+    pub fn forward_force<T>(
+        &mut self,
+        diagram: &Diagram<T>,
+        t: &mut T,
+        door_label: &str,
+    ) -> ResponseOption {
+        match self.try_forward_force(diagram, t, door_label) {
+            Ok(n) => n,
+            Err(err) => panic!("{}", err),
+        }
+    }
+
+    /// `forward_force` の、遷移できなければ何もせずに Err を返す版。
+    pub fn try_forward_force<T>(
+        &mut self,
+        diagram: &Diagram<T>,
+        t: &mut T,
+        door_label: &str,
+    ) -> Result<ResponseOption, TransitionError> {
+        let current_label = self.get_current();
+        let current_exit_vec = diagram
+            .try_get_node(&current_label)?
+            .try_get_exit_vec(door_label)?;
+        let next_label = match current_exit_vec.len() {
+            0 => {
+                return Err(TransitionError::DoorNotFound(
+                    current_label,
+                    door_label.to_string(),
+                ))
+            }
+            1 => current_exit_vec[0].to_string(),
+            _ => {
+                return Err(TransitionError::AmbiguousDoor(
+                    current_label,
+                    door_label.to_string(),
+                ))
+            }
         };
 
-        if 1 < current_exit_vec.len() {
-            panic!(
-                "{} node {} exit is not one.",
-                &self.get_current(),
-                door_label
-            )
-        }
+        self.record_edge(&current_label, door_label, &next_label);
+        Ok(self.move_to_without_line(diagram, t, &next_label))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn do_enter(log: &mut Vec<String>, _req: &dyn Request, _res: &mut dyn Response) {
+        log.push("enter".to_string());
+    }
+    fn do_leave(log: &mut Vec<String>, _req: &dyn Request, _res: &mut dyn Response) {
+        log.push("leave".to_string());
+    }
+    fn do_quit(log: &mut Vec<String>, _req: &dyn Request, res: &mut dyn Response) {
+        log.push("quit".to_string());
+        res.set_option(ResponseOption::Quits);
+    }
+
+    /// `HEAD` から `#next` で `GAME` へ。 `GAME` から `#next` で `END` へ。
+    fn new_diagram() -> Diagram<Vec<String>> {
+        let mut diagram = Diagram::new();
+        diagram.insert_fn("do_enter", do_enter);
+        diagram.insert_fn("do_leave", do_leave);
+        diagram.insert_fn("do_quit", do_quit);
+        diagram.set_entry_point("HEAD".to_string());
+        diagram.node("HEAD").next(["GAME"]);
+        diagram
+            .node("GAME")
+            .on_enter("do_enter")
+            .on_leave("do_leave")
+            .next(["END"]);
+        diagram.node("END").on_enter("do_quit");
+        diagram
+    }
+
+    #[test]
+    fn forward_force_calls_actions_and_returns_their_option() {
+        let diagram = new_diagram();
+        let mut player = DiagramPlayer::new();
+        let mut log = Vec::new();
+
+        assert_eq!(player.enter(&diagram, &mut log), ResponseOption::None);
+        assert_eq!(player.forward_force(&diagram, &mut log, NEXT_EXIT_LABEL), ResponseOption::None);
+        assert_eq!(log, vec!["enter"]);
+        assert_eq!(
+            player.try_forward_force(&diagram, &mut log, NEXT_EXIT_LABEL),
+            Ok(ResponseOption::Quits)
+        );
+        assert_eq!(log, vec!["enter", "leave", "quit"]);
+        assert_eq!(player.get_current(), "END");
+    }
+
+    #[test]
+    fn enter_returns_the_option_of_the_entry_action() {
+        let mut diagram = new_diagram();
+        diagram.set_entry_point("END".to_string());
+        let mut player = DiagramPlayer::new();
+        let mut log = Vec::new();
+
+        assert_eq!(player.enter(&diagram, &mut log), ResponseOption::Quits);
+        assert_eq!(log, vec!["quit"]);
+    }
+
+    #[test]
+    fn set_current_without_actions_calls_nothing() {
+        let diagram = new_diagram();
+        let mut player = DiagramPlayer::new();
+        let mut log = Vec::new();
+
+        player.set_current_without_actions("GAME");
+        player.set_current_without_actions("HEAD");
+        assert!(log.is_empty());
+        // 移った先からは、ふつうに進める。
+        player.forward_force(&diagram, &mut log, NEXT_EXIT_LABEL);
+        assert_eq!(log, vec!["enter"]);
+    }
 
-        self.set_current(&current_exit_vec[0].to_string());
+    #[test]
+    #[allow(deprecated)]
+    fn set_current_is_kept_as_an_alias() {
+        let mut player = DiagramPlayer::new();
+        player.set_current("GAME");
+        assert_eq!(player.get_current(), "GAME");
     }
 }
@@ -7,6 +7,7 @@ extern crate serde;
 extern crate serde_json;
 
 extern crate regex;
+extern crate unicode_normalization;
 
 /// https://stackoverflow.com/questions/28392008/more-concise-hashmap-initialization |More concise HashMap initialization
 #[macro_export]
@@ -19,7 +20,21 @@ macro_rules! hashmap {
 }
 
 pub mod models;
+pub mod coverage;
 pub mod diagram_player;
 pub mod diagram;
+pub mod diagram_builder;
+pub mod diagram_diff;
+pub mod diagram_export;
+pub mod diagram_merge;
 pub mod line_parser;
+pub mod parse_observer;
+pub mod separator;
+pub mod shared_diagram;
 pub mod shell;
+pub mod shell_snapshot;
+pub mod token_value;
+pub mod transition_error;
+
+#[cfg(test)]
+mod test_support;
@@ -1,11 +1,130 @@
 use diagram::*;
 /// 行単位のパーサー
 use diagram_player::*;
-use regex::Regex;
+use parse_observer::*;
+use separator::*;
 use shell::*;
+use std::collections::HashSet;
+use std::fmt;
+use token_value::*;
+use transition_error::*;
+use unicode_normalization::UnicodeNormalization;
 
-/// 不具合を取りたいときに真にする。
-const VERBOSE: bool = false;
+/// ノードがトークンに一致した結果。
+///
+/// # Members
+///
+/// * `end` - トークンの終わりのキャレット。区切りを読み飛ばす前。
+/// * `groups` - コールバック関数に渡す groups 。
+/// * `value` - 値のノードなら、変換した値。
+pub struct NodeMatch {
+    pub end: usize,
+    pub groups: Vec<String>,
+    pub value: Option<TokenValue>,
+}
+
+/// 通った出口の矢印 (ノード, 出口, 行き先) 。
+pub type TakenEdge = (String, String, String);
+
+/// 次の行き先の候補。
+///
+/// # Members
+///
+/// * `label` - 候補のノードのラベル。
+/// * `node_match` - そのノードが一致した結果。
+/// * `stack` - そのノードに進んだ後の、呼び出し元の積み重ね。
+/// * `edges` - そのノードに着くまでに通る矢印。
+pub struct Candidate {
+    pub label: String,
+    pub node_match: NodeMatch,
+    pub stack: Vec<CallFrame>,
+    pub edges: Vec<TakenEdge>,
+}
+
+/// 呼び出しと戻りを解いた、出口の行き先。
+///
+/// # Members
+///
+/// * `label` - 行き先のノードのラベル。
+/// * `stack` - そのノードに進んだ後の、呼び出し元の積み重ね。
+/// * `guards` - 行き先に着くまでに通る、述語の登録名。全部 真なら候補にする。
+/// * `edges` - 行き先に着くまでに通る矢印。 `#return` を通れば2本以上になる。
+pub struct Exit {
+    pub label: String,
+    pub stack: Vec<CallFrame>,
+    pub guards: Vec<String>,
+    pub edges: Vec<TakenEdge>,
+}
+
+/// 空読みで一致したノード。
+///
+/// # Members
+///
+/// * `label` - 一致したノードのラベル。
+/// * `start` - トークンの始まりのキャレット。
+/// * `end` - トークンの終わりのキャレット。区切りを読み飛ばす前。
+/// * `matched` - 一致した文字列。
+/// * `groups` - コールバック関数に渡すはずの groups 。
+/// * `value` - 値のノードなら、変換した値。
+#[derive(Clone, Debug, PartialEq)]
+pub struct ParsedToken {
+    pub label: String,
+    pub start: usize,
+    pub end: usize,
+    pub matched: String,
+    pub groups: Vec<String>,
+    pub value: Option<TokenValue>,
+}
+
+/// 空読みが どこで終わったか。
+#[derive(Clone, Debug, PartialEq)]
+pub enum DryRunEnd {
+    /// 行末まで読めた。「行末」ノードのラベル。
+    Newline(String),
+    /// このキャレットの位置で、何とも一致しなかった。本番なら `#else` ノードで処理する。
+    Else(usize),
+    /// 遷移できなかった。本番なら `#else` ノードで処理する。
+    Error(TransitionError),
+}
+
+/// 空読みの結果。
+///
+/// # Members
+///
+/// * `tokens` - 一致したノードを、一致した順に。
+/// * `end` - どこで終わったか。
+#[derive(Clone, Debug, PartialEq)]
+pub struct DryRun {
+    pub tokens: Vec<ParsedToken>,
+    pub end: DryRunEnd,
+}
+impl DryRun {
+    /// 行末まで読めたら真。
+    pub fn is_accepted(&self) -> bool {
+        matches!(self.end, DryRunEnd::Newline(_))
+    }
+}
+impl fmt::Display for DryRun {
+    /// 一致したノードを 1行に1件。最後に どこで終わったか。
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        for token in &self.tokens {
+            write!(
+                f,
+                "[{}] {}..{} \"{}\"",
+                token.label, token.start, token.end, token.matched
+            )?;
+            if !token.groups.is_empty() {
+                write!(f, " groups: {}", token.groups.join(", "))?;
+            }
+            writeln!(f)?;
+        }
+        match self.end {
+            DryRunEnd::Newline(ref tail_node_label) => writeln!(f, "newline: [{}]", tail_node_label),
+            DryRunEnd::Else(caret) => writeln!(f, "else: at {}", caret),
+            DryRunEnd::Error(ref err) => writeln!(f, "error: {}", err),
+        }
+    }
+}
 
 pub struct LineParser {}
 impl LineParser {
@@ -26,9 +145,18 @@ impl LineParser {
         req: &mut dyn Request,
         res: &mut dyn Response,
     ) {
-        // 現在地が遷移図の外なら、入り口から入れだぜ☆（＾～＾）
-        diagram_player.enter_when_out(&diagram);
+        LineParser::run_observed(diagram_player, diagram, t, req, res, &mut NullObserver {});
+    }
 
+    /// `run` の、途中経過を `observer` に知らせる版。
+    pub fn run_observed<T>(
+        diagram_player: &mut DiagramPlayer,
+        diagram: &Diagram<T>,
+        t: &mut T,
+        req: &mut dyn Request,
+        res: &mut dyn Response,
+        observer: &mut dyn ParseObserver,
+    ) {
         // レスポンスを、デフォルト値にリセット。
         if let Some(res) = res.as_mut_any().downcast_mut::<ResponseStruct>() {
             res.reset();
@@ -36,10 +164,15 @@ impl LineParser {
             panic!("Downcast fail. res.");
         }
 
-        'line: while req.get_caret() < req.get_line_len() {
+        // 現在地が遷移図の外なら、入り口から入れだぜ☆（＾～＾）
+        diagram_player.enter_when_out(diagram, t, req, res, observer);
+
+        while req.get_caret() < req.get_line_len() {
             // リクエストとレスポンスをクリアー。
             if let Some(req) = req.as_mut_any().downcast_mut::<RequestStruct>() {
                 req.groups.clear(); // クリアー
+                req.matched.clear();
+                req.value = None;
             } else {
                 panic!("Downcast fail. req.");
             }
@@ -49,46 +182,58 @@ impl LineParser {
             // ****************************************************************************************************
             // * 次の行き先に遷移。（フォワード）                                                             *
             // ****************************************************************************************************
-            let best_is_regex = if let Some(res) = res.as_any().downcast_ref::<ResponseStruct>() {
-                diagram_player.forward_parse(diagram, req, &res.exit_label.to_string())
+            let exit_label = if let Some(res) = res.as_any().downcast_ref::<ResponseStruct>() {
+                res.exit_label.to_string()
             } else {
                 panic!("Downcast fail.");
             };
-
-            // キャレットを進める。
-            if diagram_player.get_current() != "" {
-                res.set_caret(req.get_caret());
-
-                if best_is_regex {
-                    // 正規表現に一致なら
-                    LineParser::parse_reg(req, res);
-
-                } else {
-                    LineParser::parse_literal(&diagram.get_node(&diagram_player.get_current()), req, res);
-
+            let chosen = match diagram_player
+                .try_forward_parse_match(diagram, t, req, res, &exit_label, observer)
+            {
+                Ok(n) => n,
+                Err(err) => {
+                    LineParser::parse_line_error(diagram_player, diagram, t, req, res, observer, err);
+                    return;
                 }
+            };
+
+            // キャレットを進める。候補を選んだときの一致を使う。
+            if let Some(token_match) = chosen {
+                let current_node = diagram.get_node(&diagram_player.get_current());
+                let token_start = req.get_caret();
+                // 続きの区切りを読み飛ばす。
+                let caret = diagram
+                    .get_separator_of(current_node)
+                    .skip(req.get_line(), token_match.end);
 
                 if let Some(req) = req.as_mut_any().downcast_mut::<RequestStruct>() {
-                    if let Some(res) = res.as_any().downcast_ref::<ResponseStruct>() {
-                        req.caret = res.caret;
-                    } else {
-                        panic!("Downcast fail.");
-                    }
+                    req.matched = req.line[token_start..token_match.end].to_string();
+                    req.groups = token_match.groups;
+                    req.value = token_match.value;
+                    req.caret = caret;
                 } else {
                     panic!("Downcast fail.");
                 }
+                observer.on_caret(token_start, caret);
 
                 // res.set_caret(0);
                 res.set_caret(req.get_caret());
                 res.forward(NEXT_EXIT_LABEL); // デフォルト値。
+                if let Some(res) = res.as_mut_any().downcast_mut::<ResponseStruct>() {
+                    res.jump_label = None;
+                } else {
+                    panic!("Downcast fail.");
+                }
 
                 let node = &diagram.get_node(&diagram_player.get_current());
 
                 // あれば、コントローラーに処理を移譲。
                 if node.get_fn_label() == "" {
                     // コントローラーを指定していなければ、出口ラベルは、デフォルト値のまま。
-                } else if diagram.contains_fn(&node.get_fn_label()) {
-                    (diagram.get_fn(&node.get_fn_label()))(t, req, res);
+                } else if diagram.contains_fn(node.get_fn_label()) {
+                    observer.on_controller(node.get_label(), node.get_fn_label());
+                    diagram_player.record_fn(node.get_fn_label());
+                    (diagram.get_fn(node.get_fn_label()))(t, req, res);
                 } else {
                     // 無い関数が設定されていた場合は、コンソール表示だけする。
                     println!(
@@ -98,7 +243,7 @@ impl LineParser {
                     );
                 }
 
-                if let Some(res) = res.as_any().downcast_ref::<ResponseStruct>() {
+                let jump_label = if let Some(res) = res.as_mut_any().downcast_mut::<ResponseStruct>() {
                     if res.done_line {
                         // 行解析の終了。
                         let len = req.get_line_len();
@@ -109,14 +254,24 @@ impl LineParser {
                             panic!("Downcast fail.");
                         }
                     }
+                    res.jump_label.take()
                 } else {
                     panic!("Downcast fail.");
+                };
+
+                // コントローラーが指定したなら、出口を使わずに跳ぶ。
+                if let Some(jump_label) = jump_label {
+                    if !diagram.contains_node(&jump_label) {
+                        let err = TransitionError::NodeNotFound(jump_label);
+                        LineParser::parse_line_error(diagram_player, diagram, t, req, res, observer, err);
+                        return;
+                    }
+                    diagram_player.move_to(diagram, t, req, res, &jump_label, observer);
                 }
             } else {
                 // 何とも一致しなかったら実行します
-                LineParser::parse_line_else(&diagram, t, req, res);
-                // 次のラインへ。
-                break 'line;
+                LineParser::fall_to_else(diagram_player, diagram, t, req, res, observer);
+                return;
             }
 
             if let Some(res) = res.as_any().downcast_ref::<ResponseStruct>() {
@@ -132,6 +287,9 @@ impl LineParser {
                     Saves(ref _file) => {
                         return;
                     }
+                    RestoresBackup(ref _file, _generation) => {
+                        return;
+                    }
                 }
             } else {
                 panic!("Downcast fail.");
@@ -140,59 +298,130 @@ impl LineParser {
             // 次のトークンへ。
         }
 
+        if let Err(err) = LineParser::parse_newline(diagram_player, diagram, t, req, res, observer) {
+            LineParser::parse_line_error(diagram_player, diagram, t, req, res, observer, err);
+        }
+    }
+
+    /// 行末の処理。「行末」ノードの関数を呼んで、次の「行頭」ノードへ進む。
+    /// 進めないと分かったら、何もせずにエラーを返す。
+    fn parse_newline<T>(
+        diagram_player: &mut DiagramPlayer,
+        diagram: &Diagram<T>,
+        t: &mut T,
+        req: &mut dyn Request,
+        res: &mut dyn Response,
+        observer: &mut dyn ParseObserver,
+    ) -> Result<(), TransitionError> {
         // ここで、現在ノードは "#newline" を記述している必要がある。
         // ****************************************************************************************************
         //  (指定があるなら)行終了を「登録」。(行終了するわけではない)
         // ****************************************************************************************************
-        let node = &diagram.get_node(&diagram_player.get_current());
-        if node.contains_exit(&NEWLINE_EXIT_LABEL.to_string()) {
-            // 次の「行末」ノードへ。抽出するノード ラベルは 必ず先頭の1つだけ とする。
-            let tail_node_label = &node.get_exit_vec(&NEWLINE_EXIT_LABEL.to_string())[0];
-
-            // 「行末」の関数を「登録」する。
-            let tail_node = diagram.get_node(&tail_node_label);
-            let fn_label = tail_node.get_fn_label();
-            if diagram.contains_fn(&fn_label) {
-                let mut current_newline_fn: Controller<T> = *diagram.get_fn(&fn_label);
-                // ****************************************************************************************************
-                //  改行（1行読取）に対応したコールバック関数を実行。
-                // ****************************************************************************************************
-                (current_newline_fn)(t, req, res); // responseは無視する。
+        // 次の「行末」ノードへ。抽出するノード ラベルは 必ず先頭の1つだけ とする。
+        // `#return` なら 呼び出し元の「行末」ノードへ。
+        let current_label = diagram_player.get_current();
+        let (tail_node_label, registered_next_head_node_label, newline_edges) =
+            LineParser::resolve_line_end(diagram, &current_label, diagram_player.get_call_stack())?;
+        let tail_node = diagram.get_node(&tail_node_label);
 
-            } else {
-                // 無い関数が設定されていた場合は、コンソール表示だけする。
-                println!(
-                    "IGNORE: \"{}\" fn (in {} node) is not found.",
-                    &fn_label, NEWLINE_EXIT_LABEL
-                );
-            }
+        observer.on_newline(&current_label, &tail_node_label);
+        for (node_label, door_label, next_label) in &newline_edges {
+            diagram_player.record_edge(node_label, door_label, next_label);
+        }
+        // 行が終わったら、呼び出しからは全部戻る。
+        diagram_player.clear_call_stack();
+        diagram_player.move_to(diagram, t, req, res, &tail_node_label, observer);
 
-            // 次の「行頭」ノードを「登録」。抽出するノード ラベルは 必ず先頭の1つだけ とする。
-            let mut registered_next_head_node_label =
-                tail_node.get_exit_vec(NEXT_EXIT_LABEL)[0].to_string();
-            diagram_player.set_current(&registered_next_head_node_label);
-            /*
+        // 「行末」の関数を「登録」する。
+        let fn_label = tail_node.get_fn_label();
+        if diagram.contains_fn(fn_label) {
+            observer.on_controller(&tail_node_label, fn_label);
+            diagram_player.record_fn(fn_label);
+            let current_newline_fn: Controller<T> = *diagram.get_fn(fn_label);
+            // ****************************************************************************************************
+            //  改行（1行読取）に対応したコールバック関数を実行。
+            // ****************************************************************************************************
+            (current_newline_fn)(t, req, res); // responseは無視する。
+        } else {
+            // 無い関数が設定されていた場合は、コンソール表示だけする。
             println!(
-                "行終了登録 tail_node_label: [{}], registered_next_head_node_label: [{}].",
-                tail_node_label, registered_next_head_node_label
+                "IGNORE: \"{}\" fn (in {} node) is not found.",
+                &fn_label, NEWLINE_EXIT_LABEL
             );
-                */
-
+        }
 
-            // TODO 改行の設定、廃止したい。
-            // if registered_next_head_node_label != "" {
-                // 設定されているなら、上書き。
-            //}
-            /*
-            println!(
-                "行終了 self.current_label: [{}].",
-                self.current_label
-            );
-            */
+        // 次の「行頭」ノードを「登録」。
+        diagram_player.record_edge(
+            &tail_node_label,
+            NEXT_EXIT_LABEL,
+            &registered_next_head_node_label,
+        );
+        diagram_player.move_to(
+            diagram,
+            t,
+            req,
+            res,
+            &registered_next_head_node_label,
+            observer,
+        );
+        Ok(())
+    }
 
-        } else {
-            panic!("\"#newline\" door is not found. (current [{}] node)", diagram_player.get_current());
+    /// 何とも一致しなかったとき。 `#else` ノードの関数を呼んで、この行を終える。
+    /// 次の行は `#else` ノードに `#next` があればそこから、無ければ入り口から始める。
+    fn fall_to_else<T>(
+        diagram_player: &mut DiagramPlayer,
+        diagram: &Diagram<T>,
+        t: &mut T,
+        req: &mut dyn Request,
+        res: &mut dyn Response,
+        observer: &mut dyn ParseObserver,
+    ) {
+        observer.on_else(req.get_line(), req.get_caret());
+        if diagram.contains_node(ELSE_NODE_LABEL) {
+            diagram_player.record_node(ELSE_NODE_LABEL);
+            let fn_label = diagram.get_node(ELSE_NODE_LABEL).get_fn_label();
+            if diagram.contains_fn(fn_label) {
+                diagram_player.record_fn(fn_label);
+            }
         }
+        LineParser::parse_line_else(diagram, t, req, res);
+        let next_vec = match diagram.try_get_node(ELSE_NODE_LABEL) {
+            Ok(node) => node.try_get_exit_vec(NEXT_EXIT_LABEL).ok(),
+            Err(_) => None,
+        };
+        let next_label = match next_vec.map(|vec| vec.first()) {
+            Some(Some(next_label)) => {
+                diagram_player.record_edge(ELSE_NODE_LABEL, NEXT_EXIT_LABEL, next_label);
+                next_label.to_string()
+            }
+            Some(None) => {
+                // `#next` を書いたのに空。入り口から始める。
+                observer.on_error(&TransitionError::DoorNotFound(
+                    ELSE_NODE_LABEL.to_string(),
+                    NEXT_EXIT_LABEL.to_string(),
+                ));
+                "".to_string()
+            }
+            None => "".to_string(),
+        };
+        diagram_player.clear_call_stack();
+        diagram_player.move_to(diagram, t, req, res, &next_label, observer);
+    }
+
+    /// 遷移できなかったとき。プロセスは止めずに、何とも一致しなかったときと同じく `#else` で処理する。
+    /// エラーは `observer` にだけ知らせる。
+    fn parse_line_error<T>(
+        diagram_player: &mut DiagramPlayer,
+        diagram: &Diagram<T>,
+        t: &mut T,
+        req: &mut dyn Request,
+        res: &mut dyn Response,
+        observer: &mut dyn ParseObserver,
+        error: TransitionError,
+    ) {
+        observer.on_error(&error);
+        LineParser::fall_to_else(diagram_player, diagram, t, req, res, observer);
     }
 
     // cyclomatic complexity を避けたいだけ。
@@ -202,14 +431,14 @@ impl LineParser {
         req: &mut dyn Request,
         res: &mut dyn Response,
     ) {
-        if diagram.contains_node(&ELSE_NODE_LABEL.to_string()) {
-            let fn_label = diagram.get_node(&ELSE_NODE_LABEL.to_string())
+        if diagram.contains_node(ELSE_NODE_LABEL) {
+            let fn_label = diagram.get_node(ELSE_NODE_LABEL)
                 .get_fn_label();
-            if diagram.contains_fn(&fn_label) {
+            if diagram.contains_fn(fn_label) {
                 // ****************************************************************************************************
                 //  コールバック関数を実行。
                 // ****************************************************************************************************
-                (diagram.get_fn(&fn_label))(t, req, res);
+                (diagram.get_fn(fn_label))(t, req, res);
             // responseは無視する。
             } else {
                 // 無い関数が設定されていた場合は、コンソール表示だけする。
@@ -221,102 +450,1589 @@ impl LineParser {
         }
     }
 
-    fn parse_literal(node: &Node, req: &dyn Request, res: &mut dyn Response) {
-        res.set_caret(req.get_caret() + node.get_token().len());
-        let res_caret;
-        if let Some(res) = res.as_any().downcast_ref::<ResponseStruct>() {
-            res_caret = res.caret;
+    /// 現在ノードの出口の行き先を、呼び出しと戻りを解いて並べます。
+    /// 現在ノードが `call` を持つなら、呼び出し先の `#next` を並べます。
+    /// 行き先の `#return` は、呼び出し元の出口の行き先に置き換えます。
+    ///
+    /// # Arguments
+    ///
+    /// * `stack` - 呼び出し元の積み重ね。
+    /// * returns - ノードや出口が無いか、行き先のラベルが空なら Err 。
+    pub fn collect_exits<T>(
+        diagram: &Diagram<T>,
+        current_label: &str,
+        door_label: &str,
+        stack: &[CallFrame],
+    ) -> Result<Vec<Exit>, TransitionError> {
+        let current_node = diagram.try_get_node(current_label)?;
+        match *current_node.get_call() {
+            Some(ref callee) => {
+                let mut pushed = stack.to_vec();
+                pushed.push(CallFrame {
+                    label: current_label.to_string(),
+                    door_label: door_label.to_string(),
+                });
+                LineParser::collect_exits_of(diagram, callee, NEXT_EXIT_LABEL, pushed)
+            }
+            None => LineParser::collect_exits_of(diagram, current_label, door_label, stack.to_vec()),
+        }
+    }
+
+    fn collect_exits_of<T>(
+        diagram: &Diagram<T>,
+        label: &str,
+        door_label: &str,
+        stack: Vec<CallFrame>,
+    ) -> Result<Vec<Exit>, TransitionError> {
+        let node = diagram.try_get_node(label)?;
+        let exit_vec = node.try_get_exit_vec(door_label)?;
+
+        let mut vec = Vec::new();
+        for next_label in exit_vec {
+            if next_label.trim().is_empty() {
+                // 空のラベルには進めない。書き間違いとして知らせる。
+                return Err(TransitionError::NodeNotFound(next_label.to_string()));
+            }
+            let next_label = next_label.trim();
+
+            let guard = node.get_guard(door_label, next_label);
+            if next_label == RETURN_LABEL {
+                // 呼び出されていなければ、戻る先は無い。
+                if let Some(frame) = stack.last() {
+                    let mut popped = stack.clone();
+                    popped.pop();
+                    let caller_door = if door_label == NEXT_EXIT_LABEL {
+                        &frame.door_label
+                    } else {
+                        door_label
+                    };
+                    if let Ok(caller_exits) =
+                        LineParser::collect_exits_of(diagram, &frame.label, caller_door, popped)
+                    {
+                        for mut exit in caller_exits {
+                            if let Some(guard) = guard {
+                                exit.guards.insert(0, guard.to_string());
+                            }
+                            exit.edges.insert(
+                                0,
+                                (label.to_string(), door_label.to_string(), next_label.to_string()),
+                            );
+                            vec.push(exit);
+                        }
+                    }
+                }
+            } else {
+                vec.push(Exit {
+                    label: next_label.to_string(),
+                    stack: stack.clone(),
+                    guards: guard.iter().map(|guard| guard.to_string()).collect(),
+                    edges: vec![(label.to_string(), door_label.to_string(), next_label.to_string())],
+                });
+            }
+        }
+        Ok(vec)
+    }
+
+    /// 行末で進む「行末」ノードを、 `#return` を解いて探します。
+    ///
+    /// # Arguments
+    ///
+    /// * `label` - 行末にいるノードのラベル。
+    /// * `stack` - 呼び出し元の積み重ね。
+    /// * returns - (行末ノード, 通る矢印) 。 `#newline` が無いか空なら None 。
+    pub fn resolve_newline<T>(
+        diagram: &Diagram<T>,
+        label: &str,
+        stack: &[CallFrame],
+    ) -> Option<(String, Vec<TakenEdge>)> {
+        let node = diagram.try_get_node(label).ok()?;
+        // 空の `#newline` は、書いていないのと同じ。
+        let tail_node_label = node.try_get_exit_vec(NEWLINE_EXIT_LABEL).ok()?.first()?;
+        let edge = (
+            label.to_string(),
+            NEWLINE_EXIT_LABEL.to_string(),
+            tail_node_label.to_string(),
+        );
+        if tail_node_label == RETURN_LABEL {
+            let (frame, popped) = stack.split_last()?;
+            let (tail_node_label, mut edges) =
+                LineParser::resolve_newline(diagram, &frame.label, popped)?;
+            edges.insert(0, edge);
+            Some((tail_node_label, edges))
         } else {
-            panic!("Downcast fail.");
+            Some((tail_node_label.to_string(), vec![edge]))
         }
+    }
+
+    /// 行末で進む「行末」ノードと、その次の「行頭」ノードを探します。
+    /// `#newline` 、「行末」ノード、その `#next` のどれかが無ければ Err 。
+    ///
+    /// # Arguments
+    ///
+    /// * `label` - 行末にいるノードのラベル。
+    /// * `stack` - 呼び出し元の積み重ね。
+    /// * returns - (行末ノード, 行頭ノード, 行末ノードまでに通る矢印) 。
+    fn resolve_line_end<T>(
+        diagram: &Diagram<T>,
+        label: &str,
+        stack: &[CallFrame],
+    ) -> Result<(String, String, Vec<TakenEdge>), TransitionError> {
+        let (tail_node_label, edges) = match LineParser::resolve_newline(diagram, label, stack) {
+            Some(n) => n,
+            None => {
+                return Err(TransitionError::DoorNotFound(
+                    label.to_string(),
+                    NEWLINE_EXIT_LABEL.to_string(),
+                ))
+            }
+        };
+        let tail_node = diagram.try_get_node(&tail_node_label)?;
+        // 抽出するノード ラベルは 必ず先頭の1つだけ とする。
+        let head_node_label = match tail_node.try_get_exit_vec(NEXT_EXIT_LABEL)?.first() {
+            Some(n) => n.to_string(),
+            None => {
+                return Err(TransitionError::DoorNotFound(
+                    tail_node_label,
+                    NEXT_EXIT_LABEL.to_string(),
+                ))
+            }
+        };
+        Ok((tail_node_label, head_node_label, edges))
+    }
+
+    /// 現在ノードの出口から、キャレットの位置に一致する次の行き先を 優先する順に並べます。
+    /// `priority` の大きいノードが先。同じなら 固定長のトークンが先で、長いほど先。
+    /// 正規表現や行の残りといった 取り込むノードは、出口に後から書いたものほど先。
+    /// 述語の付いた行き先は、述語が真のときだけ候補にします。
+    ///
+    /// # Arguments
+    ///
+    /// * `t` - 述語に渡す、任意のオブジェクト。
+    /// * `current_label` - 現在ノードのラベル。
+    /// * `door_label` - 現在ノードの出口。
+    /// * `stack` - 呼び出し元の積み重ね。
+    /// * `req` - 読み取るコマンドラインと、読取位置。
+    /// * `observer` - 候補と、試した一致を知らせる先。
+    /// * returns - ノードや出口が無ければ Err 。
+    pub fn rank_candidates<T>(
+        diagram: &Diagram<T>,
+        t: &T,
+        current_label: &str,
+        door_label: &str,
+        stack: &[CallFrame],
+        req: &dyn Request,
+        observer: &mut dyn ParseObserver,
+    ) -> Result<Vec<Candidate>, TransitionError> {
+        // 現在ノードの出口ドア一覧。
+        let current_exit_vec = LineParser::collect_exits(diagram, current_label, door_label, stack)?;
+
+        observer.on_candidates(
+            current_label,
+            door_label,
+            &current_exit_vec
+                .iter()
+                .map(|exit| exit.label.to_string())
+                .collect::<Vec<String>>(),
+        );
 
-        // 続きにスペース「 」が１つあれば読み飛ばす
-        if 0 < (req.get_line_len() - res_caret)
-            && &req.get_line()[res_caret..(res_caret + 1)] == " "
-        {
-            res.set_caret(res_caret + 1);
+        // (候補, 優先度, 固定長なら真, トークンの長さ, 出口での順番)
+        let mut ranked = Vec::new();
+        for (index, exit) in current_exit_vec.into_iter().enumerate() {
+            if diagram.contains_node(&exit.label) {
+                let node = diagram.get_node(&exit.label);
+                let passes = exit.guards.iter().all(|guard| {
+                    // 登録されていない述語は、書き間違いかもしれないので知らせてから 偽とする。
+                    let passes = match diagram.try_passes_guard(guard, t, req) {
+                        Ok(passes) => passes,
+                        Err(err) => {
+                            observer.on_error(&err);
+                            false
+                        }
+                    };
+                    observer.on_guard(&exit.label, guard, passes);
+                    passes
+                });
+                if !passes {
+                    continue;
+                }
+                let node_match = LineParser::match_node(diagram, node, req);
+                observer.on_attempt(node, req.get_caret(), node_match.as_ref().map(|n| n.end));
+                if let Some(node_match) = node_match {
+                    let is_literal =
+                        node.get_kind() == NodeKind::Token || node.get_kind() == NodeKind::OneOf;
+                    let token_len = node_match.end - req.get_caret();
+                    ranked.push((
+                        Candidate {
+                            label: exit.label,
+                            node_match,
+                            stack: exit.stack,
+                            edges: exit.edges,
+                        },
+                        node.get_priority(),
+                        is_literal,
+                        token_len,
+                        index,
+                    ));
+                }
+            }
         }
+
+        ranked.sort_by(|a, b| {
+            b.1.cmp(&a.1).then(b.2.cmp(&a.2)).then(if a.2 {
+                // 一番長い、固定長トークンを優先。同じ長さなら前に書いた方。
+                b.3.cmp(&a.3).then(a.4.cmp(&b.4))
+            } else {
+                // 正規表現などは、最後に一致したもの。
+                b.4.cmp(&a.4)
+            })
+        });
+        Ok(ranked.into_iter().map(|r| r.0).collect())
     }
 
-    /// TODO キャレットを進める。正規表現はどこまで一致したのか分かりにくい。
-    fn parse_reg(req: &dyn Request, res: &mut dyn Response) {
-        // グループ[0]の文字数で取る。
-        let pseud_token_len = req.get_groups()[0].chars().count();
-        res.set_caret(req.get_caret() + pseud_token_len);
+    /// 行末まで辿り着ける道順を、深さ優先で探します。コールバック関数は呼びません。
+    /// 途中のノードは `#next` の出口に進むものとし、行末のノードには `#newline` が要ります。
+    ///
+    /// # Arguments
+    ///
+    /// * `t` - 述語に渡す、任意のオブジェクト。
+    /// * `current_label` - 現在ノードのラベル。
+    /// * `door_label` - 現在ノードの出口。
+    /// * `stack` - 呼び出し元の積み重ね。
+    /// * `req` - 読み取るコマンドラインと、読取位置。
+    /// * `observer` - 探す途中で試した一致を知らせる先。
+    /// * returns - 見つからなければ None 。
+    pub fn find_path<T>(
+        diagram: &Diagram<T>,
+        t: &T,
+        current_label: &str,
+        door_label: &str,
+        stack: &[CallFrame],
+        req: &dyn Request,
+        observer: &mut dyn ParseObserver,
+    ) -> Option<Vec<Candidate>> {
+        let mut probe = RequestStruct::new(req.get_line().to_string());
+        probe.caret = req.get_caret();
+        // 行き詰まると分かった (ノード, キャレット, 呼び出し元) 。
+        let mut failed = HashSet::new();
+        LineParser::search_path(
+            diagram,
+            t,
+            current_label,
+            door_label,
+            stack,
+            &mut probe,
+            &mut failed,
+            observer,
+        )
+    }
+
+    #[allow(clippy::too_many_arguments)]
+    fn search_path<T>(
+        diagram: &Diagram<T>,
+        t: &T,
+        current_label: &str,
+        door_label: &str,
+        stack: &[CallFrame],
+        probe: &mut RequestStruct,
+        failed: &mut HashSet<(String, usize, Vec<CallFrame>)>,
+        observer: &mut dyn ParseObserver,
+    ) -> Option<Vec<Candidate>> {
+        let candidates =
+            LineParser::rank_candidates(diagram, t, current_label, door_label, stack, probe, observer)
+                .ok()?;
+        for candidate in candidates {
+            let node = diagram.get_node(&candidate.label);
+            let caret = diagram
+                .get_separator_of(node)
+                .skip(probe.get_line(), candidate.node_match.end);
+            let state = (candidate.label.to_string(), caret, candidate.stack.clone());
+            if failed.contains(&state) {
+                continue;
+            }
 
-        // 続きにスペース「 」が１つあれば読み飛ばす
-        let res_caret;
-        if let Some(res) = res.as_any().downcast_ref::<ResponseStruct>() {
-            res_caret = res.caret;
+            let rest_path = if probe.get_line_len() <= caret {
+                if LineParser::resolve_newline(diagram, &candidate.label, &candidate.stack).is_some() {
+                    Some(Vec::new())
+                } else {
+                    None
+                }
+            } else {
+                let saved_caret = probe.caret;
+                probe.caret = caret;
+                let rest_path = LineParser::search_path(
+                    diagram,
+                    t,
+                    &candidate.label,
+                    NEXT_EXIT_LABEL,
+                    &candidate.stack,
+                    probe,
+                    failed,
+                    observer,
+                );
+                probe.caret = saved_caret;
+                rest_path
+            };
+
+            match rest_path {
+                Some(mut path) => {
+                    path.insert(0, candidate);
+                    return Some(path);
+                }
+                None => {
+                    failed.insert(state);
+                }
+            }
+        }
+        None
+    }
+
+    /// 行を先読みして、最後のトークンが `terminator` を持つノードに一致するなら、その終わりの行を返します。
+    /// 候補は `rank_candidates` の一番目を選んで進み、コールバック関数は呼びません。
+    ///
+    /// # Arguments
+    ///
+    /// * `t` - 述語に渡す、任意のオブジェクト。
+    /// * `current_label` - 行頭の現在ノードのラベル。遷移図の外なら入り口から。
+    /// * `stack` - 行頭の呼び出し元の積み重ね。
+    /// * `line` - 先読みする行。
+    /// * returns - 終わりの行と、つなぐときに間に挟む文字列。
+    pub fn find_terminator<T>(
+        diagram: &Diagram<T>,
+        t: &T,
+        current_label: &str,
+        stack: &[CallFrame],
+        line: &str,
+    ) -> Option<(String, String)> {
+        // 終わりの行を持つノードが無ければ、先読みするまでもない。
+        if !diagram.has_terminator() {
+            return None;
+        }
+        let mut label = if current_label.is_empty() {
+            diagram.get_entry_point()
         } else {
-            panic!("Downcast fail.");
+            current_label.to_string()
+        };
+        if !diagram.contains_node(&label) {
+            return None;
         }
-        if 0 < (req.get_line_len() - res_caret)
-            && &req.get_line()[res_caret..(res_caret + 1)] == " "
-        {
-            res.set_caret(res_caret + 1);
+
+        let mut stack = stack.to_vec();
+        let mut probe = RequestStruct::new(line.to_string());
+        while probe.caret < probe.line_len {
+            let candidate =
+                LineParser::rank_candidates(
+                    diagram,
+                    t,
+                    &label,
+                    NEXT_EXIT_LABEL,
+                    &stack,
+                    &probe,
+                    &mut NullObserver {},
+                )
+                .ok()?
+                .into_iter()
+                    .next()?;
+            let node = diagram.get_node(&candidate.label);
+            probe.caret = diagram
+                .get_separator_of(node)
+                .skip(line, candidate.node_match.end);
+            label = candidate.label;
+            stack = candidate.stack;
         }
+
+        let node = diagram.get_node(&label);
+        node.get_terminator().as_ref().map(|terminator| {
+            (
+                terminator.to_string(),
+                diagram.get_separator_of(node).get_joiner(),
+            )
+        })
     }
 
-    /// [token]文字列の長さだけ [starts]キャレットを進めます。
-    /// [token]文字列の続きに半角スペース「 」が１つあれば、1つ分だけ読み進めます。
+    /// 1行を空読みして、どのノードに どう一致するかを返します。
+    /// 述語の他は コールバック関数も `on_enter` なども呼ばず、プレイヤーも書き換えないので、
+    /// 行を実行する前に 最後まで読めるか確かめるのに使えます。
+    /// コールバック関数は出口を変えず、 `#next` に進むものとして読みます。
+    ///
+    /// # Arguments
+    ///
+    /// * `t` - 述語に渡す、任意のオブジェクト。
+    /// * `current_label` - 行頭の現在ノードのラベル。遷移図の外なら入り口から。
+    /// * `stack` - 行頭の呼び出し元の積み重ね。
+    /// * `line` - 空読みする行。
+    pub fn dry_run<T>(
+        diagram: &Diagram<T>,
+        t: &T,
+        current_label: &str,
+        stack: &[CallFrame],
+        line: &str,
+    ) -> DryRun {
+        let mut label = if current_label.is_empty() {
+            diagram.get_entry_point()
+        } else {
+            current_label.to_string()
+        };
+        let mut stack = stack.to_vec();
+        let mut probe = RequestStruct::new(line.to_string());
+        let mut tokens = Vec::new();
+        while probe.caret < probe.line_len {
+            // 候補を探す前に、出口があるか確かめる。
+            if let Err(err) = LineParser::collect_exits(diagram, &label, NEXT_EXIT_LABEL, &stack) {
+                return DryRun {
+                    tokens,
+                    end: DryRunEnd::Error(err),
+                };
+            }
+            let chosen = if diagram.is_backtrack() {
+                LineParser::find_path(
+                    diagram,
+                    t,
+                    &label,
+                    NEXT_EXIT_LABEL,
+                    &stack,
+                    &probe,
+                    &mut NullObserver {},
+                )
+                .and_then(|path| path.into_iter().next())
+            } else {
+                LineParser::rank_candidates(
+                    diagram,
+                    t,
+                    &label,
+                    NEXT_EXIT_LABEL,
+                    &stack,
+                    &probe,
+                    &mut NullObserver {},
+                )
+                .ok()
+                .and_then(|candidates| candidates.into_iter().next())
+            };
+            let candidate = match chosen {
+                Some(n) => n,
+                None => {
+                    return DryRun {
+                        tokens,
+                        end: DryRunEnd::Else(probe.caret),
+                    }
+                }
+            };
+
+            let node = diagram.get_node(&candidate.label);
+            let start = probe.caret;
+            let end = candidate.node_match.end;
+            probe.caret = diagram.get_separator_of(node).skip(line, end);
+            tokens.push(ParsedToken {
+                label: candidate.label.to_string(),
+                start,
+                end,
+                matched: line[start..end].to_string(),
+                groups: candidate.node_match.groups,
+                value: candidate.node_match.value,
+            });
+            label = candidate.label;
+            stack = candidate.stack;
+        }
+
+        let end = match LineParser::resolve_line_end(diagram, &label, &stack) {
+            Ok((tail_node_label, _, _)) => DryRunEnd::Newline(tail_node_label),
+            Err(err) => DryRunEnd::Error(err),
+        };
+        DryRun { tokens, end }
+    }
+
+    /// ノードが、キャレットの位置のトークンに一致するか調べます。
+    /// リクエストもプレイヤーも書き換えないので、候補を比べるのに何度呼んでもかまいません。
     ///
     /// # Arguments
     ///
     /// * `req` - 読み取るコマンドラインと、読取位置。
-    /// * returns - 一致したら真。
-    pub fn starts_with_literal(node: &Node, req: &dyn Request) -> bool {
-        let caret_end = req.get_caret() + node.get_token().len();
-        caret_end <= req.get_line_len()
-            && &req.get_line()[req.get_caret()..caret_end] == node.get_token()
+    /// * returns - 一致しなければ None 。トークンを持たないノードは一致しない。
+    pub fn match_node<T>(diagram: &Diagram<T>, node: &Node, req: &dyn Request) -> Option<NodeMatch> {
+        match node.get_repeat() {
+            Some(repeat) => LineParser::match_repeat(diagram, node, repeat, req),
+            None => LineParser::match_item(diagram, node, req),
+        }
+    }
+
+    /// 繰り返すトークンを、区切りを挟みながら できるだけ多く読みます。
+    /// 後ろのノードに譲ることはしないので、後ろに続くトークンと区別できるトークンにしてください。
+    ///
+    /// # Arguments
+    ///
+    /// * `req` - 読み取るコマンドラインと、読取位置。
+    /// * returns - `repeat.min` 回 以上 一致すれば、1回ごとの中身を groups に並べて返す。
+    ///   正規表現などで取り込んだものは groups[0] 、固定長のトークンは原文。
+    pub fn match_repeat<T>(
+        diagram: &Diagram<T>,
+        node: &Node,
+        repeat: Repeat,
+        req: &dyn Request,
+    ) -> Option<NodeMatch> {
+        let separator = diagram.get_separator_of(node);
+        let mut probe = RequestStruct::new(req.get_line().to_string());
+        probe.caret = req.get_caret();
+
+        let mut end = req.get_caret();
+        let mut groups = Vec::new();
+        while probe.caret < probe.line_len && groups.len() < repeat.max.unwrap_or(usize::MAX) {
+            let item = match LineParser::match_item(diagram, node, &probe) {
+                Some(n) => n,
+                None => break,
+            };
+            if item.end == probe.caret {
+                // 何も読まずに一致した。
+                break;
+            }
+            groups.push(match item.groups.into_iter().next() {
+                Some(group) => group,
+                None => probe.line[probe.caret..item.end].to_string(),
+            });
+            end = item.end;
+            probe.caret = separator.skip(&probe.line, end);
+        }
+
+        if groups.is_empty() || groups.len() < repeat.min {
+            return None;
+        }
+        Some(NodeMatch {
+            end,
+            groups,
+            value: None,
+        })
+    }
+
+    /// 繰り返しを考えずに、ノードが キャレットの位置のトークン1つに一致するか調べます。
+    fn match_item<T>(diagram: &Diagram<T>, node: &Node, req: &dyn Request) -> Option<NodeMatch> {
+        match node.get_kind() {
+            NodeKind::Token => {
+                let token_len = LineParser::match_literal(diagram, node, req)?;
+                Some(NodeMatch {
+                    end: req.get_caret() + token_len,
+                    groups: Vec::new(),
+                    value: None,
+                })
+            }
+            NodeKind::Regex => LineParser::match_reg(node, req),
+            NodeKind::Rest => Some(LineParser::match_rest(diagram, node, req)),
+            NodeKind::Quoted => LineParser::match_quoted(diagram, node, req),
+            NodeKind::Value(value_type) => LineParser::match_value(diagram, node, value_type, req),
+            NodeKind::OneOf => LineParser::match_one_of(diagram, node, req),
+            NodeKind::Single | NodeKind::Else => None,
+        }
     }
 
-    /// 正規表現を使う。
+    /// キャレットの位置から [token]文字列が始まっているか調べます。
+    /// 前方一致のノードでなければ、[token]文字列の後ろが 区切り か 行末 のときだけ一致とします。
+    /// `go` が `gomi` に一致しないようにするためです。
     ///
     /// # Arguments
     ///
-    /// * `req` - 読み取るコマンドライン。一致があると groups メンバーに入れる。
+    /// * `req` - 読み取るコマンドラインと、読取位置。
     /// * returns - 一致したら真。
-    pub fn starts_with_reg(node: &Node, req: &mut dyn Request) -> bool {
-        if VERBOSE {
-            println!("Starts_with_re");
+    pub fn starts_with_literal<T>(diagram: &Diagram<T>, node: &Node, req: &dyn Request) -> bool {
+        LineParser::match_literal(diagram, node, req).is_some()
+    }
+
+    /// [token]文字列と一致した、原文のバイト数を返します。
+    /// 大文字と小文字の区別や、 NFKC 正規化は ノードとダイアグラムの設定に従います。
+    ///
+    /// # Arguments
+    ///
+    /// * `req` - 読み取るコマンドラインと、読取位置。
+    /// * returns - 一致しなければ None 。
+    pub fn match_literal<T>(diagram: &Diagram<T>, node: &Node, req: &dyn Request) -> Option<usize> {
+        LineParser::match_literal_text(diagram, node, node.get_token(), req)
+    }
+
+    /// ノードの設定に従って、 `token` と一致した原文のバイト数を返します。
+    fn match_literal_text<T>(
+        diagram: &Diagram<T>,
+        node: &Node,
+        token: &str,
+        req: &dyn Request,
+    ) -> Option<usize> {
+        if req.get_line_len() < req.get_caret() {
+            return None;
         }
+        let rest = &req.get_line()[req.get_caret()..];
 
-        if req.get_caret() < req.get_line_len() {
-            if VERBOSE {
-                println!("node.token: {}", node.get_token());
+        let ignore_case = diagram.is_ignore_case_of(node);
+        let nfkc = diagram.is_nfkc_of(node);
+        let token_len = if !ignore_case && !nfkc {
+            if rest.starts_with(token) {
+                token.len()
+            } else {
+                return None;
             }
+        } else {
+            LineParser::match_folded(token, rest, ignore_case, nfkc)?
+        };
 
-            let re = Regex::new(&node.get_token()).unwrap();
+        if LineParser::is_token_end(diagram, node, &rest[token_len..]) {
+            Some(token_len)
+        } else {
+            None
+        }
+    }
 
-            let text;
-            let mut group_num = 0;
-            if let Some(req) = req.as_mut_any().downcast_mut::<RequestStruct>() {
-                text = &req.line[req.caret..];
+    /// トークンの後ろが、トークンの切れ目になっているか。
+    ///
+    /// # Arguments
+    ///
+    /// * `following` - トークンの後ろに続く文字列。
+    fn is_token_end<T>(diagram: &Diagram<T>, node: &Node, following: &str) -> bool {
+        let separator = diagram.get_separator_of(node);
+        if node.is_prefix() || *separator == Separator::Nothing {
+            // 区切りが無いなら、トークンの切れ目は分からない。
+            return true;
+        }
+        match following.chars().next() {
+            None | Some('\r') | Some('\n') => true,
+            Some(ch) => separator.is_separator(ch),
+        }
+    }
+
+    /// 原文の先頭から１文字ずつ伸ばしながら まとめて畳み込み、畳み込んだトークンと比べる。
+    /// 半角カナと濁点のように 続く文字と合わさって１文字になることがあるので、１文字ずつ畳み込んではいけない。
+    /// 全角の１文字が半角の２文字になることもあるので、一致した原文のバイト数を返す。
+    fn match_folded(token: &str, rest: &str, ignore_case: bool, nfkc: bool) -> Option<usize> {
+        let token = fold_text(token, ignore_case, nfkc);
+        if token.is_empty() {
+            return Some(0);
+        }
+        let token_count = token.chars().count();
 
-                if VERBOSE {
-                    println!("text: [{}]", text);
+        let mut ends = rest
+            .char_indices()
+            .map(|(i, ch)| i + ch.len_utf8())
+            .peekable();
+        while let Some(end) = ends.next() {
+            let folded = fold_text(&rest[..end], ignore_case, nfkc);
+            if folded == token {
+                // 続く文字と合わさって変わるなら、ここでは切れない。
+                match ends.peek() {
+                    Some(&next_end)
+                        if !fold_text(&rest[..next_end], ignore_case, nfkc).starts_with(&token) => {}
+                    _ => return Some(end),
                 }
+            }
+            // 畳み込んだ文字数は、原文を伸ばしても減らない。
+            if token_count < folded.chars().count() {
+                return None;
+            }
+        }
+        None
+    }
 
-                for caps in re.captures_iter(text) {
-                    // caps は サイズ 2 の配列 で同じものが入っている。
-                    let cap = &caps[0];
+    /// 正規表現を使う。キャレットの位置から始まる一致だけを見ます。
+    ///
+    /// # Arguments
+    ///
+    /// * `req` - 読み取るコマンドライン。
+    /// * returns - 一致があれば、一致した文字列を groups[0] に入れて返す。
+    pub fn match_reg(node: &Node, req: &dyn Request) -> Option<NodeMatch> {
+        if req.get_line_len() <= req.get_caret() {
+            return None;
+        }
+
+        let re = node.get_regex()?;
+        let text = &req.get_line()[req.get_caret()..];
 
-                    req.groups.push(cap.to_string());
+        match re.find(text) {
+            // 行の途中で一致しても、キャレットの位置のトークンではない。
+            Some(m) if m.start() == 0 && 0 < m.end() => Some(NodeMatch {
+                end: req.get_caret() + m.end(),
+                groups: vec![m.as_str().to_string()],
+                value: None,
+            }),
+            _ => None,
+        }
+    }
 
-                    group_num += 1;
+    /// 行の残り全部を取ります。先頭の区切りと、末尾の改行は取り除きます。
+    pub fn match_rest<T>(diagram: &Diagram<T>, node: &Node, req: &dyn Request) -> NodeMatch {
+        let separator = diagram.get_separator_of(node);
+        let rest = &req.get_line()[req.get_caret()..];
+        let text = rest
+            .trim_start_matches(|ch| separator.is_separator(ch))
+            .trim_end_matches(['\r', '\n']);
+        NodeMatch {
+            end: req.get_line_len(),
+            groups: vec![text.to_string()],
+            value: None,
+        }
+    }
+
+    /// 並べたトークンのどれかに一致するか調べます。
+    /// 複数に一致するなら、原文の長い方を選びます。
+    ///
+    /// # Arguments
+    ///
+    /// * `req` - 読み取るコマンドラインと、読取位置。
+    /// * returns - 一致すれば、 diagram.json に書いた方のトークンを groups[0] に入れて返す。
+    pub fn match_one_of<T>(diagram: &Diagram<T>, node: &Node, req: &dyn Request) -> Option<NodeMatch> {
+        let mut best: Option<(usize, &String)> = None;
+        for choice in node.get_choices() {
+            if let Some(token_len) = LineParser::match_literal_text(diagram, node, choice, req) {
+                let longer = match best {
+                    Some((best_len, _)) => best_len < token_len,
+                    None => true,
+                };
+                if longer {
+                    best = Some((token_len, choice));
                 }
+            }
+        }
+        let (token_len, choice) = best?;
+        Some(NodeMatch {
+            end: req.get_caret() + token_len,
+            groups: vec![choice.to_string()],
+            value: None,
+        })
+    }
+
+    /// 数や真偽値に変換できるトークンを取ります。
+    /// 変換できないときや、ノードの範囲を外れたときは一致しません。
+    ///
+    /// # Arguments
+    ///
+    /// * `req` - 読み取るコマンドラインと、読取位置。
+    /// * returns - 一致すれば、原文を groups[0] に、変換した値を value に入れて返す。
+    pub fn match_value<T>(
+        diagram: &Diagram<T>,
+        node: &Node,
+        value_type: ValueType,
+        req: &dyn Request,
+    ) -> Option<NodeMatch> {
+        if req.get_line_len() <= req.get_caret() {
+            return None;
+        }
+        let rest = &req.get_line()[req.get_caret()..];
 
-                if VERBOSE {
-                    println!("Group num: {}", group_num);
+        let token_len = rest
+            .find(|ch| !value_type.is_value_char(ch))
+            .unwrap_or(rest.len());
+        if token_len == 0 || !LineParser::is_token_end(diagram, node, &rest[token_len..]) {
+            return None;
+        }
+        let text = &rest[..token_len];
+        let value = value_type.parse(text, diagram.is_ignore_case_of(node))?;
+
+        if let Some(n) = value.as_f64() {
+            if node.get_min().is_some_and(|min| n < min)
+                || node.get_max().is_some_and(|max| max < n)
+            {
+                return None;
+            }
+        }
+
+        Some(NodeMatch {
+            end: req.get_caret() + token_len,
+            groups: vec![text.to_string()],
+            value: Some(value),
+        })
+    }
+
+    /// 引用符で囲んだ文字列を取ります。閉じていなければ一致しません。
+    /// `\\` `\"` `\'` `\n` `\t` `\r` をエスケープとして解きます。
+    /// それ以外のバックスラッシュは、 `C:\kifu` のようなパスのために そのまま残します。
+    ///
+    /// # Arguments
+    ///
+    /// * `req` - 読み取るコマンドラインと、読取位置。
+    /// * returns - 一致すれば、エスケープを解いた中身を groups[0] に入れて返す。
+    pub fn match_quoted<T>(diagram: &Diagram<T>, node: &Node, req: &dyn Request) -> Option<NodeMatch> {
+        if req.get_line_len() <= req.get_caret() {
+            return None;
+        }
+        let rest = &req.get_line()[req.get_caret()..];
+
+        let mut chars = rest.char_indices();
+        let quote = match chars.next() {
+            Some((_, ch)) if ch == '"' || ch == '\'' => ch,
+            _ => return None,
+        };
+
+        let mut value = String::new();
+        while let Some((i, ch)) = chars.next() {
+            if ch == quote {
+                let token_len = i + ch.len_utf8();
+                if !LineParser::is_token_end(diagram, node, &rest[token_len..]) {
+                    return None;
+                }
+                return Some(NodeMatch {
+                    end: req.get_caret() + token_len,
+                    groups: vec![value],
+                    value: None,
+                });
+            } else if ch == '\\' {
+                match chars.next() {
+                    Some((_, '\\')) => value.push('\\'),
+                    Some((_, '"')) => value.push('"'),
+                    Some((_, '\'')) => value.push('\''),
+                    Some((_, 'n')) => value.push('\n'),
+                    Some((_, 't')) => value.push('\t'),
+                    Some((_, 'r')) => value.push('\r'),
+                    Some((_, escaped)) => {
+                        value.push('\\');
+                        value.push(escaped);
+                    }
+                    None => return None,
                 }
             } else {
-                panic!("Downcast fail.");
+                value.push(ch);
             }
+        }
+        // 引用符が閉じていない。
+        None
+    }
+}
 
-            0 < group_num
-        } else {
-            false
+/// 比べる前に、大文字を小文字にしたり、全角を半角にしたりする。
+fn fold_text(text: &str, ignore_case: bool, nfkc: bool) -> String {
+    let text = if nfkc {
+        text.nfkc().collect::<String>()
+    } else {
+        text.to_string()
+    };
+    if ignore_case {
+        text.to_ascii_lowercase()
+    } else {
+        text
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::HashMap;
+    use test_support::*;
+
+    #[test]
+    fn diagram_separator_skips_runs_of_whitespace() {
+        let mut diagram = new_diagram();
+        diagram.set_separator(Separator::Whitespace);
+        diagram.node("HEAD").next(["TK.go"]);
+        diagram.node("TK.go").token("go").fn_("record").next(["TK.now"]);
+        diagram.node("TK.now").token("now").fn_("record").newline("TAIL");
+
+        assert_eq!(run_lines(&mut diagram, &["go   now"]), vec!["go", "now", "#newline"]);
+    }
+
+    #[test]
+    fn node_separator_overrides_the_diagram_separator() {
+        let mut diagram = new_diagram();
+        diagram.node("HEAD").next(["TK.a"]);
+        diagram
+            .node("TK.a")
+            .token("a")
+            .fn_("record")
+            .separator(Separator::Chars(",".to_string()))
+            .next(["TK.b"]);
+        diagram.node("TK.b").token("b").fn_("record").newline("TAIL");
+
+        assert_eq!(run_lines(&mut diagram, &["a,b"]), vec!["a", "b", "#newline"]);
+        assert_eq!(run_lines(&mut diagram, &["a b"]), vec!["#else at 0"]);
+    }
+
+    #[test]
+    fn literal_token_needs_a_boundary_unless_prefix() {
+        let mut diagram = new_diagram();
+        diagram.node("HEAD").next(["TK.go", "TK.x"]);
+        diagram.node("TK.go").token("go").fn_("record").newline("TAIL");
+        diagram.node("TK.x").token("x").fn_("record").prefix().next(["TK.go"]);
+
+        assert_eq!(run_lines(&mut diagram, &["gopher"]), vec!["#else at 0"]);
+        assert_eq!(run_lines(&mut diagram, &["go"]), vec!["go", "#newline"]);
+        assert_eq!(run_lines(&mut diagram, &["xgo"]), vec!["x", "go", "#newline"]);
+    }
+
+    #[test]
+    fn ignore_case_and_nfkc_tokens_keep_the_typed_text() {
+        let mut diagram = new_diagram();
+        diagram.node("HEAD").next(["TK.go"]);
+        diagram.node("TK.go").token("go").fn_("record").ignore_case().nfkc().newline("TAIL");
+
+        assert_eq!(run_lines(&mut diagram, &["GO"]), vec!["GO", "#newline"]);
+        assert_eq!(run_lines(&mut diagram, &["ｇｏ"]), vec!["ｇｏ", "#newline"]);
+        assert_eq!(run_lines(&mut diagram, &["Ｇｏ"]), vec!["Ｇｏ", "#newline"]);
+    }
+
+    #[test]
+    fn nfkc_normalizes_composed_half_width_kana() {
+        let mut diagram = new_diagram();
+        diagram.node("HEAD").next(["TK.ga", "TK.ka"]);
+        diagram.node("TK.ga").token("ガ").fn_("record").nfkc().newline("TAIL");
+        diagram.node("TK.ka").token("カ").fn_("record").nfkc().prefix().newline("TAIL");
+
+        assert_eq!(run_lines(&mut diagram, &["ｶﾞ"]), vec!["ｶﾞ", "#newline"]);
+        assert_eq!(run_lines(&mut diagram, &["ｶ"]), vec!["ｶ", "#newline"]);
+        assert_eq!(run_lines(&mut diagram, &["ｶﾞｶﾞ"]), vec!["#else at 0"]);
+    }
+
+    #[test]
+    fn match_folded_returns_the_typed_byte_length() {
+        assert_eq!(LineParser::match_folded("ガ", "ｶﾞ x", false, true), Some(6));
+        assert_eq!(LineParser::match_folded("カ", "ｶﾞ", false, true), None);
+        assert_eq!(LineParser::match_folded("ab", "ＡＢc", true, true), Some(6));
+        assert_eq!(LineParser::match_folded("ab", "a", true, true), None);
+    }
+
+    #[test]
+    fn rest_node_takes_the_trimmed_remainder_of_the_line() {
+        let mut diagram = new_diagram();
+        diagram.node("HEAD").next(["TK.value"]);
+        diagram.node("TK.value").token("value").fn_("record").next(["TK.rest"]);
+        diagram.node("TK.rest").rest().fn_("record_groups").newline("TAIL");
+
+        assert_eq!(
+            run_lines(&mut diagram, &["value   C:\\kifu dir\\a.kif  "]),
+            vec!["value", "[C:\\kifu dir\\a.kif  ]", "#newline"]
+        );
+    }
+
+    #[test]
+    fn quoted_node_unescapes_and_keeps_path_backslashes() {
+        let mut diagram = new_diagram();
+        diagram.node("HEAD").next(["TK.load"]);
+        diagram.node("TK.load").token("load").fn_("record").next(["TK.path"]);
+        diagram.node("TK.path").quoted().fn_("record_groups").newline("TAIL");
+
+        assert_eq!(
+            run_lines(&mut diagram, &[r#"load "C:\kifu dir\game 1.kif""#]),
+            vec!["load", r"[C:\kifu dir\game 1.kif]", "#newline"]
+        );
+        assert_eq!(
+            run_lines(&mut diagram, &[r#"load 'say \"hi\"\\'"#]),
+            vec!["load", r#"[say "hi"\]"#, "#newline"]
+        );
+    }
+
+    #[test]
+    fn unclosed_quote_does_not_match() {
+        let mut diagram = new_diagram();
+        diagram.node("HEAD").next(["TK.load"]);
+        diagram.node("TK.load").token("load").fn_("record").next(["TK.path"]);
+        diagram.node("TK.path").quoted().fn_("record_groups").newline("TAIL");
+
+        assert_eq!(
+            run_lines(&mut diagram, &[r#"load "open"#]),
+            vec!["load", "#else at 5"]
+        );
+        assert_eq!(
+            run_lines(&mut diagram, &[r#"load "a"b"#]),
+            vec!["load", "#else at 5"]
+        );
+    }
+
+    #[test]
+    fn value_node_converts_and_checks_the_range() {
+        let mut diagram = new_diagram();
+        diagram.node("HEAD").next(["TK.depth"]);
+        diagram.node("TK.depth").token("depth").fn_("record").next(["TK.n"]);
+        diagram
+            .node("TK.n")
+            .value(ValueType::Uint)
+            .range(Some(1), Some(64))
+            .fn_("record_value")
+            .newline("TAIL");
+
+        assert_eq!(
+            run_lines(&mut diagram, &["depth 64"]),
+            vec!["depth", "Some(Uint(64))", "#newline"]
+        );
+        assert_eq!(run_lines(&mut diagram, &["depth 0"]), vec!["depth", "#else at 6"]);
+        assert_eq!(run_lines(&mut diagram, &["depth 65"]), vec!["depth", "#else at 6"]);
+        assert_eq!(
+            run_lines(&mut diagram, &["depth 99999999999999999999"]),
+            vec!["depth", "#else at 6"]
+        );
+        assert_eq!(run_lines(&mut diagram, &["depth 12x"]), vec!["depth", "#else at 6"]);
+    }
+
+    #[test]
+    fn value_nodes_of_each_type() {
+        let mut diagram = new_diagram();
+        // 正規表現などと同じく、両方に一致すれば後に書いた方。
+        diagram.node("HEAD").next(["TK.bool", "TK.float", "TK.int"]);
+        diagram.node("TK.int").value(ValueType::Int).fn_("record_value").newline("TAIL");
+        diagram.node("TK.float").value(ValueType::Float).fn_("record_value").newline("TAIL");
+        diagram.node("TK.bool").value(ValueType::Bool).fn_("record_value").newline("TAIL");
+
+        assert_eq!(run_lines(&mut diagram, &["-3"]), vec!["Some(Int(-3))", "#newline"]);
+        assert_eq!(run_lines(&mut diagram, &["0.5"]), vec!["Some(Float(0.5))", "#newline"]);
+        assert_eq!(run_lines(&mut diagram, &["true"]), vec!["Some(Bool(true))", "#newline"]);
+        assert_eq!(run_lines(&mut diagram, &["maybe"]), vec!["#else at 0"]);
+    }
+
+    #[test]
+    fn one_of_prefers_the_longest_choice_and_reports_it() {
+        let mut diagram = new_diagram();
+        diagram.node("HEAD").next(["TK.go"]);
+        diagram.node("TK.go").token("go").fn_("record").next(["TK.mode"]);
+        diagram
+            .node("TK.mode")
+            .one_of(["mate", "ponder", "mat"])
+            .fn_("record_groups")
+            .newline("TAIL");
+
+        assert_eq!(
+            run_lines(&mut diagram, &["go mate", "go mat", "go ponder"]),
+            vec!["go", "[mate]", "#newline", "go", "[mat]", "#newline", "go", "[ponder]", "#newline"]
+        );
+        assert_eq!(run_lines(&mut diagram, &["go infinite"]), vec!["go", "#else at 3"]);
+    }
+
+    #[test]
+    fn one_of_reports_the_written_choice_when_ignoring_case() {
+        let mut diagram = new_diagram();
+        diagram.node("HEAD").next(["TK.result"]);
+        diagram
+            .node("TK.result")
+            .one_of(["win", "lose", "draw"])
+            .ignore_case()
+            .fn_("record_groups")
+            .newline("TAIL");
+
+        assert_eq!(run_lines(&mut diagram, &["DRAW"]), vec!["[draw]", "#newline"]);
+        assert_eq!(run_lines(&mut diagram, &["winner"]), vec!["#else at 0"]);
+    }
+
+    #[test]
+    fn priority_beats_the_longest_literal_and_exit_order() {
+        let mut diagram = new_diagram();
+        diagram.node("HEAD").next(["TK.long", "RE.num", "RE.word"]);
+        diagram.node("TK.long").token("123").fn_("record").newline("TAIL");
+        diagram.node("RE.num").regex(r"\d+").fn_("record_groups").newline("TAIL");
+        diagram.node("RE.word").regex(r"\w+").fn_("record").newline("TAIL");
+
+        // 既定では 固定長トークンが勝ち、正規表現どうしなら後に書いた方。
+        assert_eq!(run_lines(&mut diagram, &["123"]), vec!["123", "#newline"]);
+        assert_eq!(run_lines(&mut diagram, &["45"]), vec!["45", "#newline"]);
+
+        diagram.get_node_mut("RE.num").set_priority(1);
+        assert_eq!(run_lines(&mut diagram, &["123"]), vec!["[123]", "#newline"]);
+        assert_eq!(run_lines(&mut diagram, &["45"]), vec!["[45]", "#newline"]);
+    }
+
+    #[test]
+    fn backtracking_runs_controllers_only_on_the_accepted_path() {
+        let mut diagram = new_diagram();
+        diagram.node("HEAD").next(["TK.go", "RE.go"]);
+        // 長い固定長トークンが勝つが、その先が行き止まり。
+        diagram.node("TK.go").token("go").fn_("record").next(["TK.now"]);
+        diagram.node("TK.now").token("now").fn_("record").newline("TAIL");
+        diagram.node("RE.go").regex(r"go").fn_("record_groups").next(["TK.later"]);
+        diagram.node("TK.later").token("later").fn_("record").newline("TAIL");
+
+        assert_eq!(run_lines(&mut diagram, &["go later"]), vec!["go", "#else at 3"]);
+
+        diagram.set_backtrack(true);
+        assert_eq!(
+            run_lines(&mut diagram, &["go later"]),
+            vec!["[go]", "later", "#newline"]
+        );
+        assert_eq!(run_lines(&mut diagram, &["go now"]), vec!["go", "now", "#newline"]);
+    }
+
+    #[test]
+    fn regex_is_compiled_once_when_the_token_is_set() {
+        let mut diagram = new_diagram();
+        diagram.node("RE.num").regex(r"\d+");
+        assert!(diagram.get_node("RE.num").get_regex().is_some());
+
+        diagram
+            .get_node_mut("RE.num")
+            .set_token("num".to_string(), NodeKind::Token);
+        assert!(diagram.get_node("RE.num").get_regex().is_none());
+    }
+
+    #[test]
+    #[should_panic(expected = "Regex error. (RE.bad node)")]
+    fn bad_regex_is_rejected_when_loaded() {
+        let mut diagram: Diagram<Vec<String>> = Diagram::new();
+        diagram.insert_node_reg("RE.bad", "(".to_string(), "".to_string(), HashMap::new());
+    }
+
+    #[test]
+    fn repeat_collects_every_item_for_one_controller_call() {
+        let mut diagram = new_diagram();
+        diagram.node("HEAD").next(["TK.moves"]);
+        diagram.node("TK.moves").token("moves").fn_("record").next(["RE.move"]);
+        diagram
+            .node("RE.move")
+            .regex(r"[0-9a-z+*]+")
+            .repeat(1, Some(3))
+            .fn_("record_groups")
+            .next(["TK.end"])
+            .newline("TAIL");
+        diagram.node("TK.end").token("END").fn_("record").newline("TAIL");
+
+        assert_eq!(
+            run_lines(&mut diagram, &["moves 7g7f 3c3d"]),
+            vec!["moves", "[7g7f|3c3d]", "#newline"]
+        );
+        // 最大回数で止まって、残りは次のノードで読む。
+        assert_eq!(
+            run_lines(&mut diagram, &["moves 1a 2b 3c END"]),
+            vec!["moves", "[1a|2b|3c]", "END", "#newline"]
+        );
+        assert_eq!(
+            run_lines(&mut diagram, &["moves 1a 2b 3c 4d"]),
+            vec!["moves", "[1a|2b|3c]", "#else at 15"]
+        );
+    }
+
+    #[test]
+    fn repeat_below_min_does_not_match() {
+        let mut diagram = new_diagram();
+        diagram.node("HEAD").next(["TK.num"]);
+        diagram
+            .node("TK.num")
+            .value(ValueType::Uint)
+            .repeat(2, None)
+            .fn_("record_groups")
+            .newline("TAIL");
+
+        assert_eq!(run_lines(&mut diagram, &["1 2 3"]), vec!["[1|2|3]", "#newline"]);
+        assert_eq!(run_lines(&mut diagram, &["1"]), vec!["#else at 0"]);
+    }
+
+    /// `go` と `ponder` から、持ち時間の部品 `SUB.time` を呼ぶダイアグラム。
+    fn new_call_diagram() -> Diagram<Vec<String>> {
+        let mut diagram = new_diagram();
+        diagram.node("HEAD").next(["TK.go", "TK.ponder"]);
+        diagram
+            .node("TK.go")
+            .token("go")
+            .fn_("record")
+            .call("SUB.time")
+            .next(["TK.infinite"])
+            .newline("TAIL");
+        diagram
+            .node("TK.ponder")
+            .token("ponder")
+            .fn_("record")
+            .call("SUB.time")
+            .newline("TAIL");
+        diagram.node("TK.infinite").token("infinite").fn_("record").newline("TAIL");
+        diagram.node("SUB.time").next(["TK.btime", RETURN_LABEL]);
+        diagram.node("TK.btime").token("btime").fn_("record").next(["TK.btime.value"]);
+        diagram
+            .node("TK.btime.value")
+            .value(ValueType::Uint)
+            .fn_("record_value")
+            .next([RETURN_LABEL])
+            .newline(RETURN_LABEL);
+        diagram
+    }
+
+    #[test]
+    fn call_returns_to_the_exits_of_each_caller() {
+        let mut diagram = new_call_diagram();
+
+        assert_eq!(
+            run_lines(&mut diagram, &["go btime 10 infinite"]),
+            vec!["go", "btime", "Some(Uint(10))", "infinite", "#newline"]
+        );
+        assert_eq!(
+            run_lines(&mut diagram, &["ponder btime 20"]),
+            vec!["ponder", "btime", "Some(Uint(20))", "#newline"]
+        );
+        // 部品の中身を飛ばして、すぐに戻ってもいい。
+        assert_eq!(run_lines(&mut diagram, &["go infinite"]), vec!["go", "infinite", "#newline"]);
+        // 呼び出し元の出口に無いトークン。
+        assert_eq!(
+            run_lines(&mut diagram, &["ponder btime 20 infinite"]),
+            vec!["ponder", "btime", "Some(Uint(20))", "#else at 16"]
+        );
+    }
+
+    #[test]
+    fn call_stack_is_empty_after_each_line() {
+        let mut diagram = new_call_diagram();
+        let mut shell = Shell::new();
+        let mut log = Vec::new();
+
+        shell.execute_line(&mut diagram, &mut log, "go btime 10");
+        assert!(shell.snapshot().get_diagram_player().get_call_stack().is_empty());
+        shell.execute_line(&mut diagram, &mut log, "ponder btime");
+        assert!(shell.snapshot().get_diagram_player().get_call_stack().is_empty());
+    }
+
+    #[test]
+    fn observer_sees_each_step_of_a_line() {
+        let mut diagram = new_diagram();
+        diagram.node("HEAD").next(["TK.go", "RE.num"]);
+        diagram.node("TK.go").token("go").fn_("record").next(["RE.num"]);
+        diagram.node("RE.num").regex(r"\d+").fn_("record").newline("TAIL");
+        let mut shell = Shell::new();
+        let events = EventLog::attach(&mut shell);
+        let mut log = Vec::new();
+
+        shell.execute_line(&mut diagram, &mut log, "go 10");
+        assert_eq!(
+            *events.borrow(),
+            vec![
+                "candidates HEAD #next TK.go,RE.num",
+                "attempt TK.go 0 Some(2)",
+                "attempt RE.num 0 None",
+                "chosen TK.go",
+                "caret 0..3",
+                "controller TK.go record",
+                "candidates TK.go #next RE.num",
+                "attempt RE.num 3 Some(5)",
+                "chosen RE.num",
+                "caret 3..5",
+                "controller RE.num record",
+                "newline RE.num TAIL",
+                "controller TAIL record_newline",
+            ]
+        );
+
+        events.borrow_mut().clear();
+        shell.execute_line(&mut diagram, &mut log, "go x");
+        let events = events.borrow();
+        assert_eq!(
+            events[events.len() - 3..].to_vec(),
+            vec!["attempt RE.num 3 None", "no match TK.go #next 3", "else go x 3"]
+        );
+        assert_eq!(log.last().unwrap(), "#else at 3");
+    }
+
+    /// `isready` を記録した後なら真。述語の型に合わせて `&Vec` で受け取る。
+    #[allow(clippy::ptr_arg)]
+    fn is_ready(log: &Vec<String>, _req: &dyn Request) -> bool {
+        log.iter().any(|entry| entry == "isready")
+    }
+
+    #[test]
+    fn guarded_exit_is_unmatched_until_the_guard_passes() {
+        let mut diagram = new_diagram();
+        diagram.insert_guard_fn("is_ready", is_ready);
+        diagram
+            .node("HEAD")
+            .next(["TK.isready"])
+            .guarded_exit(NEXT_EXIT_LABEL, "TK.go", "is_ready")
+            .guarded_exit(NEXT_EXIT_LABEL, "TK.debug", "is_debug");
+        diagram.node("TK.isready").token("isready").fn_("record").newline("TAIL");
+        diagram.node("TK.go").token("go").fn_("record").newline("TAIL");
+        diagram.node("TK.debug").token("debug").fn_("record").newline("TAIL");
+
+        assert_eq!(run_lines(&mut diagram, &["go"]), vec!["#else at 0"]);
+        assert_eq!(
+            run_lines(&mut diagram, &["isready", "go"]),
+            vec!["isready", "#newline", "go", "#newline"]
+        );
+        // 登録されていない述語は いつも偽。
+        assert_eq!(run_lines(&mut diagram, &["debug"]), vec!["#else at 0"]);
+    }
+
+    #[test]
+    fn guards_are_read_from_json() {
+        let mut diagram = new_diagram();
+        diagram.insert_guard_fn("is_ready", is_ready);
+        diagram
+            .read_str(
+                r##"{
+                    "entry_point": "HEAD",
+                    "nodes": [
+                        { "label": "HEAD", "exit": { "#next": [ "TK.isready", { "label": "TK.go", "guard": "is_ready" } ] } },
+                        { "label": "TK.isready", "token": "isready", "fn": "record", "exit": { "#newline": [ "TAIL" ] } },
+                        { "label": "TK.go", "token": "go", "fn": "record", "exit": { "#newline": [ "TAIL" ] } },
+                        { "label": "TAIL", "fn": "record_newline", "exit": { "#next": [ "HEAD" ] } },
+                        { "label": "#else", "fn": "record_else" }
+                    ]
+                }"##,
+            )
+            .unwrap();
+
+        assert_eq!(diagram.get_node("HEAD").get_guard(NEXT_EXIT_LABEL, "TK.go"), Some("is_ready"));
+        assert_eq!(run_lines(&mut diagram, &["go"]), vec!["#else at 0"]);
+        assert_eq!(
+            run_lines(&mut diagram, &["isready", "go"]),
+            vec!["isready", "#newline", "go", "#newline"]
+        );
+    }
+
+    #[test]
+    fn unregistered_guard_is_reported_to_the_observer() {
+        let mut diagram = new_diagram();
+        diagram
+            .node("HEAD")
+            .guarded_exit(NEXT_EXIT_LABEL, "TK.debug", "is_debug");
+        diagram.node("TK.debug").token("debug").fn_("record").newline("TAIL");
+        assert_eq!(diagram.find_missing_guards(), vec!["is_debug".to_string()]);
+        let mut shell = Shell::new();
+        let events = EventLog::attach(&mut shell);
+        let mut log = Vec::new();
+
+        shell.execute_line(&mut diagram, &mut log, "debug");
+        assert!(events
+            .borrow()
+            .contains(&"error \"is_debug\" guard is not found.".to_string()));
+        assert_eq!(log, vec!["#else at 0"]);
+    }
+
+    /// 取り込んだ文字列に `#` を付けた出口に進む。
+    fn forward_to_group(_log: &mut Vec<String>, req: &dyn Request, res: &mut dyn Response) {
+        res.forward(&format!("#{}", req.get_groups()[0]));
+    }
+
+    /// 取り込んだ文字列のラベルのノードに跳ぶ。
+    fn jump_to_group(_log: &mut Vec<String>, req: &dyn Request, res: &mut dyn Response) {
+        res.jump_to(&req.get_groups()[0]);
+    }
+
+    #[test]
+    fn forward_takes_an_exit_label_made_at_runtime() {
+        let mut diagram = new_diagram();
+        diagram.insert_fn("forward_to_group", forward_to_group);
+        diagram.node("HEAD").next(["RE.mode"]);
+        diagram
+            .node("RE.mode")
+            .regex("fast|slow|other")
+            .fn_("forward_to_group")
+            .exit("#fast", ["TK.a"])
+            .exit("#slow", ["TK.b"]);
+        diagram.node("TK.a").token("a").fn_("record").newline("TAIL");
+        diagram.node("TK.b").token("b").fn_("record").newline("TAIL");
+
+        assert_eq!(run_lines(&mut diagram, &["fast a"]), vec!["a", "#newline"]);
+        assert_eq!(run_lines(&mut diagram, &["slow b"]), vec!["b", "#newline"]);
+        assert_eq!(run_lines(&mut diagram, &["fast b"]), vec!["#else at 5"]);
+        // 無い出口は、遷移できなかったとして #else で処理する。
+        assert_eq!(run_lines(&mut diagram, &["other a"]), vec!["#else at 6"]);
+    }
+
+    #[test]
+    fn jump_to_is_checked_against_the_diagram() {
+        let mut diagram = new_diagram();
+        diagram.insert_fn("jump_to_group", jump_to_group);
+        diagram.node("HEAD").next(["RE.jump"]);
+        diagram.node("RE.jump").regex(r"[A-Za-z.]+").fn_("jump_to_group");
+        diagram.node("TK.b").token("b").fn_("record").next(["TK.c"]);
+        diagram.node("TK.c").token("c").fn_("record").newline("TAIL");
+
+        // 跳んだ先のノードの出口から、続きを読む。
+        assert_eq!(run_lines(&mut diagram, &["TK.b c"]), vec!["c", "#newline"]);
+        assert_eq!(run_lines(&mut diagram, &["TK.none c"]), vec!["#else at 8"]);
+    }
+
+    /// 入ったことを記録して、行の解析を変えようとする。アクションからは効かない。
+    fn enter_and_redirect(log: &mut Vec<String>, _req: &dyn Request, res: &mut dyn Response) {
+        log.push("enter".to_string());
+        res.forward("#none");
+        res.jump_to("TAIL");
+        res.set_done_line(true);
+    }
+
+    #[test]
+    fn actions_cannot_redirect_the_line_after_a_jump() {
+        let mut diagram = new_diagram();
+        diagram.insert_fn("jump_to_group", jump_to_group);
+        diagram.insert_fn("enter_and_redirect", enter_and_redirect);
+        diagram.node("HEAD").next(["RE.jump"]);
+        diagram.node("RE.jump").regex(r"[A-Za-z.]+").fn_("jump_to_group");
+        diagram.node("TK.b").on_enter("enter_and_redirect").next(["TK.c"]);
+        diagram.node("TK.c").token("c").fn_("record").newline("TAIL");
+
+        assert_eq!(run_lines(&mut diagram, &["TK.b c"]), vec!["enter", "c", "#newline"]);
+    }
+
+    #[test]
+    fn transition_errors_go_to_the_observer_and_else() {
+        let mut diagram = new_diagram();
+        diagram.insert_fn("jump_to_group", jump_to_group);
+        diagram.node("HEAD").next(["RE.jump"]);
+        diagram.node("RE.jump").regex(r"[A-Za-z.]+").fn_("jump_to_group");
+        let mut shell = Shell::new();
+        let events = EventLog::attach(&mut shell);
+        let mut log = Vec::new();
+
+        shell.execute_line(&mut diagram, &mut log, "TK.none");
+        let events = events.borrow();
+        assert_eq!(
+            events[events.len() - 2..].to_vec(),
+            vec![
+                format!("error {}", TransitionError::NodeNotFound("TK.none".to_string())),
+                "else TK.none 7".to_string(),
+            ]
+        );
+        assert_eq!(log, vec!["#else at 7"]);
+    }
+
+    /// diagram.json としては読めるが、パースの途中で遷移できないダイアグラム。
+    fn read_broken_diagram(nodes: &str) -> Diagram<Vec<String>> {
+        let mut diagram = new_diagram();
+        diagram
+            .read_str(&format!(
+                r##"{{
+                    "entry_point": "HEAD",
+                    "nodes": [
+                        {{ "label": "TAIL", "fn": "record_newline", "exit": {{ "#next": [ "HEAD" ] }} }},
+                        {}
+                    ]
+                }}"##,
+                nodes
+            ))
+            .unwrap();
+        diagram
+    }
+
+    /// 行を実行して、記録と 知らされたエラーを返す。
+    fn run_broken_lines(diagram: &mut Diagram<Vec<String>>, lines: &[&str]) -> (Vec<String>, Vec<String>) {
+        let mut shell = Shell::new();
+        let events = EventLog::attach(&mut shell);
+        let mut log = Vec::new();
+        for line in lines {
+            shell.execute_line(diagram, &mut log, line);
+        }
+        let errors = events
+            .borrow()
+            .iter()
+            .filter(|event| event.starts_with("error "))
+            .cloned()
+            .collect();
+        (log, errors)
+    }
+
+    #[test]
+    fn empty_newline_exit_is_reported_instead_of_panicking() {
+        let mut diagram = read_broken_diagram(
+            r##"{ "label": "HEAD", "exit": { "#next": [ "TK.go" ] } },
+                { "label": "TK.go", "token": "go", "fn": "record", "exit": { "#newline": [] } },
+                { "label": "#else", "fn": "record_else" }"##,
+        );
+
+        let (log, errors) = run_broken_lines(&mut diagram, &["go"]);
+        assert_eq!(log, vec!["go", "#else at 2"]);
+        assert_eq!(
+            errors,
+            vec![format!(
+                "error {}",
+                TransitionError::DoorNotFound("TK.go".to_string(), NEWLINE_EXIT_LABEL.to_string())
+            )]
+        );
+    }
+
+    #[test]
+    fn empty_next_exit_of_else_is_reported_instead_of_panicking() {
+        let mut diagram = read_broken_diagram(
+            r##"{ "label": "HEAD", "exit": { "#next": [ "TK.go" ] } },
+                { "label": "TK.go", "token": "go", "fn": "record", "exit": { "#newline": [ "TAIL" ] } },
+                { "label": "#else", "fn": "record_else", "exit": { "#next": [] } }"##,
+        );
+
+        // 次の行は 入り口から始める。
+        let (log, errors) = run_broken_lines(&mut diagram, &["stop", "go"]);
+        assert_eq!(log, vec!["#else at 0", "go", "#newline"]);
+        assert_eq!(
+            errors,
+            vec![format!(
+                "error {}",
+                TransitionError::DoorNotFound(ELSE_NODE_LABEL.to_string(), NEXT_EXIT_LABEL.to_string())
+            )]
+        );
+    }
+
+    #[test]
+    fn blank_exit_label_is_reported_instead_of_panicking() {
+        for label in &["", " "] {
+            let mut diagram = read_broken_diagram(&format!(
+                r##"{{ "label": "HEAD", "exit": {{ "#next": [ "TK.go", "{}" ] }} }},
+                    {{ "label": "TK.go", "token": "go", "fn": "record", "exit": {{ "#newline": [ "TAIL" ] }} }},
+                    {{ "label": "#else", "fn": "record_else" }}"##,
+                label
+            ));
+
+            let (log, errors) = run_broken_lines(&mut diagram, &["go"]);
+            assert_eq!(log, vec!["#else at 0"]);
+            assert_eq!(
+                errors,
+                vec![format!("error {}", TransitionError::NodeNotFound(label.to_string()))]
+            );
         }
     }
+
+    #[test]
+    fn dry_run_returns_spans_without_running_controllers() {
+        let mut diagram = new_diagram();
+        diagram.node("HEAD").next(["TK.depth"]);
+        diagram.node("TK.depth").token("depth").fn_("record").next(["TK.n"]);
+        diagram.node("TK.n").value(ValueType::Uint).fn_("record_value").newline("TAIL");
+        let shell = Shell::new();
+        let log = Vec::new();
+
+        let dry_run = shell.dry_run(&diagram, &log, "depth 12");
+        assert!(dry_run.is_accepted());
+        assert_eq!(
+            dry_run.tokens,
+            vec![
+                ParsedToken {
+                    label: "TK.depth".to_string(),
+                    start: 0,
+                    end: 5,
+                    matched: "depth".to_string(),
+                    groups: Vec::new(),
+                    value: None,
+                },
+                ParsedToken {
+                    label: "TK.n".to_string(),
+                    start: 6,
+                    end: 8,
+                    matched: "12".to_string(),
+                    groups: vec!["12".to_string()],
+                    value: Some(TokenValue::Uint(12)),
+                },
+            ]
+        );
+        assert_eq!(dry_run.end, DryRunEnd::Newline("TAIL".to_string()));
+        assert_eq!(
+            format!("{}", dry_run),
+            "[TK.depth] 0..5 \"depth\"\n[TK.n] 6..8 \"12\" groups: 12\nnewline: [TAIL]\n"
+        );
+        assert!(log.is_empty());
+        assert_eq!(shell.get_current(), "");
+    }
+
+    #[test]
+    fn dry_run_reports_where_the_line_stops() {
+        let mut diagram = new_diagram();
+        diagram.node("HEAD").next(["TK.depth"]);
+        diagram.node("TK.depth").token("depth").fn_("record").next(["TK.n"]);
+        diagram.node("TK.n").value(ValueType::Uint).fn_("record_value");
+        let shell = Shell::new();
+        let log = Vec::new();
+
+        let dry_run = shell.dry_run(&diagram, &log, "depth x");
+        assert!(!dry_run.is_accepted());
+        assert_eq!(dry_run.tokens.len(), 1);
+        assert_eq!(dry_run.end, DryRunEnd::Else(6));
+
+        // 行末なのに #newline が無い。
+        let dry_run = shell.dry_run(&diagram, &log, "depth 3");
+        assert_eq!(dry_run.tokens.len(), 2);
+        assert!(matches!(dry_run.end, DryRunEnd::Error(_)));
+        assert!(log.is_empty());
+    }
+
+    #[test]
+    fn dry_run_starts_from_the_current_node() {
+        let mut diagram = new_diagram();
+        diagram.node("HEAD").next(["TK.usi"]);
+        diagram.node("TK.usi").token("usi").fn_("record").newline("TAIL.usi");
+        diagram.node("TAIL.usi").next(["READY"]);
+        diagram.node("READY").next(["TK.go"]);
+        diagram.node("TK.go").token("go").fn_("record").newline("TAIL");
+        let mut shell = Shell::new();
+        let mut log = Vec::new();
+
+        assert_eq!(shell.dry_run(&diagram, &log, "go").end, DryRunEnd::Else(0));
+        shell.execute_line(&mut diagram, &mut log, "usi");
+        assert_eq!(shell.get_current(), "READY");
+        assert!(shell.dry_run(&diagram, &log, "go").is_accepted());
+        assert_eq!(shell.get_current(), "READY");
+        assert_eq!(log, vec!["usi"]);
+    }
 }
@@ -1,18 +1,45 @@
 /// .json ファイルを書き出す際に使う。
 extern crate serde_json;
 
+use self::serde_json::Number;
+use separator::*;
 use std::collections::HashMap;
 
 /// JSONを出力するときにだけ使う入れ物。
 #[derive(Serialize, Default, Deserialize, Debug)]
 pub struct DiagramJson {
     entry_point: String,
+
+    #[serde(skip_serializing_if = "Option::is_none")]
+    separator: Option<String>,
+
+    #[serde(skip_serializing_if = "Option::is_none")]
+    separator_chars: Option<String>,
+
+    #[serde(skip_serializing_if = "Option::is_none")]
+    ignore_case: Option<bool>,
+
+    #[serde(skip_serializing_if = "Option::is_none")]
+    nfkc: Option<bool>,
+
+    #[serde(skip_serializing_if = "Option::is_none")]
+    backtrack: Option<bool>,
+
+    #[serde(skip_serializing_if = "Option::is_none")]
+    line_continuation: Option<bool>,
+
     nodes: Vec<NodeJson>,
 }
 impl DiagramJson {
     pub fn new() -> DiagramJson {
         DiagramJson {
             entry_point: "".to_string(),
+            separator: None,
+            separator_chars: None,
+            ignore_case: None,
+            nfkc: None,
+            backtrack: None,
+            line_continuation: None,
             nodes: Vec::new(),
         }
     }
@@ -22,6 +49,22 @@ impl DiagramJson {
     pub fn set_entry_point(&mut self, value: String) {
         self.entry_point = value;
     }
+    pub fn set_separator(&mut self, value: &Separator) {
+        self.separator = Some(value.get_name().to_string());
+        self.separator_chars = separator_chars(value);
+    }
+    pub fn set_ignore_case(&mut self, value: Option<bool>) {
+        self.ignore_case = value;
+    }
+    pub fn set_nfkc(&mut self, value: Option<bool>) {
+        self.nfkc = value;
+    }
+    pub fn set_backtrack(&mut self, value: Option<bool>) {
+        self.backtrack = value;
+    }
+    pub fn set_line_continuation(&mut self, value: Option<bool>) {
+        self.line_continuation = value;
+    }
     pub fn get_nodes(&self) -> &Vec<NodeJson> {
         &self.nodes
     }
@@ -40,13 +83,65 @@ pub struct NodeJson {
     #[serde(skip_serializing_if = "Option::is_none")]
     regex: Option<String>,
 
+    #[serde(skip_serializing_if = "Option::is_none")]
+    rest: Option<bool>,
+
+    #[serde(skip_serializing_if = "Option::is_none")]
+    quoted: Option<bool>,
+
+    #[serde(skip_serializing_if = "Option::is_none")]
+    one_of: Option<Vec<String>>,
+
+    #[serde(skip_serializing_if = "Option::is_none")]
+    #[serde(rename = "type")]
+    value_type: Option<String>, // type がキーワードで使えない。
+
+    #[serde(skip_serializing_if = "Option::is_none")]
+    min: Option<Number>,
+
+    #[serde(skip_serializing_if = "Option::is_none")]
+    max: Option<Number>,
+
     #[serde(skip_serializing_if = "Option::is_none")]
     #[serde(rename = "fn")]
     fnc: Option<String>, // fn がキーワードで使えない。
 
+    #[serde(skip_serializing_if = "Option::is_none")]
+    separator: Option<String>,
+
+    #[serde(skip_serializing_if = "Option::is_none")]
+    separator_chars: Option<String>,
+
+    #[serde(skip_serializing_if = "Option::is_none")]
+    prefix: Option<bool>,
+
+    #[serde(skip_serializing_if = "Option::is_none")]
+    ignore_case: Option<bool>,
+
+    #[serde(skip_serializing_if = "Option::is_none")]
+    nfkc: Option<bool>,
+
+    #[serde(skip_serializing_if = "Option::is_none")]
+    priority: Option<i32>,
+
+    #[serde(skip_serializing_if = "Option::is_none")]
+    terminator: Option<String>,
+
+    #[serde(skip_serializing_if = "Option::is_none")]
+    call: Option<String>,
+
+    #[serde(skip_serializing_if = "Option::is_none")]
+    repeat: Option<RepeatJson>,
+
+    #[serde(skip_serializing_if = "Option::is_none")]
+    on_enter: Option<String>,
+
+    #[serde(skip_serializing_if = "Option::is_none")]
+    on_leave: Option<String>,
+
     #[serde(skip_serializing_if = "HashMap::is_empty", default)]
     #[serde(rename = "exit")]
-    exit_map: HashMap<String, Vec<String>>,
+    exit_map: HashMap<String, Vec<ExitJson>>,
 }
 impl NodeJson {
     pub fn new() -> NodeJson {
@@ -54,7 +149,24 @@ impl NodeJson {
             label: "".to_string(),
             token: None,
             regex: None,
+            rest: None,
+            quoted: None,
+            one_of: None,
+            value_type: None,
+            min: None,
+            max: None,
             fnc: None,
+            separator: None,
+            separator_chars: None,
+            prefix: None,
+            ignore_case: None,
+            nfkc: None,
+            priority: None,
+            terminator: None,
+            call: None,
+            repeat: None,
+            on_enter: None,
+            on_leave: None,
             exit_map: HashMap::new(),
         }
     }
@@ -76,16 +188,171 @@ impl NodeJson {
     pub fn set_regex(&mut self, value: Option<String>) {
         self.regex = value;
     }
+    pub fn get_rest(&self) -> &Option<bool> {
+        &self.rest
+    }
+    pub fn set_rest(&mut self, value: Option<bool>) {
+        self.rest = value;
+    }
+    pub fn get_quoted(&self) -> &Option<bool> {
+        &self.quoted
+    }
+    pub fn set_quoted(&mut self, value: Option<bool>) {
+        self.quoted = value;
+    }
+    pub fn get_one_of(&self) -> &Option<Vec<String>> {
+        &self.one_of
+    }
+    pub fn set_one_of(&mut self, value: Option<Vec<String>>) {
+        self.one_of = value;
+    }
+    pub fn get_value_type(&self) -> &Option<String> {
+        &self.value_type
+    }
+    pub fn set_value_type(&mut self, value: Option<String>) {
+        self.value_type = value;
+    }
+    pub fn get_min(&self) -> Option<f64> {
+        self.min.as_ref().and_then(|n| n.as_f64())
+    }
+    pub fn set_min(&mut self, value: Option<f64>) {
+        self.min = value.map(to_number);
+    }
+    pub fn get_max(&self) -> Option<f64> {
+        self.max.as_ref().and_then(|n| n.as_f64())
+    }
+    pub fn set_max(&mut self, value: Option<f64>) {
+        self.max = value.map(to_number);
+    }
     pub fn get_fnc(&self) -> &Option<String> {
         &self.fnc
     }
     pub fn set_fnc(&mut self, value: Option<String>) {
         self.fnc = value;
     }
-    pub fn get_exit_map(&self) -> &HashMap<String, Vec<String>> {
+    pub fn set_separator(&mut self, value: &Separator) {
+        self.separator = Some(value.get_name().to_string());
+        self.separator_chars = separator_chars(value);
+    }
+    pub fn get_prefix(&self) -> &Option<bool> {
+        &self.prefix
+    }
+    pub fn set_prefix(&mut self, value: Option<bool>) {
+        self.prefix = value;
+    }
+    pub fn get_ignore_case(&self) -> &Option<bool> {
+        &self.ignore_case
+    }
+    pub fn set_ignore_case(&mut self, value: Option<bool>) {
+        self.ignore_case = value;
+    }
+    pub fn get_priority(&self) -> &Option<i32> {
+        &self.priority
+    }
+    pub fn set_priority(&mut self, value: Option<i32>) {
+        self.priority = value;
+    }
+    pub fn get_call(&self) -> &Option<String> {
+        &self.call
+    }
+    pub fn set_call(&mut self, value: Option<String>) {
+        self.call = value;
+    }
+    pub fn get_on_enter(&self) -> &Option<String> {
+        &self.on_enter
+    }
+    pub fn set_on_enter(&mut self, value: Option<String>) {
+        self.on_enter = value;
+    }
+    pub fn get_on_leave(&self) -> &Option<String> {
+        &self.on_leave
+    }
+    pub fn set_on_leave(&mut self, value: Option<String>) {
+        self.on_leave = value;
+    }
+    pub fn get_terminator(&self) -> &Option<String> {
+        &self.terminator
+    }
+    pub fn set_terminator(&mut self, value: Option<String>) {
+        self.terminator = value;
+    }
+    pub fn get_repeat(&self) -> &Option<RepeatJson> {
+        &self.repeat
+    }
+    pub fn set_repeat(&mut self, value: Option<RepeatJson>) {
+        self.repeat = value;
+    }
+    pub fn get_nfkc(&self) -> &Option<bool> {
+        &self.nfkc
+    }
+    pub fn set_nfkc(&mut self, value: Option<bool>) {
+        self.nfkc = value;
+    }
+    pub fn get_exit_map(&self) -> &HashMap<String, Vec<ExitJson>> {
         &self.exit_map
     }
-    pub fn insert_exit(&mut self, exit_label:&str, entrance_nodes:Vec<String>) {
+    pub fn insert_exit(&mut self, exit_label:&str, entrance_nodes:Vec<ExitJson>) {
         self.exit_map.insert(exit_label.to_string(), entrance_nodes);
     }
 }
+
+/// `Chars` なら、区切りの文字。
+fn separator_chars(value: &Separator) -> Option<String> {
+    match *value {
+        Separator::Chars(ref chars) => Some(chars.to_string()),
+        _ => None,
+    }
+}
+
+/// 出口の行き先。述語が付いていなければ、ラベルの文字列だけ書く。
+#[derive(Serialize, Deserialize, Debug)]
+#[serde(untagged)]
+pub enum ExitJson {
+    Label(String),
+    Guarded(GuardedExitJson),
+}
+
+#[derive(Serialize, Deserialize, Debug, Default)]
+pub struct GuardedExitJson {
+    label: String,
+    guard: String,
+}
+impl GuardedExitJson {
+    pub fn new(label: String, guard: String) -> GuardedExitJson {
+        GuardedExitJson { label, guard }
+    }
+    pub fn get_label(&self) -> &str {
+        &self.label
+    }
+    pub fn get_guard(&self) -> &str {
+        &self.guard
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Default)]
+pub struct RepeatJson {
+    min: usize,
+
+    #[serde(skip_serializing_if = "Option::is_none")]
+    max: Option<usize>,
+}
+impl RepeatJson {
+    pub fn new(min: usize, max: Option<usize>) -> RepeatJson {
+        RepeatJson { min, max }
+    }
+    pub fn get_min(&self) -> usize {
+        self.min
+    }
+    pub fn get_max(&self) -> Option<usize> {
+        self.max
+    }
+}
+
+/// 整数で表せる値は `0.0` ではなく `0` と書く。
+fn to_number(value: f64) -> Number {
+    if value.fract() == 0.0 && (i64::MIN as f64) <= value && value <= (i64::MAX as f64) {
+        Number::from(value as i64)
+    } else {
+        Number::from_f64(value).unwrap()
+    }
+}
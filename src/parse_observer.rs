@@ -0,0 +1,164 @@
+/// パースの途中経過を見る。
+///
+/// 不具合を取りたいときに `Shell::set_observer` で `ParseLogger` を差し込めば、
+/// ライブラリのソースを書き換えずに 途中経過を表示できる。
+use diagram::*;
+use std::io;
+use transition_error::*;
+
+/// パースの途中経過を受け取る。何もしないメソッドが既定なので、見たいものだけ書けばよい。
+pub trait ParseObserver {
+    /// 現在ノードの出口から、次の行き先の候補を並べたとき。
+    ///
+    /// # Arguments
+    ///
+    /// * `labels` - 呼び出しと戻りを解いた、出口の行き先。
+    fn on_candidates(&mut self, _current_label: &str, _door_label: &str, _labels: &[String]) {}
+
+    /// 候補のノードが、トークンに一致するか試したとき。
+    ///
+    /// # Arguments
+    ///
+    /// * `caret` - 試した読取位置。
+    /// * `end` - 一致したトークンの終わり。一致しなければ None 。
+    fn on_attempt(&mut self, _node: &Node, _caret: usize, _end: Option<usize>) {}
+
+    /// 出口の行き先に付いた述語を呼んだとき。
+    ///
+    /// # Arguments
+    ///
+    /// * `passes` - 真なら、行き先を候補にする。
+    fn on_guard(&mut self, _next_label: &str, _guard: &str, _passes: bool) {}
+
+    /// 次の行き先を選んだとき。
+    fn on_chosen(&mut self, _label: &str) {}
+
+    /// 次の行き先の候補が、どれも一致しなかったとき。
+    ///
+    /// # Arguments
+    ///
+    /// * `caret` - 試した読取位置。
+    fn on_no_match(&mut self, _current_label: &str, _door_label: &str, _caret: usize) {}
+
+    /// キャレットを進めたとき。
+    fn on_caret(&mut self, _from: usize, _to: usize) {}
+
+    /// ノードのコールバック関数を呼ぶ前。
+    fn on_controller(&mut self, _node_label: &str, _fn_label: &str) {}
+
+    /// 行末で「行末」ノードに進むとき。
+    fn on_newline(&mut self, _node_label: &str, _tail_node_label: &str) {}
+
+    /// 何とも一致せず、 `#else` ノードで処理するとき。
+    fn on_else(&mut self, _line: &str, _caret: usize) {}
+
+    /// 遷移できなかったとき。この後 `#else` ノードで処理する。
+    fn on_error(&mut self, _error: &TransitionError) {}
+
+    /// コールバック関数が指示した ファイルの保存や読み直し、復元が できなかったとき。ダイアグラムは そのまま。
+    fn on_file_error(&mut self, _file: &str, _error: &io::Error) {}
+}
+
+/// 何も見ない。
+pub struct NullObserver {}
+impl ParseObserver for NullObserver {}
+
+/// 途中経過を 標準出力に表示する。
+///
+/// # Members
+///
+/// * `enabled` - 偽にすると、差し込んだまま表示を止める。
+pub struct ParseLogger {
+    enabled: bool,
+}
+impl Default for ParseLogger {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+impl ParseLogger {
+    pub fn new() -> ParseLogger {
+        ParseLogger { enabled: true }
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        self.enabled
+    }
+    pub fn set_enabled(&mut self, value: bool) {
+        self.enabled = value;
+    }
+
+    fn print(&self, text: &str) {
+        if self.enabled {
+            println!("[parse] {}", text);
+        }
+    }
+}
+impl ParseObserver for ParseLogger {
+    fn on_candidates(&mut self, current_label: &str, door_label: &str, labels: &[String]) {
+        self.print(&format!(
+            "candidates: [{}] {} -> {}",
+            current_label,
+            door_label,
+            labels.join(", ")
+        ));
+    }
+
+    fn on_attempt(&mut self, node: &Node, caret: usize, end: Option<usize>) {
+        let result = match end {
+            Some(end) => format!("match {}..{}", caret, end),
+            None => "no match".to_string(),
+        };
+        self.print(&format!(
+            "attempt: [{}] {} at {} -> {}",
+            node.get_label(),
+            node.get_token_caption(),
+            caret,
+            result
+        ));
+    }
+
+    fn on_guard(&mut self, next_label: &str, guard: &str, passes: bool) {
+        self.print(&format!(
+            "guard: [{}] {} -> {}",
+            next_label,
+            guard,
+            if passes { "pass" } else { "fail" }
+        ));
+    }
+
+    fn on_chosen(&mut self, label: &str) {
+        self.print(&format!("chosen: [{}]", label));
+    }
+
+    fn on_no_match(&mut self, current_label: &str, door_label: &str, caret: usize) {
+        self.print(&format!(
+            "no match: [{}] {} at {}",
+            current_label, door_label, caret
+        ));
+    }
+
+    fn on_caret(&mut self, from: usize, to: usize) {
+        self.print(&format!("caret: {} -> {}", from, to));
+    }
+
+    fn on_controller(&mut self, node_label: &str, fn_label: &str) {
+        self.print(&format!("controller: {} ([{}] node)", fn_label, node_label));
+    }
+
+    fn on_newline(&mut self, node_label: &str, tail_node_label: &str) {
+        self.print(&format!("newline: [{}] -> [{}]", node_label, tail_node_label));
+    }
+
+    fn on_else(&mut self, line: &str, caret: usize) {
+        self.print(&format!("else: [{}] at {}", line, caret));
+    }
+
+    fn on_error(&mut self, error: &TransitionError) {
+        self.print(&format!("error: {}", error));
+    }
+
+    fn on_file_error(&mut self, file: &str, error: &io::Error) {
+        self.print(&format!("file error: {} {}", file, error));
+    }
+}
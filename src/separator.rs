@@ -0,0 +1,152 @@
+/// 全角スペース。日本語入力のまま打鍵すると混ざる。
+pub const FULL_WIDTH_SPACE: char = '\u{3000}';
+
+/// トークンとトークンの間の区切りの、読み飛ばし方。
+/// トークンに一致した後、続きの区切りを読み飛ばしてから 次のトークンを探す。
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub enum Separator {
+    /// 半角スペースを 1つだけ 読み飛ばす。既定値。
+    #[default]
+    OneSpace,
+    /// 半角スペースやタブなど、ASCIIの空白の連続をまとめて読み飛ばす。
+    Whitespace,
+    /// 全角スペースも半角スペースと同じに扱い、空白の連続をまとめて読み飛ばす。
+    FullWidthWhitespace,
+    /// 指定した文字の連続をまとめて読み飛ばす。
+    Chars(String),
+    /// 何も読み飛ばさない。トークンが隣り合っているプロトコル用。
+    Nothing,
+}
+impl Separator {
+    /// diagram.json に書く名前から作る。
+    ///
+    /// # Arguments.
+    ///
+    /// * 'name' - `"separator"` の値。
+    /// * 'chars' - `"separator_chars"` の値。書いてあれば `Chars` になる。
+    pub fn from_name(name: Option<&str>, chars: Option<&str>) -> Option<Separator> {
+        match Separator::try_from_name(name, chars) {
+            Ok(n) => n,
+            Err(n) => panic!("\"{}\" separator is not found.", n),
+        }
+    }
+
+    /// `from_name` の、知らない名前なら その名前を Err で返す版。
+    pub fn try_from_name<'a>(
+        name: Option<&'a str>,
+        chars: Option<&str>,
+    ) -> Result<Option<Separator>, &'a str> {
+        if let Some(chars) = chars {
+            return Ok(Some(Separator::Chars(chars.to_string())));
+        }
+        match name {
+            Some("one_space") => Ok(Some(Separator::OneSpace)),
+            Some("whitespace") => Ok(Some(Separator::Whitespace)),
+            Some("full_width_whitespace") => Ok(Some(Separator::FullWidthWhitespace)),
+            Some("nothing") => Ok(Some(Separator::Nothing)),
+            Some(n) => Err(n),
+            None => Ok(None),
+        }
+    }
+
+    /// diagram.json に書く名前。 `Chars` なら `"separator_chars"` の方に文字を書く。
+    pub fn get_name(&self) -> &str {
+        match *self {
+            Separator::OneSpace => "one_space",
+            Separator::Whitespace => "whitespace",
+            Separator::FullWidthWhitespace => "full_width_whitespace",
+            Separator::Chars(_) => "chars",
+            Separator::Nothing => "nothing",
+        }
+    }
+
+    /// 複数の行を 1行につなぐとき、間に挟む文字列。
+    pub fn get_joiner(&self) -> String {
+        match *self {
+            Separator::OneSpace | Separator::Whitespace | Separator::FullWidthWhitespace => {
+                " ".to_string()
+            }
+            Separator::Chars(ref chars) => chars.chars().take(1).collect(),
+            Separator::Nothing => "".to_string(),
+        }
+    }
+
+    /// 区切りの文字なら真。
+    pub fn is_separator(&self, ch: char) -> bool {
+        match *self {
+            Separator::OneSpace => ch == ' ',
+            Separator::Whitespace => ch.is_ascii_whitespace(),
+            Separator::FullWidthWhitespace => ch.is_ascii_whitespace() || ch == FULL_WIDTH_SPACE,
+            Separator::Chars(ref chars) => chars.contains(ch),
+            Separator::Nothing => false,
+        }
+    }
+
+    /// `caret` から続く区切りを読み飛ばした先のキャレットを返す。
+    /// キャレットは、行の先頭からのバイト数。
+    pub fn skip(&self, line: &str, caret: usize) -> usize {
+        let mut end = caret;
+        for ch in line[caret..].chars() {
+            if !self.is_separator(ch) {
+                break;
+            }
+            end += ch.len_utf8();
+            if *self == Separator::OneSpace {
+                break;
+            }
+        }
+        end
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn skip_reads_over_separators_by_kind() {
+        let line = "a  \u{3000} b";
+        assert_eq!(Separator::OneSpace.skip(line, 1), 2);
+        assert_eq!(Separator::Whitespace.skip(line, 1), 3);
+        assert_eq!(Separator::FullWidthWhitespace.skip(line, 1), line.len() - 1);
+        assert_eq!(Separator::Nothing.skip(line, 1), 1);
+        assert_eq!(Separator::Chars(",;".to_string()).skip("a,;,b", 1), 4);
+    }
+
+    #[test]
+    fn from_name_reads_diagram_json_names() {
+        assert_eq!(Separator::from_name(None, None), None);
+        assert_eq!(
+            Separator::from_name(Some("whitespace"), None),
+            Some(Separator::Whitespace)
+        );
+        assert_eq!(
+            Separator::from_name(Some("whitespace"), Some(",")),
+            Some(Separator::Chars(",".to_string()))
+        );
+        for separator in &[
+            Separator::OneSpace,
+            Separator::Whitespace,
+            Separator::FullWidthWhitespace,
+            Separator::Nothing,
+        ] {
+            assert_eq!(
+                Separator::from_name(Some(separator.get_name()), None).as_ref(),
+                Some(separator)
+            );
+        }
+    }
+
+    #[test]
+    #[should_panic(expected = "separator is not found")]
+    fn from_name_rejects_an_unknown_name() {
+        Separator::from_name(Some("tab"), None);
+    }
+
+    #[test]
+    fn get_joiner_uses_the_first_separator_char() {
+        assert_eq!(Separator::Whitespace.get_joiner(), " ");
+        assert_eq!(Separator::Chars(",;".to_string()).get_joiner(), ",");
+        assert_eq!(Separator::Nothing.get_joiner(), "");
+    }
+}
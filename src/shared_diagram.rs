@@ -0,0 +1,190 @@
+/// スレッドをまたいで共有するダイアグラム。
+/// 読み込んだ文法は 1つ で、クライアントごとに `Shell` を作るときに使う。
+/// 各 `Shell` が持つのは自分の現在ノードだけで、ダイアグラムは `Arc` で共有する。
+use diagram::*;
+use std::sync::{Arc, PoisonError, RwLock};
+
+/// 差し替えのできる、共有のダイアグラム。
+/// 読み直しは 複製を書き換えてから まるごと差し替えるので、
+/// 処理中の行は 差し替える前のダイアグラムのまま 最後まで進む。
+/// ロックを取るのは `Arc` を受け渡すときだけで、その間に他の処理は呼ばない。
+///
+/// # Members
+///
+/// * `current` - 今のダイアグラム。
+pub struct SharedDiagram<T> {
+    current: RwLock<Arc<Diagram<T>>>,
+}
+impl<T> SharedDiagram<T> {
+    pub fn new(diagram: Diagram<T>) -> SharedDiagram<T> {
+        SharedDiagram {
+            current: RwLock::new(Arc::new(diagram)),
+        }
+    }
+
+    /// 今のダイアグラム。差し替えられても、受け取った方は変わらない。
+    pub fn load(&self) -> Arc<Diagram<T>> {
+        // 差し替えは代入だけなので、ロックが毒されていても 中身は壊れていない。
+        let current = self.current.read().unwrap_or_else(PoisonError::into_inner);
+        Arc::clone(&current)
+    }
+
+    /// ダイアグラムを差し替える。
+    pub fn store(&self, diagram: Diagram<T>) {
+        let diagram = Arc::new(diagram);
+        let mut current = self.current.write().unwrap_or_else(PoisonError::into_inner);
+        *current = diagram;
+    }
+
+    /// 今のダイアグラムを複製して書き換え、うまくいったときだけ差し替える。
+    /// 書き換えはロックの外で行うので、 `f` が失敗しても 止まっても、共有のダイアグラムは元のまま。
+    /// 同時に書き換えたら、後から差し替えた方が残る。
+    pub fn try_update<F, E>(&self, f: F) -> Result<(), E>
+    where
+        F: FnOnce(&mut Diagram<T>) -> Result<(), E>,
+    {
+        let mut diagram = (*self.load()).clone();
+        f(&mut diagram)?;
+        self.store(diagram);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use parse_observer::*;
+    use shell::*;
+    use std::env;
+    use std::fs;
+    use std::io;
+    use std::process;
+    use std::sync::Mutex;
+    use std::thread;
+
+    /// 読み直せなかったファイルを記録する。
+    struct FileErrorLog {
+        errors: Arc<Mutex<Vec<String>>>,
+    }
+    impl ParseObserver for FileErrorLog {
+        fn on_file_error(&mut self, file: &str, _error: &io::Error) {
+            self.errors.lock().unwrap().push(file.to_string());
+        }
+    }
+
+    /// `t` に書いたファイルを 読み直す。
+    fn do_reload(file: &mut String, _req: &dyn Request, res: &mut dyn Response) {
+        res.set_option(ResponseOption::Reloads(file.to_string()));
+    }
+
+    /// `t` に書いたファイルに 保存する。
+    fn do_save(file: &mut String, _req: &dyn Request, res: &mut dyn Response) {
+        res.set_option(ResponseOption::Saves(file.to_string()));
+    }
+
+    fn new_diagram(entry_point: &str) -> Diagram<String> {
+        let mut diagram = Diagram::new();
+        diagram.insert_fn("do_reload", do_reload);
+        diagram.insert_fn("do_save", do_save);
+        diagram.set_entry_point(entry_point.to_string());
+        diagram.node(entry_point).next(["TK.reload", "TK.save"]);
+        diagram.node("TK.save").token("save").fn_("do_save").next(["TK.save"]);
+        diagram
+            .node("TK.reload")
+            .token("reload")
+            .fn_("do_reload")
+            // 読み直しを指示した行は、行末まで進まずに終わる。
+            .next(["TK.reload"]);
+        diagram
+    }
+
+    #[test]
+    fn try_update_keeps_the_diagram_when_it_fails() {
+        let shared = SharedDiagram::new(new_diagram("HEAD"));
+
+        let result: Result<(), &str> = shared.try_update(|diagram| {
+            diagram.set_entry_point("BROKEN".to_string());
+            Err("failed")
+        });
+        assert_eq!(result, Err("failed"));
+        assert_eq!(shared.load().get_entry_point(), "HEAD");
+
+        let result: Result<(), &str> = shared.try_update(|diagram| {
+            diagram.set_entry_point("NEXT".to_string());
+            Ok(())
+        });
+        assert_eq!(result, Ok(()));
+        assert_eq!(shared.load().get_entry_point(), "NEXT");
+    }
+
+    #[test]
+    fn poisoned_lock_still_loads_and_stores() {
+        let shared = Arc::new(SharedDiagram::new(new_diagram("HEAD")));
+        let poisoner = Arc::clone(&shared);
+        let _ = thread::spawn(move || {
+            let _current = poisoner.current.write().unwrap();
+            panic!("poison");
+        })
+        .join();
+        assert!(shared.current.is_poisoned());
+
+        assert_eq!(shared.load().get_entry_point(), "HEAD");
+        shared.store(new_diagram("NEXT"));
+        assert_eq!(shared.load().get_entry_point(), "NEXT");
+    }
+
+    #[test]
+    fn shared_reload_reports_a_broken_file_and_keeps_the_diagram() {
+        let file = env::temp_dir()
+            .join(format!("kifuwarabe_shell_shared_reload_{}.json", process::id()))
+            .to_str()
+            .unwrap()
+            .to_string();
+        let shared = SharedDiagram::new(new_diagram("HEAD"));
+        let errors = Arc::new(Mutex::new(Vec::new()));
+        let mut shell = Shell::new();
+        shell.set_observer(Box::new(FileErrorLog {
+            errors: Arc::clone(&errors),
+        }));
+
+        fs::write(&file, "{ broken").unwrap();
+        let mut t = file.to_string();
+        shell.execute_line_shared(&shared, &mut t, "reload");
+        assert_eq!(*errors.lock().unwrap(), vec![file.to_string()]);
+        assert_eq!(shared.load().get_entry_point(), "HEAD");
+
+        new_diagram("NEXT").write_file(&file);
+        shell.execute_line_shared(&shared, &mut t, "reload");
+        assert_eq!(errors.lock().unwrap().len(), 1);
+        assert_eq!(shared.load().get_entry_point(), "NEXT");
+        // 登録したコールバック関数は残る。
+        assert!(shared.load().contains_fn("do_reload"));
+
+        for backup in list_backups(&file) {
+            let _ = fs::remove_file(backup);
+        }
+        let _ = fs::remove_file(&file);
+    }
+
+    #[test]
+    fn failed_saves_are_reported_instead_of_stopping() {
+        let file = env::temp_dir()
+            .join(format!("kifuwarabe_shell_missing_dir_{}", process::id()))
+            .join("diagram.json")
+            .to_str()
+            .unwrap()
+            .to_string();
+        let mut diagram = new_diagram("HEAD");
+        let shared = SharedDiagram::new(new_diagram("HEAD"));
+        let errors = Arc::new(Mutex::new(Vec::new()));
+        let mut shell = Shell::new();
+        shell.set_observer(Box::new(FileErrorLog {
+            errors: Arc::clone(&errors),
+        }));
+
+        let mut t = file.to_string();
+        shell.execute_line(&mut diagram, &mut t, "save");
+        shell.execute_line_shared(&shared, &mut t, "save");
+        assert_eq!(*errors.lock().unwrap(), vec![file.to_string(), file.to_string()]);
+    }
+}
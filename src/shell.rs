@@ -1,49 +1,63 @@
 use diagram::ResponseOption;
+use coverage::*;
 use diagram::*;
 /// クライアント１つにつき、１つのシェルを与えます。
 /// 行単位です。
+/// ダイアグラムは `SharedDiagram` にすれば、クライアントのスレッド間で共有できます。
 ///
 /// コマンド例
 ///
-/// ```
+/// ```text
 /// cls
 /// cd C:\MuzudhoDrive\projects_rust\rust_kifuwarabe_shell
 /// cargo clippy
 /// ```
 use diagram_player::*;
 use line_parser::*;
+use parse_observer::*;
+use shared_diagram::*;
+use shell_snapshot::*;
 use std::any::Any; // https://stackoverflow.com/questions/33687447/how-to-get-a-struct-reference-from-a-boxed-trait
+use std::collections::VecDeque;
 use std::io;
-
-/// 不具合を取りたいときに真にする。
-// const VERBOSE: bool = false;
+use std::mem;
+use token_value::*;
+use transition_error::*;
 
 pub const NEXT_EXIT_LABEL: &str = "#next";
 /// デフォルトのラベル。
 pub const NEWLINE_EXIT_LABEL: &str = "#newline";
 pub const ELSE_NODE_LABEL: &str = "#else";
+/// 出口の行き先に書くと、呼び出し元の出口の行き先に戻る。
+pub const RETURN_LABEL: &str = "#return";
 
 /// コマンドライン文字列。
 ///
 /// # Members
 ///
 /// * `line` - コマンドライン文字列の1行全体です。
-/// * `line_len` - コマンドライン文字列の1行全体の文字数です。
+/// * `line_len` - コマンドライン文字列の1行全体のバイト数です。
+/// * `caret` - 読取位置。行の先頭からのバイト数です。
 /// * `groups` - あれば、正規表現の結果を入れておく。
+/// * `matched` - 今のノードに一致した部分の原文。
 pub struct RequestStruct {
-    pub line: Box<String>, // String型は長さが可変なので、固定長のBoxでラップする。
+    pub line: String,
     pub line_len: usize,
     pub caret: usize,
     pub groups: Vec<String>,
+    pub matched: String,
+    pub value: Option<TokenValue>,
 }
 impl RequestStruct {
-    fn new(line2: Box<String>) -> RequestStruct {
-        let len = line2.chars().count();
+    pub(crate) fn new(line2: String) -> RequestStruct {
+        let len = line2.len();
         RequestStruct {
             line: line2,
             line_len: len,
             caret: 0,
             groups: Vec::new(),
+            matched: "".to_string(),
+            value: None,
         }
     }
 }
@@ -63,6 +77,12 @@ impl Request for RequestStruct {
     fn get_groups(&self) -> &Vec<String> {
         &self.groups
     }
+    fn get_matched(&self) -> &str {
+        &self.matched
+    }
+    fn get_value(&self) -> Option<TokenValue> {
+        self.value
+    }
 }
 
 /// キャレット。本来、文字列解析のカーソル位置だが、ほかの機能も持たされている。
@@ -75,19 +95,22 @@ impl Request for RequestStruct {
 /// * `done_line` - 行の解析を中断するなら真にします。
 /// * `option` - シェルに指示を出す。アプリケーション終了、ファイル再読込など。
 /// * `exit_label` - 次のノード ラベルです。
+/// * `jump_label` - あれば、出口を使わずに跳ぶ先のノードのラベル。
 pub struct ResponseStruct {
     pub caret: usize,
     pub done_line: bool,
     pub option: ResponseOption,
     pub exit_label: String,
+    pub jump_label: Option<String>,
 }
 impl ResponseStruct {
-    fn new() -> ResponseStruct {
+    pub(crate) fn new() -> ResponseStruct {
         ResponseStruct {
             caret: 0,
             done_line: false,
             option: ResponseOption::None,
             exit_label: "".to_string(),
+            jump_label: None,
         }
     }
     /// デフォルト値にリセット。
@@ -96,6 +119,7 @@ impl ResponseStruct {
         self.set_done_line(false);
         self.set_option(ResponseOption::None);
         self.forward(NEXT_EXIT_LABEL); // デフォルト値にリセット。
+        self.jump_label = None;
     }
 }
 
@@ -107,10 +131,12 @@ impl Response for ResponseStruct {
     fn as_mut_any(&mut self) -> &mut dyn Any {
         self
     }
-    // .rs にハードコーディングして使う。
-    fn forward(&mut self, exit_label2: &'static str) {
+    fn forward(&mut self, exit_label2: &str) {
         self.exit_label = exit_label2.to_string();
     }
+    fn jump_to(&mut self, node_label: &str) {
+        self.jump_label = Some(node_label.to_string());
+    }
     fn set_caret(&mut self, caret2: usize) {
         self.caret = caret2
     }
@@ -140,10 +166,14 @@ pub fn standard_input_reader<T>(_t: &mut T) -> String {
 /// # Arguments
 ///
 /// * `vec_row` - コマンドを複数行 溜めておくバッファーです。
+/// * `prints_reload_diff` - ダイアグラムを読み直したとき、変更点を表示するなら真。
+/// * `observer` - あれば、パースの途中経過を知らせる。
 pub struct Shell<T: 'static> {
     diagram_player: DiagramPlayer,
-    vec_row: Vec<String>,
+    vec_row: VecDeque<String>,
     reader: Reader<T>,
+    prints_reload_diff: bool,
+    observer: Option<Box<dyn ParseObserver>>,
 }
 impl<T> Default for Shell<T> {
     fn default() -> Self {
@@ -154,20 +184,38 @@ impl<T: 'static> Shell<T> {
     pub fn new() -> Shell<T> {
         Shell {
             diagram_player: DiagramPlayer::new(),
-            vec_row: Vec::new(),
+            vec_row: VecDeque::new(),
             reader: standard_input_reader,
+            prints_reload_diff: false,
+            observer: None,
         }
     }
 
-    pub fn enter(&mut self, diagram: &Diagram<T>){
-        self.diagram_player.enter(&diagram);
+    /// 入り口に遷移する。 `on_enter` が `set_option` で出した指示を返す。
+    pub fn enter(&mut self, diagram: &Diagram<T>, t: &mut T) -> ResponseOption {
+        self.diagram_player.enter(diagram, t)
     }
 
     /// 状態遷移する。
     /// req引数の要らないフォワード。
     /// （パーサーのマッチングを省いて、強制的に指定のドアにフォワードする）
-    pub fn forward_force(&mut self, diagram: &Diagram<T>, door_label: &str) {
-        self.diagram_player.forward_force(diagram, door_label)
+    /// `on_enter` や `on_leave` が `set_option` で出した指示を返す。
+    pub fn forward_force(
+        &mut self,
+        diagram: &Diagram<T>,
+        t: &mut T,
+        door_label: &str,
+    ) -> ResponseOption {
+        self.diagram_player.forward_force(diagram, t, door_label)
+    }
+    /// `forward_force` の、遷移できなければ Err を返す版。
+    pub fn try_forward_force(
+        &mut self,
+        diagram: &Diagram<T>,
+        t: &mut T,
+        door_label: &str,
+    ) -> Result<ResponseOption, TransitionError> {
+        self.diagram_player.try_forward_force(diagram, t, door_label)
     }
     /// 状態遷移する。
     /// req引数の要るフォワード。
@@ -177,10 +225,43 @@ impl<T: 'static> Shell<T> {
     pub fn forward_parse(
         &mut self,
         diagram: &Diagram<T>,
+        t: &mut T,
         req: &mut dyn Request,
+        res: &mut dyn Response,
         door_label: &str,
     ) -> bool {
-        self.diagram_player.forward_parse(diagram, req, door_label)
+        match self.try_forward_parse(diagram, t, req, res, door_label) {
+            Ok(n) => n,
+            Err(err) => panic!("{}", err),
+        }
+    }
+    /// `forward_parse` の、現在ノードや出口が無ければ Err を返す版。
+    pub fn try_forward_parse(
+        &mut self,
+        diagram: &Diagram<T>,
+        t: &mut T,
+        req: &mut dyn Request,
+        res: &mut dyn Response,
+        door_label: &str,
+    ) -> Result<bool, TransitionError> {
+        match self.observer {
+            Some(ref mut observer) => self.diagram_player.try_forward_parse(
+                diagram,
+                t,
+                req,
+                res,
+                door_label,
+                observer.as_mut(),
+            ),
+            None => self.diagram_player.try_forward_parse(
+                diagram,
+                t,
+                req,
+                res,
+                door_label,
+                &mut NullObserver {},
+            ),
+        }
     }
 
     /// 現在ノードのラベル。
@@ -193,21 +274,257 @@ impl<T: 'static> Shell<T> {
         self.diagram_player.is_out()
     }
 
+    /// 現在ノードから 1行 空読みします。コールバック関数は呼ばず、現在ノードも変えません。
+    /// 詳しくは `LineParser::dry_run` 。
+    pub fn dry_run(&self, diagram: &Diagram<T>, t: &T, line: &str) -> DryRun {
+        LineParser::dry_run(
+            diagram,
+            t,
+            &self.diagram_player.get_current(),
+            self.diagram_player.get_call_stack(),
+            line,
+        )
+    }
+
     pub fn set_reader(&mut self, reader2: Reader<T>) {
         self.reader = reader2;
     }
+    /// 真にすると、 Reloads や RestoresBackup でダイアグラムを読み直したとき、変更点を表示します。
+    pub fn set_prints_reload_diff(&mut self, value: bool) {
+        self.prints_reload_diff = value;
+    }
+    /// パースの途中経過を知らせる先を差し込みます。不具合を取りたいときは `ParseLogger` を使ってください。
+    pub fn set_observer(&mut self, observer: Box<dyn ParseObserver>) {
+        self.observer = Some(observer);
+    }
+    /// 差し込んだ `observer` を外します。
+    pub fn clear_observer(&mut self) {
+        self.observer = None;
+    }
+    /// 通った記録。記録していなければ None 。
+    pub fn get_coverage(&self) -> Option<&Coverage> {
+        self.diagram_player.get_coverage()
+    }
+    /// `Some(Coverage::new())` を入れると、通ったノードや出口を数え始めます。 None を入れると止めます。
+    pub fn set_coverage(&mut self, value: Option<Coverage>) {
+        self.diagram_player.set_coverage(value);
+    }
     /// コマンドを1行も入力していなければ真を返します。
     pub fn is_empty(&self) -> bool {
-        self.vec_row.len() == 0
+        self.vec_row.is_empty()
     }
     /// コンソール入力以外の方法で、コマンド1行を追加したいときに使います。
     /// 行の末尾に改行は付けないでください。
     pub fn push_row(&mut self, row: &str) {
-        self.vec_row.push(format!("{}\n", row));
+        self.vec_row.push_back(format!("{}\n", row));
     }
     /// 先頭のコマンド1行をキューから削除して返します。
-    pub fn pop_row(&mut self) -> Box<String> {
-        Box::new(self.vec_row.pop().unwrap())
+    pub fn pop_row(&mut self) -> String {
+        self.vec_row.pop_front().expect("Row buffer is empty.")
+    }
+
+    /// 物理行を 1行 、末尾の改行を付けたまま読む。バッファーが空なら、リーダーから読む。
+    fn read_raw_row(&mut self, t: &mut T) -> String {
+        if self.is_empty() {
+            (self.reader)(t)
+        } else {
+            // バッファーの先頭行です。
+            self.pop_row()
+        }
+    }
+
+    /// 物理行を 1行 読む。末尾の改行は取り除く。
+    fn read_row(&mut self, t: &mut T) -> String {
+        self.read_raw_row(t).trim_end_matches(['\r', '\n']).to_string()
+    }
+
+    /// 続きの物理行を 1行 読む。
+    /// 改行も付いていない空文字列なら 入力の終わりとみなして None 。
+    /// 空行は改行が付いているので、バッファーから読んでも リーダーから読んでも 空行としてつなぐ。
+    fn read_continued_row(&mut self, t: &mut T) -> Option<String> {
+        let row = self.read_raw_row(t);
+        if row.is_empty() {
+            None
+        } else {
+            Some(row.trim_end_matches(['\r', '\n']).to_string())
+        }
+    }
+
+    /// 続きの行をつないで、論理行を 1行 読む。
+    ///
+    /// - ダイアグラムが line_continuation なら、行末のバックスラッシュで 次の行とつなぐ。
+    /// - 行が `terminator` を持つノードで終わったら、終わりの行が来るまでの行を つなぐ。終わりの行は捨てる。
+    /// - 続きを読む途中で入力が終わったら、そこまでつないだ行を返す。
+    fn read_line(&mut self, diagram: &Diagram<T>, t: &mut T) -> String {
+        let mut line = self.read_row(t);
+
+        if diagram.is_line_continuation() {
+            let joiner = diagram.get_separator().get_joiner();
+            while line.ends_with('\\') {
+                line.pop();
+                let next_row = match self.read_continued_row(t) {
+                    Some(n) => n,
+                    None => break,
+                };
+                line = format!("{}{}{}", line.trim_end(), joiner, next_row.trim_start());
+            }
+        }
+
+        if let Some((terminator, joiner)) =
+            LineParser::find_terminator(
+                diagram,
+                t,
+                &self.diagram_player.get_current(),
+                self.diagram_player.get_call_stack(),
+                &line,
+            )
+        {
+            while let Some(row) = self.read_continued_row(t) {
+                if row.trim() == terminator {
+                    break;
+                }
+                line = format!("{}{}{}", line.trim_end(), joiner, row.trim());
+            }
+        }
+        line
+    }
+
+    /// 現在ノードや、まだ処理していないコマンド行を 取っておく。
+    pub fn snapshot(&self) -> ShellSnapshot {
+        ShellSnapshot::new(self.diagram_player.clone(), self.vec_row.iter().cloned().collect())
+    }
+    /// `snapshot` で取っておいた状態に戻す。通った記録は戻さない。
+    pub fn restore(&mut self, snapshot: &ShellSnapshot) {
+        let coverage = self.diagram_player.get_coverage().cloned();
+        self.diagram_player = snapshot.get_diagram_player().clone();
+        self.diagram_player.set_coverage(coverage);
+        self.vec_row = snapshot.get_rows().iter().cloned().collect();
+    }
+
+    /// `snapshot` を ファイルに書く。書けなければ Err 。
+    pub fn write_snapshot(&self, file: &str) -> Result<(), io::Error> {
+        self.snapshot().try_write_file(file)
+    }
+    /// ファイルに書いた `snapshot` に戻す。読めなければ Err を返して、シェルは そのまま。
+    pub fn restore_file(&mut self, file: &str) -> Result<(), io::Error> {
+        let snapshot = ShellSnapshot::try_read_file(file)?;
+        self.restore(&snapshot);
+        Ok(())
+    }
+
+    /// 読み直す前のダイアグラムを複製しておく。変更点を表示しないなら複製しない。
+    fn copy_before_reload(&self, diagram: &Diagram<T>) -> Option<Diagram<T>> {
+        if self.prints_reload_diff {
+            Some(diagram.clone())
+        } else {
+            None
+        }
+    }
+
+    /// 読み直した後のダイアグラムと比べて、変更点を表示する。
+    fn print_reload_diff(&self, before: Option<Diagram<T>>, after: &Diagram<T>) {
+        if let Some(before) = before {
+            let diff = before.diff(after);
+            if diff.is_empty() {
+                println!("Reload: No changes.");
+            } else {
+                print!("Reload:\n{}", diff);
+            }
+        }
+    }
+
+    /// 1行 パースして、コールバック関数を呼び出す。
+    ///
+    /// # Returns.
+    ///
+    /// コールバック関数からシェルへの指示。
+    fn execute_request(&mut self, diagram: &Diagram<T>, t: &mut T, line: String) -> ResponseOption {
+        // リクエストは、キャレットを更新するのでミュータブル。
+        let mut req = RequestStruct::new(line);
+        let res: &mut dyn Response = &mut ResponseStruct::new();
+
+        match self.observer {
+            Some(ref mut observer) => LineParser::run_observed(
+                &mut self.diagram_player,
+                diagram,
+                t,
+                &mut req,
+                res,
+                observer.as_mut(),
+            ),
+            None => LineParser::run(&mut self.diagram_player, diagram, t, &mut req, res),
+        }
+
+        if let Some(res_struct) = res.as_mut_any().downcast_mut::<ResponseStruct>() {
+            mem::replace(&mut res_struct.option, ResponseOption::None)
+        } else {
+            panic!("Downcast fail.");
+        }
+    }
+
+    /// ファイルの保存や読み直し、復元が できなかったことを知らせる。
+    /// 標準出力は 対局の通信などに使っているかもしれないので、 `observer` が無ければ 標準エラー出力に書く。
+    fn report_file_error(&mut self, file: &str, err: &io::Error) {
+        match self.observer {
+            Some(ref mut observer) => observer.on_file_error(file, err),
+            None => eprintln!("ERROR: {} {}", file, err),
+        }
+    }
+
+    /// ダイアグラムの読み直しや保存の指示に従う。
+    fn apply_option(&mut self, diagram: &mut Diagram<T>, option: &ResponseOption) {
+        use diagram::ResponseOption::*;
+        match *option {
+            None => {}
+            Quits => {}
+            Saves(ref file) => {
+                // ファイルを上書き。
+                if let Err(err) = diagram.try_write_file(file) {
+                    self.report_file_error(file, &err);
+                }
+            }
+            Reloads(ref file) | RestoresBackup(ref file, _) => {
+                let before = self.copy_before_reload(diagram);
+                match Shell::reload(diagram, option) {
+                    Ok(()) => self.print_reload_diff(before, diagram),
+                    Err(err) => self.report_file_error(file, &err),
+                }
+            }
+        }
+    }
+
+    /// 読み直しや復元の指示なら、ダイアグラムを読み直す。失敗したら、ダイアグラムは元のまま。
+    fn reload(diagram: &mut Diagram<T>, option: &ResponseOption) -> Result<(), io::Error> {
+        use diagram::ResponseOption::*;
+        match *option {
+            // ファイルからグラフのノード構成を読取。
+            Reloads(ref file) => diagram.try_read_file(file),
+            // バックアップから復元して、読み直す。
+            RestoresBackup(ref file, generation) => diagram.restore_backup(file, generation),
+            None | Quits | Saves(_) => Ok(()),
+        }
+    }
+
+    /// 共有のダイアグラムで、指示に従う。読み直すなら、ロックの外で複製を書き換えてから差し替える。
+    /// 読み直せなければ、共有のダイアグラムは元のまま。
+    fn apply_option_shared(&mut self, shared: &SharedDiagram<T>, option: &ResponseOption) {
+        use diagram::ResponseOption::*;
+        match *option {
+            None | Quits => {}
+            Saves(ref file) => {
+                // ファイルを上書き。
+                if let Err(err) = shared.load().try_write_file(file) {
+                    self.report_file_error(file, &err);
+                }
+            }
+            Reloads(ref file) | RestoresBackup(ref file, _) => {
+                let before = self.copy_before_reload(&shared.load());
+                match shared.try_update(|diagram| Shell::reload(diagram, option)) {
+                    Ok(()) => self.print_reload_diff(before, &shared.load()),
+                    Err(err) => self.report_file_error(file, &err),
+                }
+            }
+        }
     }
 
     /// コマンドラインの入力受付、および コールバック関数呼出を行います。
@@ -215,42 +532,33 @@ impl<T: 'static> Shell<T> {
     /// 強制終了する場合は、 [Ctrl]+[C] を入力してください。
     pub fn run(&mut self, diagram: &mut Diagram<T>, t: &mut T) {
         loop {
-            // リクエストは、キャレットを更新するのでミュータブル。
-            let mut req = if self.is_empty() {
-                let line_string = (self.reader)(t);
-                RequestStruct::new(Box::new(line_string))
-            } else {
-                // バッファーの先頭行です。
-                RequestStruct::new(self.pop_row())
-            };
-
-            use diagram::ResponseOption::*;
-            let res: &mut dyn Response = &mut ResponseStruct::new();
-
-            LineParser::run(&mut self.diagram_player, diagram, t, &mut req, res);
-
-            if let Some(res_struct) = &mut res.as_mut_any().downcast_mut::<ResponseStruct>() {
-                match res_struct.option {
-                    None => {}
-                    Quits => break, // response.quits したとき run ループを抜ける。
-                    Reloads(ref file) => {
-                        // ファイルからグラフのノード構成を読取。
-                        diagram.read_file(&file);
-                    }
-                    Saves(ref file) => {
-                        // ファイルを上書き。
-                        diagram.write_file(&file);
-                    }
-                }
-            } else {
-                panic!("Downcast fail.");
+            let line_string = self.read_line(diagram, t);
+            let option = self.execute_request(diagram, t, line_string);
+            if let ResponseOption::Quits = option {
+                break; // response.quits したとき run ループを抜ける。
             }
+            self.apply_option(diagram, &option);
+        }
+    }
+
+    /// `run` の、共有のダイアグラムを使う版。
+    /// 行ごとに その時点のダイアグラムを使うので、他のスレッドが読み直したら 次の行から反映される。
+    pub fn run_shared(&mut self, shared: &SharedDiagram<T>, t: &mut T) {
+        loop {
+            let diagram = shared.load();
+            let line_string = self.read_line(&diagram, t);
+            let option = self.execute_request(&diagram, t, line_string);
+            if let ResponseOption::Quits = option {
+                break; // response.quits したとき run ループを抜ける。
+            }
+            self.apply_option_shared(shared, &option);
         }
     }
 
     /// 1行 処理するだけでいいとき。
     ///
     /// - Quits は無効になる。
+    /// - 行をつながない。 `line` を 1行 として処理する。
     ///
     /// # Arguments.
     ///
@@ -258,29 +566,136 @@ impl<T: 'static> Shell<T> {
     /// * 't' - 任意のオブジェクト。
     /// * 'line' - コマンドライン文字列。
     pub fn execute_line(&mut self, diagram: &mut Diagram<T>, t: &mut T, line: &str) {
-        // リクエストは、キャレットを更新するのでミュータブル。
-        let mut req = RequestStruct::new(Box::new(line.to_string()));
+        let option = self.execute_request(diagram, t, line.to_string());
+        // Quits は、ループの中ではないので無効。
+        self.apply_option(diagram, &option);
+    }
 
-        use diagram::ResponseOption::*;
-        let res: &mut dyn Response = &mut ResponseStruct::new();
+    /// `execute_line` の、共有のダイアグラムを使う版。
+    pub fn execute_line_shared(&mut self, shared: &SharedDiagram<T>, t: &mut T, line: &str) {
+        let diagram = shared.load();
+        let option = self.execute_request(&diagram, t, line.to_string());
+        // Quits は、ループの中ではないので無効。
+        self.apply_option_shared(shared, &option);
+    }
+}
 
-        LineParser::run(&mut self.diagram_player, diagram, t, &mut req, res);
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use test_support;
 
-        if let Some(res_struct) = &mut res.as_mut_any().downcast_mut::<ResponseStruct>() {
-            match res_struct.option {
-                None => {}
-                Quits => {} // ループの中ではないので無効。
-                Reloads(ref file) => {
-                    // ファイルからグラフのノード構成を読取。
-                    diagram.read_file(&file);
-                }
-                Saves(ref file) => {
-                    // ファイルを上書き。
-                    diagram.write_file(&file);
-                }
-            }
+    /// 入力の終わり。
+    fn eof_reader(_log: &mut Vec<String>) -> String {
+        String::new()
+    }
+
+    /// 標準入力の代わり。 `t` に溜めた行を 改行を付けて先頭から返し、無くなれば入力の終わり。
+    fn queued_reader(rows: &mut Vec<String>) -> String {
+        if rows.is_empty() {
+            String::new()
         } else {
-            panic!("Downcast fail.");
+            format!("{}\n", rows.remove(0))
         }
     }
+
+    /// `setup` の行を、 `end` の行まで つなぐダイアグラム。
+    fn new_diagram() -> Diagram<Vec<String>> {
+        let mut diagram = test_support::new_diagram();
+        diagram.node("HEAD").next(["TK.setup", "TK.go"]);
+        diagram.node("TK.setup").token("setup").terminator("end").newline("HEAD");
+        diagram.node("TK.go").token("go").newline("HEAD");
+        diagram
+    }
+
+    fn new_shell(rows: &[&str]) -> Shell<Vec<String>> {
+        let mut shell = Shell::new();
+        shell.set_reader(eof_reader);
+        for row in rows {
+            shell.push_row(row);
+        }
+        shell
+    }
+
+    #[test]
+    fn terminator_block_joins_rows_until_the_end_row() {
+        let diagram = new_diagram();
+        let mut shell = new_shell(&["setup", "a", "", "b", "end", "go"]);
+        let mut log = Vec::new();
+
+        assert_eq!(shell.read_line(&diagram, &mut log), "setup a b");
+        assert_eq!(shell.read_line(&diagram, &mut log), "go");
+    }
+
+    #[test]
+    fn blank_rows_are_joined_the_same_from_the_reader_and_the_buffer() {
+        let diagram = new_diagram();
+        let mut shell: Shell<Vec<String>> = Shell::new();
+        shell.set_reader(queued_reader);
+        let mut rows: Vec<String> = ["setup", "a", "", "b", "end", "go"]
+            .iter()
+            .map(|row| row.to_string())
+            .collect();
+
+        assert_eq!(shell.read_line(&diagram, &mut rows), "setup a b");
+        assert_eq!(shell.read_line(&diagram, &mut rows), "go");
+    }
+
+    #[test]
+    fn rows_without_a_terminator_node_are_not_looked_ahead() {
+        let mut diagram: Diagram<Vec<String>> = Diagram::new();
+        diagram.set_entry_point("HEAD".to_string());
+        diagram.node("HEAD").next(["TK.go"]);
+        diagram.node("TK.go").token("go").newline("HEAD");
+        assert!(!diagram.has_terminator());
+        assert!(new_diagram().has_terminator());
+
+        let mut shell = new_shell(&["go", "go"]);
+        let mut log = Vec::new();
+        assert_eq!(shell.read_line(&diagram, &mut log), "go");
+        assert_eq!(shell.pop_row(), "go\n");
+        assert!(shell.is_empty());
+    }
+
+    #[test]
+    fn terminator_block_stops_at_the_end_of_input() {
+        let diagram = new_diagram();
+        let mut shell = new_shell(&["setup", "a", "b"]);
+        let mut log = Vec::new();
+
+        assert_eq!(shell.read_line(&diagram, &mut log), "setup a b");
+        assert!(shell.is_empty());
+    }
+
+    #[test]
+    fn trailing_backslash_stops_at_the_end_of_input() {
+        let mut diagram = new_diagram();
+        diagram.set_line_continuation(true);
+        let mut shell = new_shell(&["go \\", "  now\\", "later", "go\\"]);
+        let mut log = Vec::new();
+
+        assert_eq!(shell.read_line(&diagram, &mut log), "go now later");
+        assert_eq!(shell.read_line(&diagram, &mut log), "go");
+    }
+
+    fn do_quit(log: &mut Vec<String>, _req: &dyn Request, res: &mut dyn Response) {
+        log.push("quit".to_string());
+        res.set_option(ResponseOption::Quits);
+    }
+
+    #[test]
+    fn option_from_an_on_enter_action_ends_the_line() {
+        let mut diagram = new_diagram();
+        diagram.insert_fn("do_quit", do_quit);
+        diagram.node("TK.quit").token("quit").on_enter("do_quit").newline("HEAD");
+        diagram.get_node_mut("HEAD").push_exit(NEXT_EXIT_LABEL, "TK.quit".to_string());
+        let mut shell = new_shell(&[]);
+        let mut log = Vec::new();
+
+        assert_eq!(
+            shell.execute_request(&diagram, &mut log, "quit".to_string()),
+            ResponseOption::Quits
+        );
+        assert_eq!(log, vec!["quit"]);
+    }
 }
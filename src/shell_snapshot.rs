@@ -0,0 +1,148 @@
+/// シェルの状態の保存と復元。
+/// 長い解析を途中で保存して続きから始めたり、パーサーの状態の不具合を ファイルにして渡したりするのに使う。
+/// ダイアグラムと、登録したコールバック関数は含まない。
+use diagram_player::*;
+use serde_json;
+use std::fs;
+use std::io;
+
+/// シェルの状態。 `Shell::snapshot` で取って、 `Shell::restore` で戻す。
+///
+/// # Members
+///
+/// * `diagram_player` - 現在ノードと、呼び出し元の積み重ね。
+/// * `rows` - まだ処理していないコマンド行。
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+pub struct ShellSnapshot {
+    diagram_player: DiagramPlayer,
+    #[serde(default)]
+    rows: Vec<String>,
+}
+impl ShellSnapshot {
+    pub fn new(diagram_player: DiagramPlayer, rows: Vec<String>) -> ShellSnapshot {
+        ShellSnapshot {
+            diagram_player,
+            rows,
+        }
+    }
+    pub fn get_diagram_player(&self) -> &DiagramPlayer {
+        &self.diagram_player
+    }
+    pub fn get_rows(&self) -> &Vec<String> {
+        &self.rows
+    }
+
+    /// ファイル読み込み。読めなければ止める。
+    pub fn read_file(file: &str) -> ShellSnapshot {
+        match ShellSnapshot::try_read_file(file) {
+            Ok(n) => n,
+            Err(err) => panic!("File open error. {}", err),
+        }
+    }
+
+    /// `read_file` の、読めなければ Err を返す版。
+    pub fn try_read_file(file: &str) -> Result<ShellSnapshot, io::Error> {
+        let data = fs::read_to_string(file)
+            .map_err(|err| io::Error::new(err.kind(), format!("{} {}", file, err)))?;
+        serde_json::from_str(&data).map_err(|err| {
+            io::Error::new(io::ErrorKind::InvalidData, format!("{} {}", file, err))
+        })
+    }
+
+    /// ファイル上書き書込。書けなければ止める。
+    pub fn write_file(&self, file: &str) {
+        if let Err(err) = self.try_write_file(file) {
+            panic!("File write error. {}", err);
+        }
+    }
+
+    /// `write_file` の、書けなければ Err を返す版。
+    pub fn try_write_file(&self, file: &str) -> Result<(), io::Error> {
+        let json_str = serde_json::to_string_pretty(self)?;
+        fs::write(file, json_str).map_err(|err| io::Error::new(err.kind(), format!("{} {}", file, err)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use shell::*;
+    use std::env;
+
+    const JSON: &str = r##"{
+        "diagram_player": {
+            "current_label": "TK.btime",
+            "call_stack": [ { "label": "TK.go", "door_label": "#next" } ]
+        },
+        "rows": [ "go", "quit" ]
+    }"##;
+
+    #[test]
+    fn snapshot_round_trips_through_json() {
+        let snapshot: ShellSnapshot = serde_json::from_str(JSON).unwrap();
+        let json = serde_json::to_string(&snapshot).unwrap();
+        let snapshot: ShellSnapshot = serde_json::from_str(&json).unwrap();
+
+        assert_eq!(snapshot.get_diagram_player().get_current(), "TK.btime");
+        assert_eq!(
+            snapshot.get_diagram_player().get_call_stack(),
+            &vec![CallFrame {
+                label: "TK.go".to_string(),
+                door_label: "#next".to_string(),
+            }]
+        );
+        assert_eq!(snapshot.get_rows(), &vec!["go".to_string(), "quit".to_string()]);
+    }
+
+    #[test]
+    fn missing_fields_take_their_defaults() {
+        let snapshot: ShellSnapshot =
+            serde_json::from_str(r#"{ "diagram_player": { "current_label": "HEAD" } }"#).unwrap();
+
+        assert_eq!(snapshot.get_diagram_player().get_current(), "HEAD");
+        assert!(snapshot.get_diagram_player().get_call_stack().is_empty());
+        assert!(snapshot.get_rows().is_empty());
+    }
+
+    #[test]
+    fn shell_resumes_from_a_snapshot_file() {
+        let file = env::temp_dir().join(format!("kifuwarabe_shell_snapshot_{}.json", std::process::id()));
+        let file = file.to_str().unwrap();
+        let snapshot: ShellSnapshot = serde_json::from_str(JSON).unwrap();
+        snapshot.write_file(file);
+
+        let mut shell: Shell<()> = Shell::new();
+        shell.restore(&ShellSnapshot::read_file(file));
+        let _ = fs::remove_file(file);
+
+        assert_eq!(shell.get_current(), "TK.btime");
+        assert_eq!(shell.snapshot().get_diagram_player().get_call_stack().len(), 1);
+        assert_eq!(shell.pop_row(), "go");
+        assert_eq!(shell.pop_row(), "quit");
+        assert!(shell.is_empty());
+    }
+
+    #[test]
+    fn shell_reports_snapshot_file_errors_and_keeps_its_state() {
+        let dir = env::temp_dir().join(format!("kifuwarabe_shell_snapshot_missing_{}", std::process::id()));
+        let file = dir.join("shell.json");
+        let file = file.to_str().unwrap();
+        let mut shell: Shell<()> = Shell::new();
+        shell.push_row("go");
+
+        assert_eq!(shell.write_snapshot(file).unwrap_err().kind(), io::ErrorKind::NotFound);
+        assert_eq!(shell.restore_file(file).unwrap_err().kind(), io::ErrorKind::NotFound);
+
+        fs::create_dir_all(&dir).unwrap();
+        fs::write(file, "{ broken").unwrap();
+        assert_eq!(shell.restore_file(file).unwrap_err().kind(), io::ErrorKind::InvalidData);
+        assert_eq!(shell.pop_row(), "go\n");
+
+        shell.push_row("quit");
+        shell.write_snapshot(file).unwrap();
+        let mut resumed: Shell<()> = Shell::new();
+        resumed.restore_file(file).unwrap();
+        let _ = fs::remove_dir_all(&dir);
+        assert_eq!(resumed.pop_row(), "quit\n");
+    }
+}
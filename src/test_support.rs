@@ -0,0 +1,103 @@
+/// テストで使い回す、ダイアグラムと記録係。
+/// コールバック関数は `Vec<String>` に 何が呼ばれたかを書き足していく。
+use diagram::*;
+use parse_observer::*;
+use shell::*;
+use std::cell::RefCell;
+use std::rc::Rc;
+use transition_error::*;
+
+/// 一致した原文を記録する。
+pub fn record(log: &mut Vec<String>, req: &dyn Request, _res: &mut dyn Response) {
+    log.push(req.get_matched().to_string());
+}
+
+/// 取り込んだ groups を記録する。
+pub fn record_groups(log: &mut Vec<String>, req: &dyn Request, _res: &mut dyn Response) {
+    log.push(format!("[{}]", req.get_groups().join("|")));
+}
+
+/// 変換した値を記録する。
+pub fn record_value(log: &mut Vec<String>, req: &dyn Request, _res: &mut dyn Response) {
+    log.push(format!("{:?}", req.get_value()));
+}
+
+pub fn record_newline(log: &mut Vec<String>, _req: &dyn Request, _res: &mut dyn Response) {
+    log.push("#newline".to_string());
+}
+
+pub fn record_else(log: &mut Vec<String>, req: &dyn Request, _res: &mut dyn Response) {
+    log.push(format!("#else at {}", req.get_caret()));
+}
+
+/// 入り口の `HEAD` と、行末の `TAIL` 、 `#else` だけのダイアグラム。
+/// 行末まで読んだノードには `.newline("TAIL")` を付ける。
+pub fn new_diagram() -> Diagram<Vec<String>> {
+    let mut diagram = Diagram::new();
+    diagram.insert_fn("record", record);
+    diagram.insert_fn("record_groups", record_groups);
+    diagram.insert_fn("record_value", record_value);
+    diagram.insert_fn("record_newline", record_newline);
+    diagram.insert_fn("record_else", record_else);
+    diagram.set_entry_point("HEAD".to_string());
+    diagram.node("TAIL").fn_("record_newline").next(["HEAD"]);
+    diagram.node(ELSE_NODE_LABEL).fn_("record_else");
+    diagram
+}
+
+/// 新しいシェルで 行を順に実行して、記録を返す。
+pub fn run_lines(diagram: &mut Diagram<Vec<String>>, lines: &[&str]) -> Vec<String> {
+    let mut shell = Shell::new();
+    let mut log = Vec::new();
+    for line in lines {
+        shell.execute_line(diagram, &mut log, line);
+    }
+    log
+}
+
+/// 知らされた出来事を 1件 1行で記録する。 `Send` でなくても差し込める。
+pub struct EventLog {
+    events: Rc<RefCell<Vec<String>>>,
+}
+impl EventLog {
+    /// シェルに差し込んで、記録の読み口を返す。
+    pub fn attach<T>(shell: &mut Shell<T>) -> Rc<RefCell<Vec<String>>> {
+        let events = Rc::new(RefCell::new(Vec::new()));
+        shell.set_observer(Box::new(EventLog {
+            events: Rc::clone(&events),
+        }));
+        events
+    }
+    fn push(&mut self, event: String) {
+        self.events.borrow_mut().push(event);
+    }
+}
+impl ParseObserver for EventLog {
+    fn on_candidates(&mut self, current_label: &str, door_label: &str, labels: &[String]) {
+        self.push(format!("candidates {} {} {}", current_label, door_label, labels.join(",")));
+    }
+    fn on_attempt(&mut self, node: &Node, caret: usize, end: Option<usize>) {
+        self.push(format!("attempt {} {} {:?}", node.get_label(), caret, end));
+    }
+    fn on_chosen(&mut self, label: &str) {
+        self.push(format!("chosen {}", label));
+    }
+    fn on_no_match(&mut self, current_label: &str, door_label: &str, caret: usize) {
+        self.push(format!("no match {} {} {}", current_label, door_label, caret));
+    }
+    fn on_caret(&mut self, from: usize, to: usize) {
+        self.push(format!("caret {}..{}", from, to));
+    }
+    fn on_controller(&mut self, node_label: &str, fn_label: &str) {
+        self.push(format!("controller {} {}", node_label, fn_label));
+    }
+    fn on_newline(&mut self, node_label: &str, tail_node_label: &str) {
+        self.push(format!("newline {} {}", node_label, tail_node_label));
+    }
+    fn on_else(&mut self, line: &str, caret: usize) {
+        self.push(format!("else {} {}", line, caret));
+    }
+    fn on_error(&mut self, error: &TransitionError) {
+        self.push(format!("error {}", error));
+    }
+}
@@ -0,0 +1,154 @@
+/// 型付きの値のトークンを、パーサーが変換した値。 `Request::get_value` で受け取る。
+/// `(\d+)` の正規表現で取ってから コールバック関数で読み直す代わりに、パーサーが数や真偽値に変換する。
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum TokenValue {
+    Int(i64),
+    Uint(u64),
+    Float(f64),
+    Bool(bool),
+}
+impl TokenValue {
+    pub fn as_i64(&self) -> Option<i64> {
+        match *self {
+            TokenValue::Int(n) => Some(n),
+            TokenValue::Uint(n) if n <= i64::MAX as u64 => Some(n as i64),
+            _ => None,
+        }
+    }
+    pub fn as_u64(&self) -> Option<u64> {
+        match *self {
+            TokenValue::Uint(n) => Some(n),
+            TokenValue::Int(n) if 0 <= n => Some(n as u64),
+            _ => None,
+        }
+    }
+    /// 整数も浮動小数点数として取り出せる。
+    pub fn as_f64(&self) -> Option<f64> {
+        match *self {
+            TokenValue::Int(n) => Some(n as f64),
+            TokenValue::Uint(n) => Some(n as f64),
+            TokenValue::Float(n) => Some(n),
+            TokenValue::Bool(_) => None,
+        }
+    }
+    pub fn as_bool(&self) -> Option<bool> {
+        match *self {
+            TokenValue::Bool(b) => Some(b),
+            _ => None,
+        }
+    }
+}
+
+/// 値の種類。
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ValueType {
+    /// 符号付き整数。 i64 に収まるもの。
+    Int,
+    /// 符号無し整数。 u64 に収まるもの。
+    Uint,
+    /// 浮動小数点数。
+    Float,
+    /// `true` か `false` 。
+    Bool,
+}
+impl ValueType {
+    /// diagram.json の `"type"` に書く名前から作る。
+    pub fn from_name(name: &str) -> ValueType {
+        match ValueType::try_from_name(name) {
+            Some(n) => n,
+            None => panic!("\"{}\" type is not found.", name),
+        }
+    }
+    /// `from_name` の、知らない名前なら None を返す版。
+    pub fn try_from_name(name: &str) -> Option<ValueType> {
+        match name {
+            "int" => Some(ValueType::Int),
+            "uint" => Some(ValueType::Uint),
+            "float" => Some(ValueType::Float),
+            "bool" => Some(ValueType::Bool),
+            _ => None,
+        }
+    }
+    pub fn get_name(&self) -> &'static str {
+        match *self {
+            ValueType::Int => "int",
+            ValueType::Uint => "uint",
+            ValueType::Float => "float",
+            ValueType::Bool => "bool",
+        }
+    }
+
+    /// 値のトークンになり得る文字か。ここで区切ってから変換する。
+    pub fn is_value_char(&self, ch: char) -> bool {
+        match *self {
+            ValueType::Int => ch.is_ascii_digit() || ch == '-' || ch == '+',
+            ValueType::Uint => ch.is_ascii_digit() || ch == '+',
+            ValueType::Float => {
+                ch.is_ascii_digit() || ch == '-' || ch == '+' || ch == '.' || ch == 'e' || ch == 'E'
+            }
+            ValueType::Bool => ch.is_ascii_alphabetic(),
+        }
+    }
+
+    /// トークンを値に変換する。変換できなければ None 。
+    ///
+    /// # Arguments
+    ///
+    /// * `ignore_case` - 真なら `True` や `FALSE` も真偽値として読む。
+    pub fn parse(&self, text: &str, ignore_case: bool) -> Option<TokenValue> {
+        match *self {
+            ValueType::Int => text.parse::<i64>().ok().map(TokenValue::Int),
+            ValueType::Uint => text.parse::<u64>().ok().map(TokenValue::Uint),
+            ValueType::Float => match text.parse::<f64>() {
+                Ok(n) if n.is_finite() => Some(TokenValue::Float(n)),
+                _ => None,
+            },
+            ValueType::Bool => {
+                let text = if ignore_case {
+                    text.to_ascii_lowercase()
+                } else {
+                    text.to_string()
+                };
+                match text.as_str() {
+                    "true" => Some(TokenValue::Bool(true)),
+                    "false" => Some(TokenValue::Bool(false)),
+                    _ => None,
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_rejects_values_that_do_not_fit() {
+        assert_eq!(ValueType::Uint.parse("18446744073709551615", false), Some(TokenValue::Uint(u64::MAX)));
+        assert_eq!(ValueType::Uint.parse("18446744073709551616", false), None);
+        assert_eq!(ValueType::Uint.parse("-1", false), None);
+        assert_eq!(ValueType::Int.parse("-9223372036854775808", false), Some(TokenValue::Int(i64::MIN)));
+        assert_eq!(ValueType::Int.parse("9223372036854775808", false), None);
+        assert_eq!(ValueType::Float.parse("1e400", false), None);
+        assert_eq!(ValueType::Float.parse("-2.5", false), Some(TokenValue::Float(-2.5)));
+    }
+
+    #[test]
+    fn bool_is_case_sensitive_unless_ignore_case() {
+        assert_eq!(ValueType::Bool.parse("true", false), Some(TokenValue::Bool(true)));
+        assert_eq!(ValueType::Bool.parse("FALSE", false), None);
+        assert_eq!(ValueType::Bool.parse("FALSE", true), Some(TokenValue::Bool(false)));
+        assert_eq!(ValueType::Bool.parse("yes", true), None);
+    }
+
+    #[test]
+    fn accessors_convert_only_when_the_value_fits() {
+        assert_eq!(TokenValue::Uint(u64::MAX).as_i64(), None);
+        assert_eq!(TokenValue::Uint(7).as_i64(), Some(7));
+        assert_eq!(TokenValue::Int(-1).as_u64(), None);
+        assert_eq!(TokenValue::Int(3).as_f64(), Some(3.0));
+        assert_eq!(TokenValue::Bool(true).as_f64(), None);
+        assert_eq!(TokenValue::Float(1.5).as_bool(), None);
+    }
+}
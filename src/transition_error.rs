@@ -0,0 +1,44 @@
+/// 状態遷移できなかった理由。
+/// `try_` の付いたメソッドが返す。付いていない方は、同じ理由で panic する。
+use std::error::Error;
+use std::fmt;
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum TransitionError {
+    /// ノードのラベル。
+    NodeNotFound(String),
+    /// コールバック関数の登録名。
+    FnNotFound(String),
+    /// 述語の登録名。
+    GuardNotFound(String),
+    /// (ノードのラベル, 出口) 。
+    DoorNotFound(String, String),
+    /// 行き先が１つに決まらない (ノードのラベル, 出口) 。
+    AmbiguousDoor(String, String),
+}
+impl fmt::Display for TransitionError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            TransitionError::NodeNotFound(ref node_label) => {
+                write!(f, "\"{}\" node is not found.", node_label)
+            }
+            TransitionError::FnNotFound(ref fn_label) => {
+                write!(f, "\"{}\" fn is not found.", fn_label)
+            }
+            TransitionError::GuardNotFound(ref guard) => {
+                write!(f, "\"{}\" guard is not found.", guard)
+            }
+            TransitionError::DoorNotFound(ref node_label, ref door_label) => write!(
+                f,
+                "\"{}\" door is not found. ({} node)",
+                door_label, node_label
+            ),
+            TransitionError::AmbiguousDoor(ref node_label, ref door_label) => write!(
+                f,
+                "\"{}\" door has more than one exit. ({} node)",
+                door_label, node_label
+            ),
+        }
+    }
+}
+impl Error for TransitionError {}